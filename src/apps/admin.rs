@@ -1,4 +1,4 @@
-use crate::{Result, Uuid, Version};
+use crate::{Error, Result, Uuid, Version};
 
 crate::app!();
 
@@ -20,6 +20,8 @@ impl App<'_> {
     /// NOTE: This command requires user confirmation (by tapping the device).
     /// Current firmware implementation has no timeout, so if the user aborts
     /// the operation host-side, the device is "stuck" until replug.
+    /// Over CTAP, the request can be cancelled instead, see
+    /// [`CancelHandle`][crate::transport::ctap::CancelHandle].
     ///
     /// Rebooting can cause the connection to return error, which should
     /// be special-cased by the caller.
@@ -51,7 +53,12 @@ impl App<'_> {
         Ok(Uuid::from_u128(
             bytes
                 .try_into()
-                .map_err(|_| anyhow::anyhow!("expected 16 byte UUID, got {}", &hex::encode(bytes)))
+                .map_err(|_| {
+                    Error::Protocol(format!(
+                        "expected 16 byte UUID, got {}",
+                        &hex::encode(bytes)
+                    ))
+                })
                 .map(u128::from_be_bytes)?,
         ))
     }
@@ -60,10 +67,10 @@ impl App<'_> {
     pub fn version(&mut self) -> Result<Version> {
         let version_bytes = self.transport.instruct(Self::VERSION_COMMAND)?;
         let bytes: [u8; 4] = version_bytes.as_slice().try_into().map_err(|_| {
-            anyhow::anyhow!(
+            Error::Protocol(format!(
                 "expected 4 bytes version, got {}",
-                &hex::encode(version_bytes)
-            )
+                &hex::encode(&version_bytes)
+            ))
         })?;
         Ok(bytes.into())
     }
@@ -78,6 +85,6 @@ impl App<'_> {
         locked
             .first()
             .map(|&locked| locked == 1)
-            .ok_or_else(|| anyhow::anyhow!("response to locked status empty"))
+            .ok_or_else(|| Error::Protocol("response to locked status empty".to_string()))
     }
 }
@@ -1,17 +1,259 @@
-// use crate::{Result, transport::Init};
-
-ctap_app!();
-
-// impl<'t> crate::Select<'t> for App<'t> {
-//     const RID: &'static [u8] = super::Rid::NFC_FORUM;
-//     const PIX: &'static [u8] = super::Pix::NDEF;
-// }
-
-// impl App<'_> {
-//     pub fn info(&mut self) -> Result<Init> {
-//         self.transport
-//             .call(Instruction::Select.into(), &Self::CAPABILITIES_PARAMETER)
-//             .map(drop)?;
-//         self.fetch()
-//     }
-// }
+//! The FIDO authenticator app, spoken to over CTAPHID.
+//!
+//! CTAP2 commands are CBOR encoded, cf. the [specification][ctap21].
+//!
+//! [ctap21]: https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html
+
+use std::collections::BTreeMap;
+
+use crate::{
+    cbor::Value,
+    device::ctap::Device,
+    transport::ctap::{Code, Command, Init},
+    Error, Result,
+};
+
+pub mod credential_management;
+pub mod pin;
+pub mod u2f;
+pub mod webauthn;
+pub use pin::{PinProtocol, PinToken};
+
+/// Like the apps made with `ctap_app!()`, but keeping the CTAPHID channel.
+pub struct App<'t> {
+    transport: &'t mut Device,
+    init: Option<Init>,
+    info: Option<Info>,
+}
+
+impl<'t> From<&'t mut Device> for App<'t> {
+    fn from(transport: &'t mut Device) -> App<'t> {
+        Self {
+            transport,
+            init: None,
+            info: None,
+        }
+    }
+}
+
+impl<'t> core::ops::Deref for App<'t> {
+    type Target = Device;
+    fn deref(&self) -> &Self::Target {
+        self.transport
+    }
+}
+
+impl<'t> core::ops::DerefMut for App<'t> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.transport
+    }
+}
+
+/// CTAP2 command bytes
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Ctap2Command {
+    MakeCredential = 0x01,
+    GetAssertion = 0x02,
+    GetInfo = 0x04,
+    ClientPin = 0x06,
+    Reset = 0x07,
+    GetNextAssertion = 0x08,
+    CredentialManagement = 0x0A,
+    /// authenticatorCredentialManagement as prototyped in FIDO_2_1_PRE
+    CredentialManagementPreview = 0x41,
+}
+
+impl App<'_> {
+    /// The CTAPHID channel of this app, allocated on first use.
+    pub fn init(&mut self) -> Result<Init> {
+        match self.init {
+            Some(init) => Ok(init),
+            None => {
+                let init = self.transport.init()?;
+                self.init = Some(init);
+                Ok(init)
+            }
+        }
+    }
+
+    /// Send a CTAP2 command (via CTAPHID_CBOR), returning the response parameters (if any).
+    pub fn cbor(
+        &mut self,
+        command: Ctap2Command,
+        parameters: Option<&Value>,
+    ) -> Result<Option<Value>> {
+        let init = self.init()?;
+        if !init.can_cbor {
+            return Err(Error::Unsupported("CTAP2 on this device".to_string()));
+        }
+
+        let mut data = vec![command as u8];
+        if let Some(parameters) = parameters {
+            data.extend_from_slice(&parameters.to_vec());
+        }
+        let response = self.call(init.channel, Command::new(Code::Cbor).with_data(&data)?)?;
+        trace!("CTAP2 response: {}", hex::encode(&response));
+
+        let (&status, response) = response
+            .split_first()
+            .ok_or_else(|| Error::Protocol("empty CTAP2 response".to_string()))?;
+        if status != 0 {
+            return Err(Error::Ctap2(status));
+        }
+        if response.is_empty() {
+            return Ok(None);
+        }
+        Value::from_slice(response).map(Some)
+    }
+
+    /// authenticatorGetInfo, queried once per app (and again after changing the PIN or resetting).
+    pub fn info(&mut self) -> Result<Info> {
+        if let Some(info) = self.info.as_ref() {
+            return Ok(info.clone());
+        }
+        let response = self
+            .cbor(Ctap2Command::GetInfo, None)?
+            .ok_or_else(|| Error::Protocol("empty authenticatorGetInfo response".to_string()))?;
+        let info = Info::try_from(&response)?;
+        self.info = Some(info.clone());
+        Ok(info)
+    }
+
+    /// Whether the authenticator implements CTAP 2.1 (not just its preview).
+    pub fn is_fido_2_1(&mut self) -> Result<bool> {
+        Ok(self.info()?.versions.iter().any(|v| v == "FIDO_2_1"))
+    }
+
+    /// authenticatorReset, deleting all credentials and the PIN.
+    ///
+    /// Authenticators only accept this within 10 seconds of being plugged in,
+    /// and wait for the user to confirm presence.
+    pub fn reset(&mut self) -> Result<()> {
+        self.info = None;
+        self.cbor(Ctap2Command::Reset, None).map(drop)
+    }
+}
+
+/// Response to authenticatorGetInfo.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Info {
+    pub versions: Vec<String>,
+    pub extensions: Vec<String>,
+    pub aaguid: [u8; 16],
+    pub options: BTreeMap<String, bool>,
+    pub max_msg_size: Option<u64>,
+    pub pin_uv_auth_protocols: Vec<u64>,
+    pub firmware_version: Option<u64>,
+}
+
+fn texts(value: Option<&Value>) -> Result<Vec<String>> {
+    value
+        .map(|value| {
+            value
+                .as_array()
+                .ok_or_else(|| Error::Protocol("expected array".to_string()))?
+                .iter()
+                .map(|text| {
+                    text.as_text()
+                        .map(str::to_string)
+                        .ok_or_else(|| Error::Protocol("expected text".to_string()))
+                })
+                .collect()
+        })
+        .transpose()
+        .map(Option::unwrap_or_default)
+}
+
+fn unsigned(value: &Value) -> Result<u64> {
+    value
+        .as_integer()
+        .and_then(|integer| u64::try_from(integer).ok())
+        .ok_or_else(|| Error::Protocol("expected unsigned integer".to_string()))
+}
+
+impl TryFrom<&Value> for Info {
+    type Error = Error;
+    fn try_from(response: &Value) -> Result<Self> {
+        let aaguid = response
+            .get(0x03)
+            .and_then(Value::as_bytes)
+            .and_then(|aaguid| aaguid.try_into().ok())
+            .ok_or_else(|| Error::Protocol("missing or invalid AAGUID".to_string()))?;
+
+        let mut options = BTreeMap::new();
+        if let Some(map) = response.get(0x04) {
+            let map = map
+                .as_map()
+                .ok_or_else(|| Error::Protocol("options are not a map".to_string()))?;
+            for (key, value) in map {
+                match (key.as_text(), value.as_bool()) {
+                    (Some(key), Some(value)) => {
+                        options.insert(key.to_string(), value);
+                    }
+                    _ => return Err(Error::Protocol("invalid option".to_string())),
+                }
+            }
+        }
+
+        let pin_uv_auth_protocols = response
+            .get(0x06)
+            .map(|protocols| {
+                protocols
+                    .as_array()
+                    .ok_or_else(|| Error::Protocol("expected array".to_string()))?
+                    .iter()
+                    .map(unsigned)
+                    .collect()
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        Ok(Self {
+            versions: texts(response.get(0x01))?,
+            extensions: texts(response.get(0x02))?,
+            aaguid,
+            options,
+            max_msg_size: response.get(0x05).map(unsigned).transpose()?,
+            pin_uv_auth_protocols,
+            firmware_version: response.get(0x0E).map(unsigned).transpose()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_info() {
+        let response = Value::Map(vec![
+            (
+                1.into(),
+                Value::Array(vec!["U2F_V2".into(), "FIDO_2_0".into()]),
+            ),
+            (2.into(), Value::Array(vec!["hmac-secret".into()])),
+            (3.into(), Value::Bytes(vec![0xAB; 16])),
+            (
+                4.into(),
+                Value::Map(vec![
+                    ("rk".into(), true.into()),
+                    ("clientPin".into(), false.into()),
+                ]),
+            ),
+            (5.into(), 3072.into()),
+            (6.into(), Value::Array(vec![1.into()])),
+        ]);
+        let info = Info::try_from(&Value::from_slice(&response.to_vec()).unwrap()).unwrap();
+        assert_eq!(info.versions, ["U2F_V2", "FIDO_2_0"]);
+        assert_eq!(info.extensions, ["hmac-secret"]);
+        assert_eq!(info.aaguid, [0xAB; 16]);
+        assert!(info.options["rk"]);
+        assert!(!info.options["clientPin"]);
+        assert_eq!(info.max_msg_size, Some(3072));
+        assert_eq!(info.pin_uv_auth_protocols, [1]);
+        assert_eq!(info.firmware_version, None);
+
+        assert!(Info::try_from(&Value::Map(vec![])).is_err());
+    }
+}
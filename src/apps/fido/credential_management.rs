@@ -0,0 +1,301 @@
+//! authenticatorCredentialManagement, for discoverable (resident) credentials.
+//!
+//! All subcommands are authorized with a [`PinToken`], cf. [`App::credential_management_token`].
+
+use super::{pin::PERMISSION_CREDENTIAL_MANAGEMENT, App, Ctap2Command, PinToken};
+use crate::{cbor::Value, Error, Result};
+
+/// CTAP2_ERR_NO_CREDENTIALS
+const NO_CREDENTIALS: u8 = 0x2E;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+enum Subcommand {
+    GetCredsMetadata = 0x01,
+    EnumerateRpsBegin = 0x02,
+    EnumerateRpsGetNextRp = 0x03,
+    EnumerateCredentialsBegin = 0x04,
+    EnumerateCredentialsGetNextCredential = 0x05,
+    DeleteCredential = 0x06,
+}
+
+/// Usage of the discoverable credential storage.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Metadata {
+    pub existing: u64,
+    pub remaining: u64,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RelyingParty {
+    pub id: String,
+    pub name: Option<String>,
+    /// SHA-256 of the ID, used to enumerate its credentials
+    pub id_hash: [u8; 32],
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct User {
+    pub id: Vec<u8>,
+    pub name: Option<String>,
+    pub display_name: Option<String>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Credential {
+    pub id: Vec<u8>,
+    pub user: User,
+    /// COSE encoded
+    pub public_key: Option<Value>,
+    pub cred_protect: Option<u64>,
+}
+
+fn missing(field: &str) -> Error {
+    Error::Protocol(format!("missing or invalid {}", field))
+}
+
+fn optional_text(map: &Value, key: &str) -> Option<String> {
+    map.get(key).and_then(Value::as_text).map(str::to_string)
+}
+
+fn count(response: &Value, key: i32, field: &str) -> Result<u64> {
+    response
+        .get(key)
+        .and_then(Value::as_integer)
+        .and_then(|count| u64::try_from(count).ok())
+        .ok_or_else(|| missing(field))
+}
+
+impl TryFrom<&Value> for RelyingParty {
+    type Error = Error;
+    fn try_from(response: &Value) -> Result<Self> {
+        let rp = response.get(0x03).ok_or_else(|| missing("rp"))?;
+        Ok(Self {
+            id: optional_text(rp, "id").ok_or_else(|| missing("rp.id"))?,
+            name: optional_text(rp, "name"),
+            id_hash: response
+                .get(0x04)
+                .and_then(Value::as_bytes)
+                .and_then(|hash| hash.try_into().ok())
+                .ok_or_else(|| missing("rpIDHash"))?,
+        })
+    }
+}
+
+impl TryFrom<&Value> for Credential {
+    type Error = Error;
+    fn try_from(response: &Value) -> Result<Self> {
+        let user = response.get(0x06).ok_or_else(|| missing("user"))?;
+        let user = User {
+            id: user
+                .get("id")
+                .and_then(Value::as_bytes)
+                .ok_or_else(|| missing("user.id"))?
+                .to_vec(),
+            name: optional_text(user, "name"),
+            display_name: optional_text(user, "displayName"),
+        };
+        let id = response
+            .get(0x07)
+            .and_then(|descriptor| descriptor.get("id"))
+            .and_then(Value::as_bytes)
+            .ok_or_else(|| missing("credentialID"))?
+            .to_vec();
+        Ok(Self {
+            id,
+            user,
+            public_key: response.get(0x08).cloned(),
+            cred_protect: response
+                .get(0x0A)
+                .and_then(Value::as_integer)
+                .and_then(|level| u64::try_from(level).ok()),
+        })
+    }
+}
+
+impl App<'_> {
+    /// A PIN token authorizing credential management.
+    ///
+    /// CTAP 2.1 authenticators require the `cm` permission, which tokens from
+    /// getPinToken lack; 2.0 and preview authenticators only know getPinToken.
+    pub fn credential_management_token(&mut self, pin: &str) -> Result<PinToken> {
+        if self.is_fido_2_1()? {
+            self.pin_token_with_permissions(pin, PERMISSION_CREDENTIAL_MANAGEMENT, None)
+        } else {
+            self.pin_token(pin)
+        }
+    }
+
+    /// Prefer the CTAP 2.1 command over its preview.
+    ///
+    /// Needs to be determined before enumerations, as any command other than
+    /// the next enumeration step makes the authenticator drop their state.
+    fn credential_management_command(&mut self) -> Result<Ctap2Command> {
+        Ok(if self.is_fido_2_1()? {
+            Ctap2Command::CredentialManagement
+        } else {
+            Ctap2Command::CredentialManagementPreview
+        })
+    }
+
+    fn credential_management(
+        &mut self,
+        command: Ctap2Command,
+        token: &PinToken,
+        subcommand: Subcommand,
+        subcommand_parameters: Option<Value>,
+    ) -> Result<Value> {
+        let mut message = vec![subcommand as u8];
+        if let Some(subcommand_parameters) = subcommand_parameters.as_ref() {
+            message.extend_from_slice(&subcommand_parameters.to_vec());
+        }
+        let mut parameters = vec![
+            (0x01.into(), (subcommand as u8).into()),
+            (0x03.into(), (token.protocol as u8).into()),
+            (0x04.into(), token.authenticate(&message).into()),
+        ];
+        if let Some(subcommand_parameters) = subcommand_parameters {
+            parameters.push((0x02.into(), subcommand_parameters));
+        }
+        Ok(self
+            .cbor(command, Some(&Value::Map(parameters)))?
+            .unwrap_or_else(|| Value::Map(vec![])))
+    }
+
+    /// Continuation subcommands carry no authentication.
+    fn credential_management_next(
+        &mut self,
+        command: Ctap2Command,
+        subcommand: Subcommand,
+    ) -> Result<Value> {
+        let parameters = Value::Map(vec![(0x01.into(), (subcommand as u8).into())]);
+        Ok(self
+            .cbor(command, Some(&parameters))?
+            .unwrap_or_else(|| Value::Map(vec![])))
+    }
+
+    /// getCredsMetadata
+    pub fn credentials_metadata(&mut self, token: &PinToken) -> Result<Metadata> {
+        let command = self.credential_management_command()?;
+        let response =
+            self.credential_management(command, token, Subcommand::GetCredsMetadata, None)?;
+        Ok(Metadata {
+            existing: count(&response, 0x01, "existingResidentCredentialsCount")?,
+            remaining: count(
+                &response,
+                0x02,
+                "maxPossibleRemainingResidentCredentialsCount",
+            )?,
+        })
+    }
+
+    /// enumerateRPsBegin, followed by enumerateRPsGetNextRP
+    pub fn relying_parties(&mut self, token: &PinToken) -> Result<Vec<RelyingParty>> {
+        let command = self.credential_management_command()?;
+        let response =
+            match self.credential_management(command, token, Subcommand::EnumerateRpsBegin, None) {
+                Err(Error::Ctap2(NO_CREDENTIALS)) => return Ok(vec![]),
+                response => response?,
+            };
+        let total = count(&response, 0x05, "totalRPs")?;
+        let mut rps = vec![RelyingParty::try_from(&response)?];
+        for _ in 1..total {
+            let response =
+                self.credential_management_next(command, Subcommand::EnumerateRpsGetNextRp)?;
+            rps.push(RelyingParty::try_from(&response)?);
+        }
+        Ok(rps)
+    }
+
+    /// enumerateCredentialsBegin, followed by enumerateCredentialsGetNextCredential
+    pub fn credentials(
+        &mut self,
+        token: &PinToken,
+        rp_id_hash: &[u8; 32],
+    ) -> Result<Vec<Credential>> {
+        let parameters = Value::Map(vec![(0x01.into(), rp_id_hash[..].into())]);
+        let command = self.credential_management_command()?;
+        let response = match self.credential_management(
+            command,
+            token,
+            Subcommand::EnumerateCredentialsBegin,
+            Some(parameters),
+        ) {
+            Err(Error::Ctap2(NO_CREDENTIALS)) => return Ok(vec![]),
+            response => response?,
+        };
+        let total = count(&response, 0x09, "totalCredentials")?;
+        let mut credentials = vec![Credential::try_from(&response)?];
+        for _ in 1..total {
+            let response = self.credential_management_next(
+                command,
+                Subcommand::EnumerateCredentialsGetNextCredential,
+            )?;
+            credentials.push(Credential::try_from(&response)?);
+        }
+        Ok(credentials)
+    }
+
+    /// deleteCredential
+    pub fn delete_credential(&mut self, token: &PinToken, credential_id: &[u8]) -> Result<()> {
+        let descriptor = Value::Map(vec![
+            ("id".into(), credential_id.into()),
+            ("type".into(), "public-key".into()),
+        ]);
+        let parameters = Value::Map(vec![(0x02.into(), descriptor)]);
+        let command = self.credential_management_command()?;
+        self.credential_management(
+            command,
+            token,
+            Subcommand::DeleteCredential,
+            Some(parameters),
+        )
+        .map(drop)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_rp_and_credential() {
+        let rp = Value::Map(vec![
+            (
+                0x03.into(),
+                Value::Map(vec![("id".into(), "example.com".into())]),
+            ),
+            (0x04.into(), Value::Bytes(vec![0x11; 32])),
+            (0x05.into(), 2.into()),
+        ]);
+        let rp = RelyingParty::try_from(&rp).unwrap();
+        assert_eq!(rp.id, "example.com");
+        assert_eq!(rp.name, None);
+        assert_eq!(rp.id_hash, [0x11; 32]);
+
+        let credential = Value::Map(vec![
+            (
+                0x06.into(),
+                Value::Map(vec![
+                    ("id".into(), Value::Bytes(vec![1, 2, 3])),
+                    ("name".into(), "alice".into()),
+                ]),
+            ),
+            (
+                0x07.into(),
+                Value::Map(vec![
+                    ("id".into(), Value::Bytes(vec![0xCC; 16])),
+                    ("type".into(), "public-key".into()),
+                ]),
+            ),
+            (0x0A.into(), 2.into()),
+        ]);
+        let credential = Credential::try_from(&credential).unwrap();
+        assert_eq!(credential.id, [0xCC; 16]);
+        assert_eq!(credential.user.id, [1, 2, 3]);
+        assert_eq!(credential.user.name.as_deref(), Some("alice"));
+        assert_eq!(credential.cred_protect, Some(2));
+
+        assert!(Credential::try_from(&Value::Map(vec![])).is_err());
+    }
+}
@@ -0,0 +1,381 @@
+//! authenticatorClientPIN, with PIN/UV auth protocols one and two.
+//!
+//! Both protocols agree on a shared secret via ephemeral ECDH on P-256, which is then
+//! used to encrypt PINs (AES-256-CBC) and authenticate requests (HMAC-SHA-256).
+
+use aes::cipher::{block_padding::NoPadding, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use ring::{agreement, hkdf, hmac, rand::SystemRandom};
+use sha2::{Digest as _, Sha256};
+
+use super::{App, Ctap2Command};
+use crate::{
+    cbor::Value,
+    cose::{PublicKey, ECDH_ES_HKDF_256},
+    Error, Result,
+};
+
+type Aes256CbcEnc = cbc::Encryptor<aes::Aes256>;
+type Aes256CbcDec = cbc::Decryptor<aes::Aes256>;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PinProtocol {
+    One = 1,
+    Two = 2,
+}
+
+impl TryFrom<u64> for PinProtocol {
+    type Error = Error;
+    fn try_from(protocol: u64) -> Result<Self> {
+        match protocol {
+            1 => Ok(Self::One),
+            2 => Ok(Self::Two),
+            _ => Err(Error::InvalidInput(format!(
+                "unknown PIN/UV auth protocol {}",
+                protocol
+            ))),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+enum Subcommand {
+    GetPinRetries = 0x01,
+    GetKeyAgreement = 0x02,
+    SetPin = 0x03,
+    ChangePin = 0x04,
+    GetPinToken = 0x05,
+    GetPinUvAuthTokenUsingPinWithPermissions = 0x09,
+}
+
+/// Permission to make credentials (`mc`), for [`App::pin_token_with_permissions`].
+pub const PERMISSION_MAKE_CREDENTIAL: u8 = 0x01;
+/// Permission to get assertions (`ga`).
+pub const PERMISSION_GET_ASSERTION: u8 = 0x02;
+/// Permission to manage discoverable credentials (`cm`).
+pub const PERMISSION_CREDENTIAL_MANAGEMENT: u8 = 0x04;
+
+/// The secret shared with the authenticator after key agreement.
+pub struct SharedSecret {
+    protocol: PinProtocol,
+    /// protocol one: the single key; protocol two: HMAC key followed by AES key
+    key: Vec<u8>,
+}
+
+struct Len(usize);
+
+impl hkdf::KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+fn hkdf_sha256(ikm: &[u8], info: &[u8]) -> Vec<u8> {
+    let mut okm = vec![0u8; 32];
+    hkdf::Salt::new(hkdf::HKDF_SHA256, &[0u8; 32])
+        .extract(ikm)
+        .expand(&[info], Len(32))
+        // INVARIANT: 32 bytes are a valid length for HKDF-SHA-256
+        .and_then(|okm_| okm_.fill(&mut okm))
+        .unwrap();
+    okm
+}
+
+impl SharedSecret {
+    fn derive(protocol: PinProtocol, z: &[u8]) -> Self {
+        let key = match protocol {
+            PinProtocol::One => Sha256::digest(z).to_vec(),
+            PinProtocol::Two => {
+                let mut key = hkdf_sha256(z, b"CTAP2 HMAC key");
+                key.extend_from_slice(&hkdf_sha256(z, b"CTAP2 AES key"));
+                key
+            }
+        };
+        Self { protocol, key }
+    }
+
+    fn hmac_key(&self) -> &[u8] {
+        &self.key[..32]
+    }
+
+    fn aes_key(&self) -> &[u8] {
+        match self.protocol {
+            PinProtocol::One => &self.key,
+            PinProtocol::Two => &self.key[32..],
+        }
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self.protocol {
+            PinProtocol::One => Aes256CbcEnc::new(self.aes_key().into(), &[0u8; 16].into())
+                .encrypt_padded_vec_mut::<NoPadding>(plaintext),
+            PinProtocol::Two => {
+                let mut iv = [0u8; 16];
+                getrandom::getrandom(&mut iv).map_err(|e| Error::Rng(format!("IV: {}", e)))?;
+                let mut ciphertext = iv.to_vec();
+                ciphertext.extend_from_slice(
+                    &Aes256CbcEnc::new(self.aes_key().into(), &iv.into())
+                        .encrypt_padded_vec_mut::<NoPadding>(plaintext),
+                );
+                ciphertext
+            }
+        })
+    }
+
+    pub fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let (iv, ciphertext) = match self.protocol {
+            PinProtocol::One => ([0u8; 16], ciphertext),
+            PinProtocol::Two => {
+                if ciphertext.len() < 16 {
+                    return Err(Error::Protocol("ciphertext too short".to_string()));
+                }
+                let (iv, ciphertext) = ciphertext.split_at(16);
+                (iv.try_into()?, ciphertext)
+            }
+        };
+        Aes256CbcDec::new(self.aes_key().into(), &iv.into())
+            .decrypt_padded_vec_mut::<NoPadding>(ciphertext)
+            .map_err(|_| Error::Protocol("ciphertext not a multiple of block size".to_string()))
+    }
+
+    pub fn authenticate(&self, message: &[u8]) -> Vec<u8> {
+        authenticate(self.protocol, self.hmac_key(), message)
+    }
+}
+
+fn authenticate(protocol: PinProtocol, key: &[u8], message: &[u8]) -> Vec<u8> {
+    let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), message);
+    match protocol {
+        PinProtocol::One => tag.as_ref()[..16].to_vec(),
+        PinProtocol::Two => tag.as_ref().to_vec(),
+    }
+}
+
+/// A PIN token, authorizing PIN protected commands until the authenticator is power cycled.
+pub struct PinToken {
+    pub protocol: PinProtocol,
+    token: Vec<u8>,
+}
+
+impl PinToken {
+    /// The `pinUvAuthParam` for a message.
+    pub fn authenticate(&self, message: &[u8]) -> Vec<u8> {
+        authenticate(self.protocol, &self.token, message)
+    }
+}
+
+/// PINs are at least four Unicode code points, and at most 63 bytes long.
+fn padded_pin(pin: &str) -> Result<Vec<u8>> {
+    if pin.chars().count() < 4 {
+        return Err(Error::InvalidInput(
+            "PIN must have at least 4 characters".to_string(),
+        ));
+    }
+    if pin.len() > 63 {
+        return Err(Error::InvalidInput(
+            "PIN must be at most 63 bytes long".to_string(),
+        ));
+    }
+    let mut padded = pin.as_bytes().to_vec();
+    padded.resize(64, 0);
+    Ok(padded)
+}
+
+fn pin_hash(pin: &str) -> Vec<u8> {
+    Sha256::digest(pin.as_bytes())[..16].to_vec()
+}
+
+impl App<'_> {
+    /// The protocol to use, preferring protocol two if the authenticator supports it.
+    pub fn pin_protocol(&mut self) -> Result<PinProtocol> {
+        let protocols = self.info()?.pin_uv_auth_protocols;
+        if protocols.contains(&2) {
+            Ok(PinProtocol::Two)
+        } else if protocols.contains(&1) {
+            Ok(PinProtocol::One)
+        } else {
+            Err(Error::Unsupported(
+                "authenticator has no known PIN/UV auth protocol".to_string(),
+            ))
+        }
+    }
+
+    fn client_pin(
+        &mut self,
+        protocol: PinProtocol,
+        subcommand: Subcommand,
+        mut parameters: Vec<(Value, Value)>,
+    ) -> Result<Option<Value>> {
+        parameters.push((0x01.into(), (protocol as u8).into()));
+        parameters.push((0x02.into(), (subcommand as u8).into()));
+        self.cbor(Ctap2Command::ClientPin, Some(&Value::Map(parameters)))
+    }
+
+    /// Number of PIN attempts left before the authenticator blocks.
+    pub fn pin_retries(&mut self) -> Result<u8> {
+        let protocol = self.pin_protocol()?;
+        self.client_pin(protocol, Subcommand::GetPinRetries, vec![])?
+            .as_ref()
+            .and_then(|response| response.get(0x03))
+            .and_then(Value::as_integer)
+            .and_then(|retries| u8::try_from(retries).ok())
+            .ok_or_else(|| Error::Protocol("missing pinRetries".to_string()))
+    }
+
+    /// Agree on a shared secret, returning it with our public key (COSE encoded).
+    pub fn key_agreement(&mut self, protocol: PinProtocol) -> Result<(Value, SharedSecret)> {
+        let response = self
+            .client_pin(protocol, Subcommand::GetKeyAgreement, vec![])?
+            .ok_or_else(|| Error::Protocol("missing keyAgreement".to_string()))?;
+        let peer = PublicKey::try_from(
+            response
+                .get(0x01)
+                .ok_or_else(|| Error::Protocol("missing keyAgreement".to_string()))?,
+        )?
+        .uncompressed()
+        .ok_or_else(|| Error::Protocol("keyAgreement key not P-256".to_string()))?;
+
+        let rng = SystemRandom::new();
+        let private_key = agreement::EphemeralPrivateKey::generate(&agreement::ECDH_P256, &rng)
+            .map_err(|_| Error::Rng("ephemeral key generation failed".to_string()))?;
+        let public_key = private_key
+            .compute_public_key()
+            .map_err(|_| Error::Rng("ephemeral key generation failed".to_string()))?;
+        let shared_secret = agreement::agree_ephemeral(
+            private_key,
+            &agreement::UnparsedPublicKey::new(&agreement::ECDH_P256, peer),
+            Error::Protocol("invalid keyAgreement key".to_string()),
+            |z| Ok(SharedSecret::derive(protocol, z)),
+        )?;
+
+        let public_key = PublicKey::from_uncompressed(ECDH_ES_HKDF_256, public_key.as_ref())?;
+        Ok((public_key.to_value(), shared_secret))
+    }
+
+    /// Set the initial PIN.
+    pub fn set_pin(&mut self, pin: &str) -> Result<()> {
+        let new_pin = padded_pin(pin)?;
+        let protocol = self.pin_protocol()?;
+        let (key_agreement, shared_secret) = self.key_agreement(protocol)?;
+
+        let new_pin_enc = shared_secret.encrypt(&new_pin)?;
+        let pin_uv_auth_param = shared_secret.authenticate(&new_pin_enc);
+        self.info = None;
+        self.client_pin(
+            protocol,
+            Subcommand::SetPin,
+            vec![
+                (0x03.into(), key_agreement),
+                (0x04.into(), pin_uv_auth_param.into()),
+                (0x05.into(), new_pin_enc.into()),
+            ],
+        )
+        .map(drop)
+    }
+
+    /// Change an existing PIN.
+    pub fn change_pin(&mut self, current_pin: &str, pin: &str) -> Result<()> {
+        let new_pin = padded_pin(pin)?;
+        let protocol = self.pin_protocol()?;
+        let (key_agreement, shared_secret) = self.key_agreement(protocol)?;
+
+        let pin_hash_enc = shared_secret.encrypt(&pin_hash(current_pin))?;
+        let new_pin_enc = shared_secret.encrypt(&new_pin)?;
+        let mut message = new_pin_enc.clone();
+        message.extend_from_slice(&pin_hash_enc);
+        let pin_uv_auth_param = shared_secret.authenticate(&message);
+        self.client_pin(
+            protocol,
+            Subcommand::ChangePin,
+            vec![
+                (0x03.into(), key_agreement),
+                (0x04.into(), pin_uv_auth_param.into()),
+                (0x05.into(), new_pin_enc.into()),
+                (0x06.into(), pin_hash_enc.into()),
+            ],
+        )
+        .map(drop)
+    }
+
+    /// Obtain a PIN token (getPinToken, without permissions).
+    ///
+    /// CTAP 2.1 authenticators restrict these to making credentials and getting assertions.
+    pub fn pin_token(&mut self, pin: &str) -> Result<PinToken> {
+        self.request_pin_token(pin, Subcommand::GetPinToken, vec![])
+    }
+
+    /// Obtain a PIN token with permissions (getPinUvAuthTokenUsingPinWithPermissions, CTAP 2.1).
+    ///
+    /// The `PERMISSION_*` constants may be combined; making credentials and
+    /// getting assertions also need the relying party ID.
+    pub fn pin_token_with_permissions(
+        &mut self,
+        pin: &str,
+        permissions: u8,
+        rp_id: Option<&str>,
+    ) -> Result<PinToken> {
+        let mut parameters = vec![(0x09.into(), permissions.into())];
+        if let Some(rp_id) = rp_id {
+            parameters.push((0x0A.into(), rp_id.into()));
+        }
+        self.request_pin_token(
+            pin,
+            Subcommand::GetPinUvAuthTokenUsingPinWithPermissions,
+            parameters,
+        )
+    }
+
+    fn request_pin_token(
+        &mut self,
+        pin: &str,
+        subcommand: Subcommand,
+        mut parameters: Vec<(Value, Value)>,
+    ) -> Result<PinToken> {
+        let protocol = self.pin_protocol()?;
+        let (key_agreement, shared_secret) = self.key_agreement(protocol)?;
+
+        let pin_hash_enc = shared_secret.encrypt(&pin_hash(pin))?;
+        parameters.push((0x03.into(), key_agreement));
+        parameters.push((0x06.into(), pin_hash_enc.into()));
+        let response = self
+            .client_pin(protocol, subcommand, parameters)?
+            .ok_or_else(|| Error::Protocol("missing pinUvAuthToken".to_string()))?;
+        let token_enc = response
+            .get(0x02)
+            .and_then(Value::as_bytes)
+            .ok_or_else(|| Error::Protocol("missing pinUvAuthToken".to_string()))?;
+
+        Ok(PinToken {
+            protocol,
+            token: shared_secret.decrypt(token_enc)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pin_padding() {
+        assert!(padded_pin("123").is_err());
+        assert!(padded_pin(&"1".repeat(64)).is_err());
+        let padded = padded_pin("1234").unwrap();
+        assert_eq!(padded.len(), 64);
+        assert_eq!(&padded[..5], b"1234\0");
+    }
+
+    #[test]
+    fn encryption_roundtrip() {
+        for protocol in [PinProtocol::One, PinProtocol::Two] {
+            let shared_secret = SharedSecret::derive(protocol, &[0x42; 32]);
+            let plaintext = padded_pin("correct horse").unwrap();
+            let ciphertext = shared_secret.encrypt(&plaintext).unwrap();
+            assert_eq!(shared_secret.decrypt(&ciphertext).unwrap(), plaintext);
+        }
+        let one = SharedSecret::derive(PinProtocol::One, &[0x42; 32]);
+        assert_eq!(one.authenticate(b"message").len(), 16);
+        let two = SharedSecret::derive(PinProtocol::Two, &[0x42; 32]);
+        assert_eq!(two.authenticate(b"message").len(), 32);
+    }
+}
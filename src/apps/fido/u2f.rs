@@ -0,0 +1,309 @@
+//! U2F (CTAP1) REGISTER and AUTHENTICATE, as APDUs wrapped in CTAPHID_MSG.
+//!
+//! U2F has no keepalives: while the authenticator waits for user presence, it answers
+//! with "conditions not satisfied" (6985), and the request is repeated until it succeeds.
+
+use std::time::{Duration, Instant};
+
+use super::App;
+use crate::{
+    cose::{PublicKey, ES256},
+    pki::Certificate,
+    transport::{
+        ctap::{Code, Command, Status},
+        StatusWord,
+    },
+    Error, Result,
+};
+
+const REGISTER: u8 = 0x01;
+const AUTHENTICATE: u8 = 0x02;
+const VERSION: u8 = 0x03;
+
+const ENFORCE_USER_PRESENCE_AND_SIGN: u8 = 0x03;
+const CHECK_ONLY: u8 = 0x07;
+
+/// How long to keep repeating requests waiting for user presence, unless the device
+/// has its own [`cancel_after`][crate::device::ctap::Device::cancel_after].
+pub const PRESENCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Response to REGISTER.
+#[derive(Clone, Debug)]
+pub struct Registration {
+    /// uncompressed P-256 point
+    pub public_key: Vec<u8>,
+    pub key_handle: Vec<u8>,
+    pub attestation_certificate: Certificate,
+    /// DER encoded ECDSA signature
+    pub signature: Vec<u8>,
+}
+
+/// Response to AUTHENTICATE.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Authentication {
+    pub user_present: bool,
+    pub counter: u32,
+    /// DER encoded ECDSA signature
+    pub signature: Vec<u8>,
+}
+
+fn verify(public_key: &[u8], message: &[u8], signature: &[u8]) -> Result<()> {
+    PublicKey::from_uncompressed(ES256, public_key)?.verify(message, signature)
+}
+
+impl TryFrom<&[u8]> for Registration {
+    type Error = Error;
+    fn try_from(response: &[u8]) -> Result<Self> {
+        use x509_parser::prelude::{FromDer, X509Certificate};
+
+        let too_short = || Error::Protocol("REGISTER response too short".to_string());
+        if response.len() < 67 {
+            return Err(too_short());
+        }
+        if response[0] != 0x05 {
+            return Err(Error::Protocol(
+                "REGISTER response has no reserved byte".to_string(),
+            ));
+        }
+        let public_key = response[1..66].to_vec();
+        let key_handle_length = response[66] as usize;
+        let rest = &response[67..];
+        if rest.len() < key_handle_length {
+            return Err(too_short());
+        }
+        let (key_handle, rest) = rest.split_at(key_handle_length);
+
+        // the certificate's DER encoding tells its length, the signature follows
+        let (remaining, _) = X509Certificate::from_der(rest)?;
+        let (certificate, signature) = rest.split_at(rest.len() - remaining.len());
+
+        Ok(Self {
+            public_key,
+            key_handle: key_handle.to_vec(),
+            attestation_certificate: Certificate::try_from_der(certificate)?,
+            signature: signature.to_vec(),
+        })
+    }
+}
+
+impl Registration {
+    /// Verify the signature with the attestation certificate's public key.
+    ///
+    /// This does not verify the certificate itself.
+    pub fn verify(&self, challenge: &[u8; 32], application: &[u8; 32]) -> Result<()> {
+        let mut message = vec![0x00];
+        message.extend_from_slice(application);
+        message.extend_from_slice(challenge);
+        message.extend_from_slice(&self.key_handle);
+        message.extend_from_slice(&self.public_key);
+
+        let certificate = self.attestation_certificate.certificate();
+        verify(
+            certificate.public_key().subject_public_key.data.as_ref(),
+            &message,
+            &self.signature,
+        )
+    }
+}
+
+impl TryFrom<&[u8]> for Authentication {
+    type Error = Error;
+    fn try_from(response: &[u8]) -> Result<Self> {
+        if response.len() < 5 {
+            return Err(Error::Protocol(
+                "AUTHENTICATE response too short".to_string(),
+            ));
+        }
+        Ok(Self {
+            user_present: response[0] & 1 != 0,
+            counter: u32::from_be_bytes(response[1..5].try_into()?),
+            signature: response[5..].to_vec(),
+        })
+    }
+}
+
+impl Authentication {
+    /// Verify the signature with the public key obtained during registration.
+    pub fn verify(
+        &self,
+        public_key: &[u8],
+        challenge: &[u8; 32],
+        application: &[u8; 32],
+    ) -> Result<()> {
+        let mut message = application.to_vec();
+        message.push(self.user_present as u8);
+        message.extend_from_slice(&self.counter.to_be_bytes());
+        message.extend_from_slice(challenge);
+        verify(public_key, &message, &self.signature)
+    }
+}
+
+/// Extended length encoding, as U2F over HID requires.
+fn apdu(instruction: u8, p1: u8, data: &[u8]) -> Vec<u8> {
+    let mut apdu = vec![0x00, instruction, p1, 0x00, 0x00];
+    if !data.is_empty() {
+        apdu.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        apdu.extend_from_slice(data);
+    }
+    apdu.extend_from_slice(&[0x00, 0x00]);
+    apdu
+}
+
+impl App<'_> {
+    /// Send a U2F APDU via CTAPHID_MSG, returning response data and status word.
+    pub fn msg(&mut self, apdu: &[u8]) -> Result<(Vec<u8>, StatusWord)> {
+        let init = self.init()?;
+        if !init.can_msg {
+            return Err(Error::Unsupported("U2F on this device".to_string()));
+        }
+        let mut response = self.call(init.channel, Command::new(Code::Msg).with_data(apdu)?)?;
+        if response.len() < 2 {
+            return Err(Error::Protocol("U2F response without status".to_string()));
+        }
+        let sw = response.split_off(response.len() - 2);
+        Ok((response, StatusWord(u16::from_be_bytes([sw[0], sw[1]]))))
+    }
+
+    /// Repeat a request until the user confirms presence.
+    fn msg_with_presence(&mut self, apdu: &[u8]) -> Result<Vec<u8>> {
+        let deadline = Instant::now() + self.cancel_after.unwrap_or(PRESENCE_TIMEOUT);
+        loop {
+            match self.msg(apdu)? {
+                (response, StatusWord::SUCCESS) => return Ok(response),
+                (_, StatusWord::CONDITIONS_NOT_SATISFIED) => {
+                    if let Some(observer) = self.keepalive.as_deref() {
+                        observer(Status::UserPresenceNeeded);
+                    }
+                    if self.cancel.take() {
+                        return Err(Error::Aborted);
+                    }
+                    if Instant::now() >= deadline {
+                        return Err(Error::UserPresence);
+                    }
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+                (_, sw) => return Err(Error::Status(sw)),
+            }
+        }
+    }
+
+    /// U2F protocol version, should be `U2F_V2`.
+    pub fn u2f_version(&mut self) -> Result<String> {
+        match self.msg(&apdu(VERSION, 0, &[]))? {
+            (version, StatusWord::SUCCESS) => String::from_utf8(version)
+                .map_err(|_| Error::Protocol("version is not UTF-8".to_string())),
+            (_, sw) => Err(Error::Status(sw)),
+        }
+    }
+
+    /// REGISTER a new key pair for the application (SHA-256 of the AppID).
+    pub fn u2f_register(
+        &mut self,
+        challenge: &[u8; 32],
+        application: &[u8; 32],
+    ) -> Result<Registration> {
+        let mut data = challenge.to_vec();
+        data.extend_from_slice(application);
+        let response = self.msg_with_presence(&apdu(REGISTER, 0, &data))?;
+        Registration::try_from(response.as_slice())
+    }
+
+    /// AUTHENTICATE with a registered key handle, requiring user presence.
+    pub fn u2f_authenticate(
+        &mut self,
+        challenge: &[u8; 32],
+        application: &[u8; 32],
+        key_handle: &[u8],
+    ) -> Result<Authentication> {
+        let data = authenticate_data(challenge, application, key_handle)?;
+        let response =
+            self.msg_with_presence(&apdu(AUTHENTICATE, ENFORCE_USER_PRESENCE_AND_SIGN, &data))?;
+        Authentication::try_from(response.as_slice())
+    }
+
+    /// Does the authenticator recognize the key handle for the application?
+    pub fn u2f_check(&mut self, application: &[u8; 32], key_handle: &[u8]) -> Result<bool> {
+        let data = authenticate_data(&[0; 32], application, key_handle)?;
+        match self.msg(&apdu(AUTHENTICATE, CHECK_ONLY, &data))? {
+            (_, StatusWord::CONDITIONS_NOT_SATISFIED) => Ok(true),
+            (_, StatusWord::WRONG_DATA) => Ok(false),
+            (_, sw) => Err(Error::Status(sw)),
+        }
+    }
+}
+
+fn authenticate_data(
+    challenge: &[u8; 32],
+    application: &[u8; 32],
+    key_handle: &[u8],
+) -> Result<Vec<u8>> {
+    let key_handle_length = u8::try_from(key_handle.len())
+        .map_err(|_| Error::InvalidInput("key handle too long".to_string()))?;
+    let mut data = challenge.to_vec();
+    data.extend_from_slice(application);
+    data.push(key_handle_length);
+    data.extend_from_slice(key_handle);
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hex_literal::hex;
+
+    #[test]
+    fn extended_length_apdus() {
+        assert_eq!(apdu(VERSION, 0, &[]), hex!("00030000 000000"));
+        assert_eq!(
+            apdu(AUTHENTICATE, CHECK_ONLY, &[0xAA; 3]),
+            hex!("00020700 000003 aaaaaa 0000")
+        );
+    }
+
+    #[test]
+    fn parse_authentication() {
+        let authentication = Authentication::try_from(&hex!("01 0000002a 3006")[..]).unwrap();
+        assert!(authentication.user_present);
+        assert_eq!(authentication.counter, 42);
+        assert_eq!(authentication.signature, hex!("3006"));
+        assert!(Authentication::try_from(&hex!("01 0000")[..]).is_err());
+    }
+
+    #[test]
+    fn verify_authentication() {
+        use ring::{rand::SystemRandom, signature, signature::KeyPair as _};
+
+        let rng = SystemRandom::new();
+        let algorithm = &signature::ECDSA_P256_SHA256_ASN1_SIGNING;
+        let pkcs8 = signature::EcdsaKeyPair::generate_pkcs8(algorithm, &rng).unwrap();
+        let key_pair = signature::EcdsaKeyPair::from_pkcs8(algorithm, pkcs8.as_ref()).unwrap();
+
+        let (challenge, application) = ([1; 32], [2; 32]);
+        let mut message = application.to_vec();
+        message.extend_from_slice(&hex!("01 00000007"));
+        message.extend_from_slice(&challenge);
+        let mut authentication = Authentication {
+            user_present: true,
+            counter: 7,
+            signature: key_pair.sign(&rng, &message).unwrap().as_ref().to_vec(),
+        };
+        let public_key = key_pair.public_key().as_ref();
+        assert!(authentication
+            .verify(public_key, &challenge, &application)
+            .is_ok());
+
+        authentication.counter = 8;
+        assert!(authentication
+            .verify(public_key, &challenge, &application)
+            .is_err());
+    }
+
+    #[test]
+    fn truncated_registration() {
+        let mut response = vec![0x05];
+        response.extend_from_slice(&[0x04; 65]);
+        response.push(64);
+        response.extend_from_slice(&[0xAA; 10]);
+        assert!(Registration::try_from(response.as_slice()).is_err());
+    }
+}
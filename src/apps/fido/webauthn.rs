@@ -0,0 +1,461 @@
+//! authenticatorMakeCredential and authenticatorGetAssertion, with verification
+//! of the packed attestation statement and assertion signatures.
+//!
+//! cf. the [WebAuthn specification][webauthn] for the data formats.
+//!
+//! [webauthn]: https://www.w3.org/TR/webauthn-2/#sctn-authenticator-data
+
+use sha2::{Digest as _, Sha256};
+
+use super::{credential_management::User, App, Ctap2Command, PinToken};
+use crate::{
+    cbor::Value,
+    cose::{PublicKey, EDDSA, ES256},
+    pki::Certificate,
+    Error, Result,
+};
+
+const FLAG_USER_PRESENT: u8 = 1 << 0;
+const FLAG_USER_VERIFIED: u8 = 1 << 2;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 1 << 6;
+const FLAG_EXTENSIONS: u8 = 1 << 7;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AttestedCredential {
+    pub aaguid: [u8; 16],
+    pub id: Vec<u8>,
+    pub public_key: PublicKey,
+}
+
+/// Authenticator data, as signed by attestations and assertions.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AuthenticatorData {
+    pub rp_id_hash: [u8; 32],
+    pub flags: u8,
+    pub counter: u32,
+    pub attested_credential: Option<AttestedCredential>,
+    pub extensions: Option<Value>,
+}
+
+impl AuthenticatorData {
+    pub fn user_present(&self) -> bool {
+        self.flags & FLAG_USER_PRESENT != 0
+    }
+
+    pub fn user_verified(&self) -> bool {
+        self.flags & FLAG_USER_VERIFIED != 0
+    }
+}
+
+fn error(reason: &str) -> Error {
+    Error::Protocol(format!("authenticator data: {}", reason))
+}
+
+fn take(data: &[u8], n: usize) -> Result<(&[u8], &[u8])> {
+    if data.len() < n {
+        return Err(error("truncated"));
+    }
+    Ok(data.split_at(n))
+}
+
+impl TryFrom<&[u8]> for AuthenticatorData {
+    type Error = Error;
+    fn try_from(data: &[u8]) -> Result<Self> {
+        let (rp_id_hash, data) = take(data, 32)?;
+        let (flags, data) = take(data, 1)?;
+        let (counter, mut data) = take(data, 4)?;
+        let flags = flags[0];
+
+        let attested_credential = if flags & FLAG_ATTESTED_CREDENTIAL != 0 {
+            let (aaguid, rest) = take(data, 16)?;
+            let (length, rest) = take(rest, 2)?;
+            let (id, rest) = take(rest, u16::from_be_bytes(length.try_into()?) as usize)?;
+            let (public_key, rest) = Value::decode(rest)?;
+            data = rest;
+            Some(AttestedCredential {
+                aaguid: aaguid.try_into()?,
+                id: id.to_vec(),
+                public_key: PublicKey::try_from(&public_key)?,
+            })
+        } else {
+            None
+        };
+
+        let extensions = if flags & FLAG_EXTENSIONS != 0 {
+            let (extensions, rest) = Value::decode(data)?;
+            data = rest;
+            Some(extensions)
+        } else {
+            None
+        };
+
+        if !data.is_empty() {
+            return Err(error("trailing data"));
+        }
+
+        Ok(Self {
+            rp_id_hash: rp_id_hash.try_into()?,
+            flags,
+            counter: u32::from_be_bytes(counter.try_into()?),
+            attested_credential,
+            extensions,
+        })
+    }
+}
+
+/// Response to authenticatorMakeCredential.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Attestation {
+    /// attestation statement format, e.g. `packed`
+    pub format: String,
+    /// raw authenticator data, as signed
+    pub auth_data: Vec<u8>,
+    pub authenticator_data: AuthenticatorData,
+    pub statement: Value,
+}
+
+/// Response to authenticatorGetAssertion.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Assertion {
+    pub credential_id: Option<Vec<u8>>,
+    /// raw authenticator data, as signed
+    pub auth_data: Vec<u8>,
+    pub authenticator_data: AuthenticatorData,
+    pub signature: Vec<u8>,
+    pub user_id: Option<Vec<u8>>,
+}
+
+fn signed_message(auth_data: &[u8], client_data_hash: &[u8; 32]) -> Vec<u8> {
+    let mut message = auth_data.to_vec();
+    message.extend_from_slice(client_data_hash);
+    message
+}
+
+fn certificate_key(certificate: &Certificate) -> Result<PublicKey> {
+    PublicKey::from_uncompressed(
+        ES256,
+        certificate
+            .certificate()
+            .public_key()
+            .subject_public_key
+            .data
+            .as_ref(),
+    )
+    .map_err(|_| Error::Certificate("attestation key is not P-256".to_string()))
+}
+
+impl TryFrom<&Value> for Attestation {
+    type Error = Error;
+    fn try_from(response: &Value) -> Result<Self> {
+        let format = response
+            .get(0x01)
+            .and_then(Value::as_text)
+            .ok_or_else(|| Error::Protocol("missing fmt".to_string()))?;
+        let auth_data = response
+            .get(0x02)
+            .and_then(Value::as_bytes)
+            .ok_or_else(|| Error::Protocol("missing authData".to_string()))?;
+        let statement = response
+            .get(0x03)
+            .ok_or_else(|| Error::Protocol("missing attStmt".to_string()))?;
+        let authenticator_data = AuthenticatorData::try_from(auth_data)?;
+        if authenticator_data.attested_credential.is_none() {
+            return Err(error("no attested credential"));
+        }
+        Ok(Self {
+            format: format.to_string(),
+            auth_data: auth_data.to_vec(),
+            authenticator_data,
+            statement: statement.clone(),
+        })
+    }
+}
+
+impl Attestation {
+    pub fn credential(&self) -> &AttestedCredential {
+        // INVARIANT: checked when parsing
+        self.authenticator_data
+            .attested_credential
+            .as_ref()
+            .unwrap()
+    }
+
+    /// The attestation certificate chain (`x5c`), empty for self attestation.
+    pub fn certificates(&self) -> Result<Vec<Certificate>> {
+        let x5c = match self.statement.get("x5c") {
+            Some(x5c) => x5c,
+            None => return Ok(vec![]),
+        };
+        x5c.as_array()
+            .ok_or_else(|| Error::Protocol("x5c is not an array".to_string()))?
+            .iter()
+            .map(|der| {
+                der.as_bytes()
+                    .ok_or_else(|| Error::Protocol("x5c entry is not bytes".to_string()))
+                    .and_then(Certificate::try_from_der)
+            })
+            .collect()
+    }
+
+    /// Verify a packed attestation statement.
+    ///
+    /// With `x5c`, the signature is verified with the leaf certificate, which must equal
+    /// the `trusted` certificate if one is passed (e.g., a dev certificate). Without `x5c`,
+    /// this is self attestation, verified with the credential's own key.
+    ///
+    /// Certificate chains are not verified.
+    pub fn verify(&self, client_data_hash: &[u8; 32], trusted: Option<&Certificate>) -> Result<()> {
+        if self.format != "packed" {
+            return Err(Error::Unsupported(format!(
+                "attestation format {}",
+                self.format
+            )));
+        }
+        let algorithm = self
+            .statement
+            .get("alg")
+            .and_then(Value::as_integer)
+            .ok_or_else(|| Error::Protocol("missing alg".to_string()))?;
+        let signature = self
+            .statement
+            .get("sig")
+            .and_then(Value::as_bytes)
+            .ok_or_else(|| Error::Protocol("missing sig".to_string()))?;
+
+        let key = match (self.certificates()?.first(), trusted) {
+            (Some(leaf), Some(trusted)) if leaf.der() != trusted.der() => {
+                return Err(Error::Certificate(
+                    "attestation certificate is not the trusted one".to_string(),
+                ))
+            }
+            (Some(leaf), _) => certificate_key(leaf)?,
+            (None, Some(_)) => {
+                return Err(Error::Certificate(
+                    "self attestation, but a certificate was expected".to_string(),
+                ))
+            }
+            (None, None) => self.credential().public_key.clone(),
+        };
+        if algorithm != key.algorithm().into() {
+            return Err(Error::Protocol(format!(
+                "attestation algorithm {} does not match key",
+                algorithm
+            )));
+        }
+        key.verify(
+            &signed_message(&self.auth_data, client_data_hash),
+            signature,
+        )
+    }
+}
+
+impl TryFrom<&Value> for Assertion {
+    type Error = Error;
+    fn try_from(response: &Value) -> Result<Self> {
+        let auth_data = response
+            .get(0x02)
+            .and_then(Value::as_bytes)
+            .ok_or_else(|| Error::Protocol("missing authData".to_string()))?;
+        let signature = response
+            .get(0x03)
+            .and_then(Value::as_bytes)
+            .ok_or_else(|| Error::Protocol("missing signature".to_string()))?;
+        Ok(Self {
+            credential_id: response
+                .get(0x01)
+                .and_then(|descriptor| descriptor.get("id"))
+                .and_then(Value::as_bytes)
+                .map(<[u8]>::to_vec),
+            auth_data: auth_data.to_vec(),
+            authenticator_data: AuthenticatorData::try_from(auth_data)?,
+            signature: signature.to_vec(),
+            user_id: response
+                .get(0x04)
+                .and_then(|user| user.get("id"))
+                .and_then(Value::as_bytes)
+                .map(<[u8]>::to_vec),
+        })
+    }
+}
+
+impl Assertion {
+    /// Verify the signature with the credential's public key.
+    pub fn verify(&self, public_key: &PublicKey, client_data_hash: &[u8; 32]) -> Result<()> {
+        public_key.verify(
+            &signed_message(&self.auth_data, client_data_hash),
+            &self.signature,
+        )
+    }
+}
+
+fn check_rp_id_hash(authenticator_data: &AuthenticatorData, rp_id: &str) -> Result<()> {
+    if authenticator_data.rp_id_hash[..] != Sha256::digest(rp_id.as_bytes())[..] {
+        return Err(error("RP ID hash does not match"));
+    }
+    Ok(())
+}
+
+fn credential_descriptor(id: &[u8]) -> Value {
+    Value::Map(vec![
+        ("id".into(), id.into()),
+        ("type".into(), "public-key".into()),
+    ])
+}
+
+impl App<'_> {
+    /// authenticatorMakeCredential, offering ES256 and EdDSA.
+    ///
+    /// Authenticators with a PIN set need a PIN token.
+    pub fn make_credential(
+        &mut self,
+        client_data_hash: &[u8; 32],
+        rp_id: &str,
+        user: &User,
+        resident_key: bool,
+        pin_token: Option<&PinToken>,
+    ) -> Result<Attestation> {
+        let mut user_entity = vec![("id".into(), user.id.as_slice().into())];
+        if let Some(name) = user.name.as_deref() {
+            user_entity.push(("name".into(), name.into()));
+        }
+        if let Some(display_name) = user.display_name.as_deref() {
+            user_entity.push(("displayName".into(), display_name.into()));
+        }
+        let algorithms = [ES256, EDDSA]
+            .into_iter()
+            .map(|algorithm| {
+                Value::Map(vec![
+                    ("alg".into(), algorithm.into()),
+                    ("type".into(), "public-key".into()),
+                ])
+            })
+            .collect();
+
+        let mut parameters = vec![
+            (0x01.into(), client_data_hash[..].into()),
+            (0x02.into(), Value::Map(vec![("id".into(), rp_id.into())])),
+            (0x03.into(), Value::Map(user_entity)),
+            (0x04.into(), Value::Array(algorithms)),
+        ];
+        if resident_key {
+            parameters.push((0x07.into(), Value::Map(vec![("rk".into(), true.into())])));
+        }
+        if let Some(token) = pin_token {
+            parameters.push((0x08.into(), token.authenticate(client_data_hash).into()));
+            parameters.push((0x09.into(), (token.protocol as u8).into()));
+        }
+
+        let response = self
+            .cbor(Ctap2Command::MakeCredential, Some(&Value::Map(parameters)))?
+            .ok_or_else(|| Error::Protocol("empty makeCredential response".to_string()))?;
+        let attestation = Attestation::try_from(&response)?;
+        check_rp_id_hash(&attestation.authenticator_data, rp_id)?;
+        Ok(attestation)
+    }
+
+    /// authenticatorGetAssertion, requiring user presence.
+    ///
+    /// With an empty `allow_list`, the authenticator picks a discoverable credential.
+    pub fn get_assertion(
+        &mut self,
+        client_data_hash: &[u8; 32],
+        rp_id: &str,
+        allow_list: &[&[u8]],
+        pin_token: Option<&PinToken>,
+    ) -> Result<Assertion> {
+        let mut parameters = vec![
+            (0x01.into(), rp_id.into()),
+            (0x02.into(), client_data_hash[..].into()),
+            (0x05.into(), Value::Map(vec![("up".into(), true.into())])),
+        ];
+        if !allow_list.is_empty() {
+            let allow_list = allow_list
+                .iter()
+                .map(|id| credential_descriptor(id))
+                .collect();
+            parameters.push((0x03.into(), Value::Array(allow_list)));
+        }
+        if let Some(token) = pin_token {
+            parameters.push((0x06.into(), token.authenticate(client_data_hash).into()));
+            parameters.push((0x07.into(), (token.protocol as u8).into()));
+        }
+
+        let response = self
+            .cbor(Ctap2Command::GetAssertion, Some(&Value::Map(parameters)))?
+            .ok_or_else(|| Error::Protocol("empty getAssertion response".to_string()))?;
+        let assertion = Assertion::try_from(&response)?;
+        check_rp_id_hash(&assertion.authenticator_data, rp_id)?;
+        Ok(assertion)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ring::{
+        rand::SystemRandom,
+        signature::{self, KeyPair as _},
+    };
+
+    fn auth_data(public_key: &PublicKey, counter: u32) -> Vec<u8> {
+        let mut data = Sha256::digest(b"example.com").to_vec();
+        data.push(FLAG_USER_PRESENT | FLAG_ATTESTED_CREDENTIAL);
+        data.extend_from_slice(&counter.to_be_bytes());
+        data.extend_from_slice(&[0xAA; 16]);
+        data.extend_from_slice(&[0, 4, 1, 2, 3, 4]);
+        data.extend_from_slice(&public_key.to_value().to_vec());
+        data
+    }
+
+    #[test]
+    fn parse_authenticator_data() {
+        let public_key = PublicKey::Ed25519 { x: [7; 32] };
+        let data = auth_data(&public_key, 42);
+        let parsed = AuthenticatorData::try_from(data.as_slice()).unwrap();
+        assert!(parsed.user_present());
+        assert!(!parsed.user_verified());
+        assert_eq!(parsed.counter, 42);
+        assert!(check_rp_id_hash(&parsed, "example.com").is_ok());
+        assert!(check_rp_id_hash(&parsed, "example.org").is_err());
+        let credential = parsed.attested_credential.unwrap();
+        assert_eq!(credential.aaguid, [0xAA; 16]);
+        assert_eq!(credential.id, [1, 2, 3, 4]);
+        assert_eq!(credential.public_key, public_key);
+
+        for length in [0, 36, data.len() - 1] {
+            assert!(AuthenticatorData::try_from(&data[..length]).is_err());
+        }
+        let mut trailing = data;
+        trailing.push(0);
+        assert!(AuthenticatorData::try_from(trailing.as_slice()).is_err());
+    }
+
+    #[test]
+    fn verify_self_attestation() {
+        let rng = SystemRandom::new();
+        let pkcs8 = signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let key_pair = signature::Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let public_key = PublicKey::Ed25519 {
+            x: key_pair.public_key().as_ref().try_into().unwrap(),
+        };
+
+        let client_data_hash = [0x33; 32];
+        let data = auth_data(&public_key, 0);
+        let signature = key_pair.sign(&signed_message(&data, &client_data_hash));
+        let response = Value::Map(vec![
+            (0x01.into(), "packed".into()),
+            (0x02.into(), data.into()),
+            (
+                0x03.into(),
+                Value::Map(vec![
+                    ("alg".into(), EDDSA.into()),
+                    ("sig".into(), signature.as_ref().into()),
+                ]),
+            ),
+        ]);
+        let attestation = Attestation::try_from(&response).unwrap();
+        assert!(attestation.certificates().unwrap().is_empty());
+        assert!(attestation.verify(&client_data_hash, None).is_ok());
+        assert!(attestation.verify(&[0x34; 32], None).is_err());
+    }
+}
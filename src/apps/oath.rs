@@ -1,28 +1,115 @@
 use core::fmt::{self, Write as _};
 
-use anyhow::anyhow;
 use flexiber::{Decodable, Encodable, TaggedSlice};
+use serde::Serialize;
 
-use crate::{Error, Result};
+use crate::{transport::StatusWord, Error, Result, Transport};
 
-// pcsc_app!();
-app!();
+/// Like the apps made with `app!()`, but keeping the answer to SELECT.
+pub struct App<'t> {
+    transport: &'t mut dyn Transport,
+    selection: Selection,
+}
+
+impl<'t> From<&'t mut dyn Transport> for App<'t> {
+    fn from(transport: &'t mut dyn Transport) -> App<'t> {
+        Self {
+            transport,
+            selection: Selection::default(),
+        }
+    }
+}
 
-// impl<'t> crate::apps::PcscSelect<'t> for App<'t> {
 impl<'t> crate::Select<'t> for App<'t> {
     const RID: &'static [u8] = super::Rid::YUBICO;
     const PIX: &'static [u8] = super::Pix::OATH;
-    // fn select(transport: &'t mut dyn Transport) -> Result<Self> {
-    //     return Err(anyhow::anyhow!("OATH app not supported on this transport"));
-    // }
+
+    fn select(transport: &'t mut dyn Transport) -> Result<Self> {
+        let answer = transport.select(Self::application_id())?;
+        let selection = Selection::parse(&answer)?;
+        Ok(Self {
+            transport,
+            selection,
+        })
+    }
+}
+
+/// The answer of the OATH app to SELECT.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Selection {
+    pub version: Vec<u8>,
+    /// Device specific salt, to derive the key from a password.
+    pub salt: Vec<u8>,
+    /// Present while a password is set but not validated.
+    pub challenge: Option<Vec<u8>>,
+}
+
+impl Selection {
+    fn parse(answer: &[u8]) -> Result<Self> {
+        let mut selection = Self::default();
+        for (tag, range) in crate::transport::tlvs(answer)? {
+            let value = answer[range].to_vec();
+            match Tag::try_from(tag) {
+                Ok(Tag::Version) => selection.version = value,
+                Ok(Tag::CredentialId) => selection.salt = value,
+                Ok(Tag::Challenge) => selection.challenge = Some(value),
+                _ => {}
+            }
+        }
+        Ok(selection)
+    }
+}
+
+/// Derive the key of a password (PBKDF2-HMAC-SHA1, 1000 iterations, 16 bytes).
+pub fn derive_key(password: &str, salt: &[u8]) -> [u8; 16] {
+    use ring::pbkdf2;
+    let mut key = [0; 16];
+    // INVARIANT: 1000 is non-zero
+    let iterations = core::num::NonZeroU32::new(1000).unwrap();
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA1,
+        iterations,
+        salt,
+        password.as_bytes(),
+        &mut key,
+    );
+    key
+}
+
+pub(crate) fn hmac_sha1(key: &[u8], message: &[u8]) -> Vec<u8> {
+    use ring::hmac;
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, key);
+    hmac::sign(&key, message).as_ref().to_vec()
 }
 
-#[derive(Clone, Copy, Debug, Eq, Default, PartialEq)]
+/// HOTP (RFC 4226) truncated value, before reduction to the number of digits.
+///
+/// For TOTP, the challenge is the time step; for HOTP, the counter (both big endian).
+pub(crate) fn truncated(digest: Digest, secret: &[u8], challenge: &[u8]) -> u32 {
+    use ring::hmac;
+    let algorithm = match digest {
+        Digest::Sha1 => hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+        Digest::Sha256 => hmac::HMAC_SHA256,
+    };
+    let mac = hmac::sign(&hmac::Key::new(algorithm, secret), challenge);
+    let mac = mac.as_ref();
+    let offset = (mac[mac.len() - 1] & 0xF) as usize;
+    // INVARIANT: offset + 4 <= 19 < mac.len()
+    u32::from_be_bytes(mac[offset..][..4].try_into().unwrap()) & 0x7FFF_FFFF
+}
+
+fn random_challenge() -> Result<[u8; 8]> {
+    let mut challenge = [0u8; 8];
+    getrandom::getrandom(&mut challenge).map_err(|e| Error::Rng(format!("challenge: {}", e)))?;
+    Ok(challenge)
+}
+
+#[derive(Clone, Copy, Debug, Eq, Default, PartialEq, Serialize)]
 pub struct Hotp {
     pub initial_counter: u32,
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
 pub struct Totp {
     pub period: u32,
 }
@@ -33,7 +120,22 @@ impl Default for Totp {
     }
 }
 
-#[derive(Clone, Eq, PartialEq)]
+impl Totp {
+    /// The period encoded in a credential ID such as `60/issuer:label`,
+    /// or the default period if there is no such prefix.
+    pub fn from_credential_id(credential_id: &str) -> Self {
+        credential_id
+            .split_once('/')
+            .filter(|(prefix, _)| !prefix.is_empty() && prefix.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|(prefix, _)| prefix.parse().ok())
+            .filter(|&period| period > 0)
+            .map(|period| Self { period })
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
 pub enum Kind {
     Hotp(Hotp),
     Totp(Totp),
@@ -57,7 +159,8 @@ impl fmt::Debug for Kind {
     }
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
 #[repr(u8)]
 pub enum Digest {
     Sha1 = 0x1,
@@ -70,7 +173,28 @@ impl TryFrom<&str> for Digest {
         Ok(match name.to_uppercase().as_ref() {
             "SHA1" => Self::Sha1,
             "SHA256" => Self::Sha256,
-            name => return Err(anyhow!("Unknown or unimplemented hash algorithm {}", name)),
+            name => {
+                return Err(Error::InvalidInput(format!(
+                    "Unknown or unimplemented hash algorithm {}",
+                    name
+                )))
+            }
+        })
+    }
+}
+
+impl TryFrom<u8> for Digest {
+    type Error = Error;
+    fn try_from(algorithm: u8) -> Result<Self> {
+        Ok(match algorithm {
+            0x1 => Self::Sha1,
+            0x2 => Self::Sha256,
+            algorithm => {
+                return Err(Error::InvalidInput(format!(
+                    "Unknown or unimplemented hash algorithm {:#x}",
+                    algorithm
+                )))
+            }
         })
     }
 }
@@ -114,7 +238,9 @@ impl Secret {
     ///
     /// But 14B = 112b < 128b.
     pub fn from_base32(encoded: &str, digest: Digest) -> Result<Self> {
-        let unshortened = data_encoding::BASE32.decode(encoded.as_bytes())?;
+        let unshortened = data_encoding::BASE32
+            .decode(encoded.as_bytes())
+            .map_err(|e| Error::InvalidInput(format!("secret is not base 32: {}", e)))?;
         let mut shortened = match digest {
             Digest::Sha1 => {
                 use sha1::{Digest, Sha1};
@@ -158,16 +284,21 @@ impl Secret {
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+/// Serialized without the secret.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
 pub struct Credential {
     // add device UUID/serial?
     // pub uuid: [u8; 16],
     pub label: String,
     pub issuer: Option<String>,
+    #[serde(skip)]
     pub secret: Secret,
+    #[serde(flatten)]
     pub kind: Kind,
     pub algorithm: Digest,
     pub digits: u8,
+    /// Calculations require touching the key.
+    pub touch_required: bool,
 }
 
 impl Credential {
@@ -181,6 +312,7 @@ impl Credential {
             kind: Kind::Totp(Totp { period: 30 }),
             algorithm: Digest::default(),
             digits: 6,
+            touch_required: false,
         })
     }
 }
@@ -215,6 +347,16 @@ impl Credential {
 
         key
     }
+
+    /// Reference HOTP calculation (RFC 4226), to cross-check the authenticator.
+    pub fn hotp(&self, counter: u64) -> Result<Code> {
+        let mut response = vec![self.digits];
+        response.extend_from_slice(
+            &truncated(self.algorithm, &self.secret.0, &counter.to_be_bytes()).to_be_bytes(),
+        );
+        Code::from_truncated(&response)
+            .map_err(|_| Error::InvalidInput(format!("invalid number of digits {}", self.digits)))
+    }
 }
 
 impl fmt::Display for Credential {
@@ -227,6 +369,7 @@ impl fmt::Display for Credential {
     }
 }
 
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
 pub struct Authenticate {
     pub label: String,
     pub timestamp: u64,
@@ -247,6 +390,86 @@ impl Authenticate {
     }
 }
 
+/// A one-time password, as calculated by the authenticator.
+///
+/// Displayed as the zero-padded decimal code.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub struct Code {
+    pub digits: u8,
+    pub value: u32,
+    /// Start of the TOTP time step, in seconds since the epoch.
+    pub valid_from: Option<u64>,
+    /// End (exclusive) of the TOTP time step, in seconds since the epoch.
+    pub valid_until: Option<u64>,
+}
+
+impl Code {
+    /// Parse a truncated response (`76 05 <digits> <4 bytes>`).
+    fn from_truncated_response(response: &[u8]) -> Result<Self> {
+        match crate::transport::tlvs(response)?[..] {
+            [(tag, ref range)] if tag == Tag::TruncatedResponse as u8 => {
+                Self::from_truncated(&response[range.clone()])
+            }
+            _ => Err(Error::Protocol(format!(
+                "malformed OATH response {}",
+                hex::encode(response)
+            ))),
+        }
+    }
+
+    /// Parse the value of a truncated response (`<digits> <4 bytes>`).
+    fn from_truncated(value: &[u8]) -> Result<Self> {
+        match *value {
+            // a 31 bit value has at most ten decimal digits
+            [digits, ref truncated @ ..] if truncated.len() == 4 && (1..=10).contains(&digits) => {
+                let truncated = u32::from_be_bytes(truncated.try_into().unwrap()) & 0x7FFFFFFF;
+                Ok(Self {
+                    digits,
+                    value: (u64::from(truncated) % 10u64.pow(digits as _)) as u32,
+                    valid_from: None,
+                    valid_until: None,
+                })
+            }
+            _ => Err(Error::Protocol(format!(
+                "malformed OATH code {}",
+                hex::encode(value)
+            ))),
+        }
+    }
+
+    /// Set the validity window to the time step of `timestamp`.
+    fn in_period(mut self, timestamp: u64, period: u64) -> Self {
+        let step = timestamp / period;
+        self.valid_from = Some(step * period);
+        self.valid_until = Some((step + 1) * period);
+        self
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:0digits$}", self.value, digits = self.digits as usize)
+    }
+}
+
+/// A credential's result of [App::calculate_all].
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Calculated {
+    Code(Code),
+    /// HOTP credentials need an explicit calculation, which advances their counter.
+    Hotp,
+    /// Credentials requiring touch need an explicit calculation.
+    TouchRequired,
+}
+
+impl Calculated {
+    /// Whether the credential needs an explicit calculation.
+    pub fn needs_calculate(&self) -> bool {
+        !matches!(self, Self::Code(_))
+    }
+}
+
 pub enum Command {
     Register(Credential),
     // Authenticate(CredentialId),
@@ -263,9 +486,19 @@ pub enum Tag {
     NameList = 0x72,
     Key = 0x73,
     Challenge = 0x74,
+    Response = 0x75,
+    TruncatedResponse = 0x76,
+    Hotp = 0x77,
+    Property = 0x78,
+    Version = 0x79,
     InitialCounter = 0x7A,
+    Algorithm = 0x7B,
+    Touch = 0x7C,
 }
 
+/// Property (of [Tag::Property]) of credentials that require touch.
+pub const PROPERTY_REQUIRE_TOUCH: u8 = 0x02;
+
 impl TryFrom<u8> for Tag {
     type Error = Error;
     fn try_from(byte: u8) -> Result<Self> {
@@ -275,8 +508,15 @@ impl TryFrom<u8> for Tag {
             0x72 => NameList,
             0x73 => Key,
             0x74 => Challenge,
+            0x75 => Response,
+            0x76 => TruncatedResponse,
+            0x77 => Hotp,
+            0x78 => Property,
+            0x79 => Version,
             0x7A => InitialCounter,
-            byte => return Err(anyhow!("Not a known tag: {}", byte)),
+            0x7B => Algorithm,
+            0x7C => Touch,
+            byte => return Err(Error::Protocol(format!("Not a known tag: {}", byte))),
         })
     }
 }
@@ -297,9 +537,12 @@ impl flexiber::TagLike for Tag {
 pub enum Instruction {
     Put = 0x1,
     Delete = 0x2,
+    SetCode = 0x3,
     Reset = 0x4,
     List = 0xA1,
     Calculate = 0xA2,
+    Validate = 0xA3,
+    CalculateAll = 0xA4,
 }
 
 impl Encodable for Tag {
@@ -323,7 +566,137 @@ impl Decodable<'_> for Tag {
     }
 }
 
+impl<'t> App<'t> {
+    /// Select the app, validate the password (if any) and make the calls, all in one transaction.
+    ///
+    /// Another process selecting another application in between would undo the validation.
+    pub fn with_password<R>(
+        transport: &'t mut dyn Transport,
+        password: Option<&str>,
+        calls: impl FnOnce(&mut App<'_>) -> Result<R>,
+    ) -> Result<R> {
+        let mut calls = Some(calls);
+        let mut result = None;
+        transport.transaction(&mut |transport| {
+            let mut app = <App<'_> as crate::Select<'_>>::select(transport)?;
+            if let Some(password) = password {
+                app.validate(password)?;
+            }
+            // INVARIANT: transactions make their calls once
+            result = Some(calls.take().unwrap()(&mut app)?);
+            Ok(())
+        })?;
+        // INVARIANT: set if the transaction succeeded
+        Ok(result.unwrap())
+    }
+}
+
 impl App<'_> {
+    /// Make the calls without other processes interleaving their commands.
+    fn exclusive<R>(&mut self, calls: impl FnOnce(&mut App<'_>) -> Result<R>) -> Result<R> {
+        let mut calls = Some(calls);
+        let mut result = None;
+        let selection = &mut self.selection;
+        self.transport.transaction(&mut |transport| {
+            let mut app = App {
+                transport,
+                selection: selection.clone(),
+            };
+            // INVARIANT: transactions make their calls once
+            result = Some(calls.take().unwrap()(&mut app)?);
+            *selection = app.selection;
+            Ok(())
+        })?;
+        // INVARIANT: set if the transaction succeeded
+        Ok(result.unwrap())
+    }
+
+    /// The answer to SELECT.
+    pub fn selection(&self) -> &Selection {
+        &self.selection
+    }
+
+    /// Whether the password must be [validated][App::validate] before other commands.
+    pub fn password_required(&self) -> bool {
+        self.selection.challenge.is_some()
+    }
+
+    /// Unlock the app with its password, checking that the app knows the password too.
+    pub fn validate(&mut self, password: &str) -> Result<()> {
+        let challenge = match &self.selection.challenge {
+            Some(challenge) => challenge.clone(),
+            None => return Ok(()),
+        };
+        let key = derive_key(password, &self.selection.salt);
+        let our_challenge = random_challenge()?;
+
+        let mut data = Vec::new();
+        for (tag, value) in [
+            (Tag::Response, hmac_sha1(&key, &challenge)),
+            (Tag::Challenge, Vec::from(our_challenge)),
+        ] {
+            let part = TaggedSlice::from(tag, &value)
+                .map_err(|e| e.kind())?
+                .to_vec()
+                .map_err(|e| e.kind())?;
+            data.extend_from_slice(&part);
+        }
+
+        let response = match self.transport.call(Instruction::Validate as u8, &data) {
+            Err(Error::Status(StatusWord::WRONG_DATA)) => return Err(Error::WrongPassword),
+            result => result?,
+        };
+        let expected = hmac_sha1(&key, &our_challenge);
+        match crate::transport::tlvs(&response)?[..] {
+            [(tag, ref range)]
+                if tag == Tag::Response as u8
+                    && ring::constant_time::verify_slices_are_equal(
+                        &response[range.clone()],
+                        &expected,
+                    )
+                    .is_ok() => {}
+            _ => {
+                return Err(Error::Signature(
+                    "OATH app did not prove knowledge of the password".to_string(),
+                ))
+            }
+        }
+        self.selection.challenge = None;
+        Ok(())
+    }
+
+    /// Set the password (or remove it, with `None`).
+    ///
+    /// An existing password must be [validated][App::validate] first.
+    pub fn set_password(&mut self, password: Option<&str>) -> Result<()> {
+        let mut data = Vec::new();
+        let parts = match password {
+            Some(password) => {
+                let key = derive_key(password, &self.selection.salt);
+                let challenge = random_challenge()?;
+                let mut key_part =
+                    vec![(u8::from(&Kind::Totp(Totp::default())) << 4) + Digest::Sha1 as u8];
+                key_part.extend_from_slice(&key);
+                vec![
+                    (Tag::Key, key_part),
+                    (Tag::Challenge, Vec::from(challenge)),
+                    (Tag::Response, hmac_sha1(&key, &challenge)),
+                ]
+            }
+            None => vec![(Tag::Key, vec![])],
+        };
+        for (tag, value) in parts {
+            let part = TaggedSlice::from(tag, &value)
+                .map_err(|e| e.kind())?
+                .to_vec()
+                .map_err(|e| e.kind())?;
+            data.extend_from_slice(&part);
+        }
+        self.transport
+            .call(Instruction::SetCode as u8, &data)
+            .map(drop)
+    }
+
     /// Returns the credential ID.
     pub fn register(&mut self, credential: Credential) -> Result<String> {
         info!(" registering credential {:?}", &credential);
@@ -332,9 +705,6 @@ impl App<'_> {
         //     struct.pack("<BB", d.oath_type | d.hash_algorithm, d.digits) + secret,
         // )
 
-        // if touch_required:
-        //     data += struct.pack(b">BB", TAG_PROPERTY, PROP_REQUIRE_TOUCH)
-
         // if d.counter > 0:
         //     data += Tlv(TAG_IMF, struct.pack(">I", d.counter))
 
@@ -358,6 +728,11 @@ impl App<'_> {
             .map_err(|e| e.kind())?;
         data.extend_from_slice(&key_part);
 
+        // a tag and value byte, without length
+        if credential.touch_required {
+            data.extend_from_slice(&[Tag::Property as u8, PROPERTY_REQUIRE_TOUCH]);
+        }
+
         if let Kind::Hotp(Hotp { initial_counter }) = credential.kind {
             let counter_part =
                 TaggedSlice::from(Tag::InitialCounter, &initial_counter.to_be_bytes())
@@ -367,10 +742,6 @@ impl App<'_> {
             data.extend_from_slice(&counter_part);
         }
 
-        // TODO: touch....
-        // if touch_required:
-        //     data += struct.pack(b">BB", TAG_PROPERTY, PROP_REQUIRE_TOUCH)
-
         self.transport
             .call(Instruction::Put as u8, &data)
             .map(drop)?;
@@ -378,10 +749,10 @@ impl App<'_> {
         Ok(credential_id)
     }
 
-    /// Very limited implementation, more to come.
+    /// Calculate the TOTP code of a credential at the given timestamp.
     ///
-    /// Does *not* respect non-default TOTP periods.
-    pub fn authenticate(&mut self, authenticate: Authenticate) -> Result<String> {
+    /// The period is taken from the credential ID (e.g., `60/issuer:label`).
+    pub fn authenticate(&mut self, authenticate: Authenticate) -> Result<Code> {
         let mut data = Vec::new();
 
         let credential_id = authenticate.label;
@@ -392,7 +763,8 @@ impl App<'_> {
             .map_err(|e| e.kind())?;
         data.extend_from_slice(&credential_id_part);
 
-        let challenge = authenticate.timestamp / (Totp::default().period as u64);
+        let period = Totp::from_credential_id(&credential_id).period as u64;
+        let challenge = authenticate.timestamp / period;
         let challenge_bytes = challenge.to_be_bytes();
         let challenge_part = TaggedSlice::from(Tag::Challenge, &challenge_bytes)
             .map_err(|e| e.kind())?
@@ -405,12 +777,118 @@ impl App<'_> {
                 .call_iso(0, Instruction::Calculate as u8, 0x00, 0x01, &data)?;
         debug!("response: {}", hex::encode(&response));
 
-        assert_eq!(response[0], 0x76);
-        assert_eq!(response[1], 5);
-        let digits = response[2] as usize;
-        let truncated_code = u32::from_be_bytes(response[3..].try_into().unwrap());
-        let code = (truncated_code & 0x7FFFFFFF) % 10u32.pow(digits as _);
-        Ok(format!("{:0digits$}", code, digits = digits))
+        Ok(Code::from_truncated_response(&response)?.in_period(authenticate.timestamp, period))
+    }
+
+    /// Calculate the HOTP code of a credential, advancing its counter.
+    ///
+    /// Sends no challenge, the authenticator uses its counter instead.
+    pub fn calculate_hotp(&mut self, label: &str) -> Result<Code> {
+        debug!("credential ID: {}", label);
+        let data = TaggedSlice::from(Tag::CredentialId, label.as_bytes())
+            .map_err(|e| e.kind())?
+            .to_vec()
+            .map_err(|e| e.kind())?;
+
+        let response =
+            match self
+                .transport
+                .call_iso(0, Instruction::Calculate as u8, 0x00, 0x01, &data)
+            {
+                Err(Error::Status(StatusWord::NOT_FOUND)) => {
+                    return Err(Error::NotFound(label.to_string()))
+                }
+                result => result?,
+            };
+        debug!("response: {}", hex::encode(&response));
+        Code::from_truncated_response(&response)
+    }
+
+    /// Calculate the TOTP codes of all credentials at the given timestamp, in one command.
+    ///
+    /// HOTP and touch-required credentials are flagged instead. TOTP credentials with
+    /// a non-default period are calculated separately.
+    pub fn calculate_all(&mut self, timestamp: u64) -> Result<Vec<(String, Calculated)>> {
+        // the follow-up calculations must reach this app, too
+        self.exclusive(|app| {
+            let mut codes = app.calculate_all_default_period(timestamp)?;
+
+            // the one challenge is only right for the default period
+            for (id, calculated) in codes.iter_mut() {
+                if !calculated.needs_calculate() && Totp::from_credential_id(id) != Totp::default()
+                {
+                    let authenticate = Authenticate {
+                        label: id.clone(),
+                        timestamp,
+                    };
+                    *calculated = Calculated::Code(app.authenticate(authenticate)?);
+                }
+            }
+
+            Ok(codes)
+        })
+    }
+
+    /// IDs of all credentials, and whether they require touch, from one CALCULATE ALL.
+    ///
+    /// Unlike LIST, CALCULATE ALL reports the touch property.
+    pub fn list_with_touch(&mut self) -> Result<Vec<(String, bool)>> {
+        Ok(self
+            .calculate_all_default_period(0)?
+            .into_iter()
+            .map(|(id, calculated)| (id, calculated == Calculated::TouchRequired))
+            .collect())
+    }
+
+    fn calculate_all_default_period(
+        &mut self,
+        timestamp: u64,
+    ) -> Result<Vec<(String, Calculated)>> {
+        let period = Totp::default().period as u64;
+        let challenge_bytes = (timestamp / period).to_be_bytes();
+        let data = TaggedSlice::from(Tag::Challenge, &challenge_bytes)
+            .map_err(|e| e.kind())?
+            .to_vec()
+            .map_err(|e| e.kind())?;
+
+        let response =
+            self.transport
+                .call_iso(0, Instruction::CalculateAll as u8, 0x00, 0x01, &data)?;
+        debug!("response: {}", hex::encode(&response));
+
+        let tlvs = crate::transport::tlvs(&response)?;
+        let mut codes = Vec::new();
+        for pair in tlvs.chunks(2) {
+            let (id, tag, value) = match pair {
+                [(id_tag, id), (tag, value)] if *id_tag == Tag::CredentialId as u8 => {
+                    (&response[id.clone()], *tag, &response[value.clone()])
+                }
+                _ => {
+                    return Err(Error::Protocol(format!(
+                        "malformed OATH response {}",
+                        hex::encode(&response)
+                    )))
+                }
+            };
+            let id = std::str::from_utf8(id)
+                .map_err(|_| Error::Protocol("credential ID is not UTF-8".to_string()))?
+                .to_string();
+            let calculated = match Tag::try_from(tag)? {
+                Tag::TruncatedResponse => {
+                    Calculated::Code(Code::from_truncated(value)?.in_period(timestamp, period))
+                }
+                Tag::Hotp => Calculated::Hotp,
+                Tag::Touch => Calculated::TouchRequired,
+                tag => {
+                    return Err(Error::Protocol(format!(
+                        "unexpected tag {:?} for credential {}",
+                        tag, id
+                    )))
+                }
+            };
+            codes.push((id, calculated));
+        }
+        Ok(codes)
     }
 
     pub fn delete(&mut self, label: String) -> Result<()> {
@@ -424,9 +902,10 @@ impl App<'_> {
             .map_err(|e| e.kind())?;
         data.extend_from_slice(&credential_id_part);
 
-        self.transport
-            .call(Instruction::Delete as u8, &data)
-            .map(drop)
+        match self.transport.call(Instruction::Delete as u8, &data) {
+            Err(Error::Status(StatusWord::NOT_FOUND)) => Err(Error::NotFound(credential_id)),
+            result => result.map(drop),
+        }
     }
 
     pub fn list(&mut self) -> Result<Vec<String>> {
@@ -446,7 +925,8 @@ impl App<'_> {
                 .map_err(|e| e.kind())?;
             // debug!("{:?}", &hex::encode(data));
             // let kind = data[0] ...
-            let credential_id = std::str::from_utf8(&data[1..])?;
+            let credential_id = std::str::from_utf8(&data[1..])
+                .map_err(|_| Error::Protocol("credential ID is not UTF-8".to_string()))?;
             trace!("{:?}", &credential_id);
             labels.push(credential_id.to_string());
             if decoder.is_finished() {
@@ -457,8 +937,120 @@ impl App<'_> {
 
     pub fn reset(&mut self) -> Result<()> {
         self.transport
-            .call_iso(0, Instruction::Reset as u8, 0xDE, 0xAD, &[])
-            .map(drop)
-        // _, self._salt, self._challenge = _parse_select(self.protocol.select(AID.OATH))
+            .call_iso(0, Instruction::Reset as u8, 0xDE, 0xAD, &[])?;
+        // resetting removes the password
+        self.selection.challenge = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn serialize_credential() {
+        let credential =
+            Credential::default_totp("alice", "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap();
+        let json = serde_json::to_value(&credential).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "label": "alice",
+                "issuer": null,
+                "kind": "totp",
+                "period": 30,
+                "algorithm": "sha1",
+                "digits": 6,
+                "touch_required": false,
+            })
+        );
+    }
+
+    #[test]
+    fn rfc4226_truncation() {
+        // RFC 4226, appendix D
+        let secret = b"12345678901234567890";
+        let expected = [1284755224u32, 1094287082, 137359152];
+        for (counter, expected) in expected.into_iter().enumerate() {
+            let challenge = (counter as u64).to_be_bytes();
+            assert_eq!(truncated(Digest::Sha1, secret, &challenge), expected);
+        }
+    }
+
+    #[test]
+    fn reference_hotp() {
+        let mut credential =
+            Credential::default_totp("hotp", "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap();
+        credential.kind = Kind::Hotp(Hotp::default());
+        // RFC 4226, appendix D
+        let expected = [
+            "755224", "287082", "359152", "969429", "338314", "254676", "287922", "162583",
+            "399871", "520489",
+        ];
+        for (counter, expected) in expected.iter().enumerate() {
+            assert_eq!(
+                credential.hotp(counter as u64).unwrap().to_string(),
+                *expected
+            );
+        }
+
+        credential.digits = 0;
+        assert!(matches!(credential.hotp(0), Err(Error::InvalidInput(_))));
+    }
+
+    #[test]
+    fn parse_selection() {
+        let answer = hex::decode("79030404007108010203040506070874080807060504030201").unwrap();
+        let selection = Selection::parse(&answer).unwrap();
+        assert_eq!(selection.version, [4, 4, 0]);
+        assert_eq!(selection.salt, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(selection.challenge, Some(vec![8, 7, 6, 5, 4, 3, 2, 1]));
+        assert_eq!(Selection::parse(&[]).unwrap(), Selection::default());
+    }
+
+    #[test]
+    fn password_key() {
+        assert_eq!(
+            hex::encode(derive_key("password", &[1, 2, 3, 4, 5, 6, 7, 8])),
+            "ed1b5a43d3a86504dd13c9da7606bd35"
+        );
+    }
+
+    #[test]
+    fn period_from_credential_id() {
+        assert_eq!(Totp::from_credential_id("60/issuer:label").period, 60);
+        assert_eq!(Totp::from_credential_id("15/label").period, 15);
+        assert_eq!(Totp::from_credential_id("issuer:label").period, 30);
+        assert_eq!(Totp::from_credential_id("a/b:label").period, 30);
+        assert_eq!(Totp::from_credential_id("0/label").period, 30);
+        assert_eq!(Totp::from_credential_id("/label").period, 30);
+    }
+
+    #[test]
+    fn parse_truncated_response() {
+        let code = Code::from_truncated_response(&hex::decode("7605063b9acaea").unwrap()).unwrap();
+        assert_eq!(code.value, 234);
+        assert_eq!(code.to_string(), "000234");
+
+        let code = Code::from_truncated_response(&hex::decode("760508ffffffff").unwrap()).unwrap();
+        assert_eq!(code.value, 47483647);
+        assert_eq!(code.to_string(), "47483647");
+
+        for malformed in [
+            "",
+            "7605",
+            "760406499602",
+            "770506499602d2",
+            "760500499602d2",
+            "76050b499602d2",
+            "760506499602d27600",
+        ] {
+            let response = hex::decode(malformed).unwrap();
+            assert!(matches!(
+                Code::from_truncated_response(&response),
+                Err(Error::Protocol(_))
+            ));
+        }
     }
 }
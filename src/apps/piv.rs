@@ -1,6 +1,78 @@
+use crate::{transport::StatusWord, Error, Result};
+
 app!();
 
 impl<'t> crate::Select<'t> for App<'t> {
     const RID: &'static [u8] = super::Rid::NIST;
     const PIX: &'static [u8] = super::Pix::PIV;
 }
+
+const VERIFY: u8 = 0x20;
+/// Key reference of the PIV card application PIN
+const PIN_REFERENCE: u8 = 0x80;
+
+impl App<'_> {
+    /// Verify the PIV PIN (6 to 8 characters).
+    ///
+    /// If it is wrong, this fails with [`WrongPin`][Error::WrongPin] carrying
+    /// the remaining retries.
+    pub fn verify_pin(&mut self, pin: &str) -> Result<()> {
+        if !(6..=8).contains(&pin.len()) {
+            return Err(Error::InvalidInput(
+                "PIV PIN must have 6 to 8 characters".to_string(),
+            ));
+        }
+        let mut data = pin.as_bytes().to_vec();
+        data.resize(8, 0xFF);
+        self.transport
+            .call_iso(0, VERIFY, 0x00, PIN_REFERENCE, &data)
+            .map(drop)
+            .map_err(wrong_pin)
+    }
+
+    /// Remaining PIN retries, or `None` if the PIN is already verified in this session.
+    pub fn pin_retries(&mut self) -> Result<Option<u8>> {
+        match self.transport.call_iso(0, VERIFY, 0x00, PIN_REFERENCE, &[]) {
+            Ok(_) => Ok(None),
+            Err(Error::Status(sw)) if sw.retries().is_some() => Ok(sw.retries()),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+fn wrong_pin(error: Error) -> Error {
+    match error {
+        Error::Status(sw) => match sw.retries() {
+            Some(retries) => Error::WrongPin { retries },
+            None if sw == StatusWord::AUTHENTICATION_BLOCKED => Error::WrongPin { retries: 0 },
+            None => Error::Status(sw),
+        },
+        error => error,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn retries_from_status() {
+        assert!(matches!(
+            wrong_pin(Error::Status(StatusWord(0x63C2))),
+            Error::WrongPin { retries: 2 }
+        ));
+        assert!(matches!(
+            wrong_pin(Error::Status(StatusWord::AUTHENTICATION_BLOCKED)),
+            Error::WrongPin { retries: 0 }
+        ));
+        assert!(matches!(
+            wrong_pin(Error::Status(StatusWord::NOT_FOUND)),
+            Error::Status(StatusWord::NOT_FOUND)
+        ));
+        assert_eq!(
+            StatusWord(0x63C2).to_string(),
+            "63C2 (verification failed, 2 retries left)"
+        );
+        assert_eq!(StatusWord(0x6A82).to_string(), "6A82 (not found)");
+    }
+}
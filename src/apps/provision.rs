@@ -1,7 +1,6 @@
-use anyhow::anyhow;
 use iso7816::Instruction;
 
-use crate::Result;
+use crate::{Error, Result, Select as _};
 
 app!();
 
@@ -89,32 +88,47 @@ impl App<'_> {
         let bytes: &[u8] = &version_bytes;
         bytes
             .try_into()
-            .map_err(|_| anyhow::anyhow!("expected 16 byte UUID, got {}", &hex::encode(bytes)))
+            .map_err(|_| {
+                Error::Protocol(format!(
+                    "expected 16 byte UUID, got {}",
+                    &hex::encode(bytes)
+                ))
+            })
             .map(u128::from_be_bytes)
     }
 
     pub fn write_file(&mut self, data: &[u8], path: &str) -> Result<()> {
         if data.len() > 8192 {
-            return Err(anyhow!("data too long (8192 byte limit)"));
+            return Err(Error::InvalidInput(
+                "data too long (8192 byte limit)".to_string(),
+            ));
         }
-        if path.as_bytes().len() > 128 {
-            return Err(anyhow!("path {} too long (128 byte limit)", path));
+        if path.len() > 128 {
+            return Err(Error::InvalidInput(format!(
+                "path {} too long (128 byte limit)",
+                path
+            )));
         }
 
-        self.transport
-            .call(Instruction::Select.into(), &Self::PATH_ID)
-            .map(drop)?;
-        self.transport
-            .call(Instruction::WriteBinary.into(), path.as_bytes())
-            .map(drop)?;
-
-        self.transport
-            .call(Instruction::Select.into(), &Self::DATA_ID)
-            .map(drop)?;
-        self.transport
-            .call(Instruction::WriteBinary.into(), data)
-            .map(drop)?;
-
-        self.transport.instruct(Self::WRITE_FILE).map(drop)
+        // another process selecting another application in between would break this
+        self.transport.transaction(&mut |transport| {
+            transport.select(Self::application_id())?;
+
+            transport
+                .call(Instruction::Select.into(), &Self::PATH_ID)
+                .map(drop)?;
+            transport
+                .call(Instruction::WriteBinary.into(), path.as_bytes())
+                .map(drop)?;
+
+            transport
+                .call(Instruction::Select.into(), &Self::DATA_ID)
+                .map(drop)?;
+            transport
+                .call(Instruction::WriteBinary.into(), data)
+                .map(drop)?;
+
+            transport.instruct(Self::WRITE_FILE).map(drop)
+        })
     }
 }
@@ -34,6 +34,33 @@ pub struct GlobalOptions {
     #[clap(global = true, help_heading = "TRANSPORT", long)]
     pub pcsc: bool,
 
+    /// Cancel CTAP requests still waiting for user presence after this many seconds.
+    #[clap(
+        global = true,
+        help_heading = "TRANSPORT",
+        long,
+        value_name = "SECONDS"
+    )]
+    pub timeout: Option<u64>,
+
+    /// Record the traffic of app commands, raw APDUs and CTAPHID packets to a JSON lines transcript.
+    #[clap(global = true, help_heading = "TRANSPORT", long, value_name = "FILE")]
+    pub record: Option<String>,
+
+    /// Do not redact secrets (e.g. OATH keys) in the transcript.
+    #[clap(global = true, help_heading = "TRANSPORT", long, requires = "record")]
+    pub record_secrets: bool,
+
+    /// Run app commands against a transcript recorded with `--record`, instead of a device.
+    #[clap(
+        global = true,
+        help_heading = "TRANSPORT",
+        long,
+        value_name = "FILE",
+        conflicts_with = "record"
+    )]
+    pub replay: Option<String>,
+
     /// Specify UUID of a Solo 2 device.
     #[clap(global = true, help_heading = "SELECTION", long, short)]
     pub uuid: Option<String>,
@@ -49,6 +76,10 @@ pub struct GlobalOptions {
     )]
     pub all: bool,
 
+    /// Output format; `json` prints one JSON document (or event) per line.
+    #[clap(global = true, long, value_enum, default_value = "text")]
+    pub output: Output,
+
     /// Verbosity level (can be specified multiple times)
     #[clap(flatten)]
     pub verbose: clap_verbosity_flag::Verbosity<clap_verbosity_flag::WarnLevel>,
@@ -56,6 +87,18 @@ pub struct GlobalOptions {
 
 #[derive(Subcommand)]
 pub enum Subcommands {
+    /// Send raw APDUs via PCSC, interactively if none is given
+    Apdu {
+        /// Select this application first, e.g. A00000084700000001
+        #[clap(long)]
+        aid: Option<String>,
+        /// Pretty-print responses as BER-TLV
+        #[clap(long)]
+        tlv: bool,
+        /// The command APDU (hex), e.g. 00A4040009A00000084700000001
+        apdu: Option<String>,
+    },
+
     #[clap(subcommand)]
     App(Apps),
 
@@ -65,6 +108,9 @@ pub enum Subcommands {
     #[clap(subcommand)]
     Completion(Completion),
 
+    #[clap(subcommand)]
+    Ctaphid(Ctaphid),
+
     /// List all available devices
     #[clap(visible_alias = "ls")]
     List,
@@ -120,6 +166,37 @@ pub enum Completion {
     Zsh,
 }
 
+#[derive(Subcommand)]
+/// Raw CTAPHID commands
+pub enum Ctaphid {
+    /// Measure ping round trips
+    Ping {
+        /// Payload size in bytes (at most 7609), instead of a range of sizes
+        #[clap(long, short)]
+        size: Option<usize>,
+        /// Round trips per payload size
+        #[clap(long, short, default_value_t = 10)]
+        count: u32,
+    },
+    /// Send a vendor command, printing the response
+    Send {
+        /// Vendor command, from 0x40 to 0x7F
+        #[clap(long, value_parser = parse_vendor_code)]
+        cmd: u8,
+        /// Request data (hex)
+        #[clap(long, default_value = "")]
+        data: String,
+    },
+}
+
+fn parse_vendor_code(code: &str) -> Result<u8, String> {
+    let hex = code.trim_start_matches("0x").trim_start_matches("0X");
+    match u8::from_str_radix(hex, 16) {
+        Ok(code @ 0x40..=0x7F) => Ok(code),
+        _ => Err("expected a hex byte from 0x40 to 0x7F".to_string()),
+    }
+}
+
 #[derive(Subcommand)]
 /// PKI-related
 pub enum Pki {
@@ -199,12 +276,55 @@ pub enum Admin {
 #[clap(infer_subcommands = true)]
 /// FIDO app
 pub enum Fido {
+    /// Manage discoverable credentials (prompts for the PIN)
+    #[clap(subcommand)]
+    Credentials(FidoCredentials),
+    /// CTAP2 authenticator info (versions, extensions, options, ...)
+    Info,
     /// FIDO init response
     Init,
+    /// Manage the FIDO2 PIN
+    #[clap(subcommand)]
+    Pin(FidoPin),
+    /// Reset FIDO app, deleting all credentials and the PIN (requires replugging the key)
+    Reset,
+    /// Make a credential and an assertion, verifying attestation and signatures
+    SelfTest {
+        /// Expected attestation certificate in DER format (e.g., from `solo2 pki dev fido`)
+        #[clap(long)]
+        certificate: Option<String>,
+    },
+    /// Register and authenticate via U2F (CTAP1), verifying the signatures
+    U2fCheck,
     /// FIDO wink
     Wink,
 }
 
+#[derive(Subcommand)]
+#[clap(infer_subcommands = true)]
+/// FIDO2 discoverable credentials
+pub enum FidoCredentials {
+    /// Delete a credential
+    Delete {
+        /// Credential ID, in hex (as listed)
+        id: String,
+    },
+    /// List relying parties and their credentials
+    List,
+}
+
+#[derive(Subcommand)]
+#[clap(infer_subcommands = true)]
+/// FIDO2 PIN (prompted for interactively)
+pub enum FidoPin {
+    /// Change the existing PIN
+    Change,
+    /// Number of PIN attempts left
+    Retries,
+    /// Set the initial PIN
+    Set,
+}
+
 #[derive(Subcommand)]
 #[clap(infer_subcommands = true)]
 /// NDEF app
@@ -224,13 +344,29 @@ pub enum Oath {
     /// Print the application's AID
     Aid,
     // Authenticate,
+    /// Calculate the TOTPs of all credentials at once
+    Codes {
+        /// timestamp to use to generate the OTPs, as seconds since the UNIX epoch
+        timestamp: Option<String>,
+    },
     /// Delete existing credential
     Delete {
         /// Label of credential
         label: String,
     },
+    /// Calculate HOTP for a registered credential, advancing its counter
+    Hotp {
+        /// Label of credential
+        label: String,
+    },
     /// List all credentials
     List,
+    /// Set the password protecting the credentials
+    Password {
+        /// remove the password instead
+        #[clap(long)]
+        clear: bool,
+    },
     /// Register new credential
     Register(OathRegister),
     /// Reset OATH app, deleting all credentials
@@ -273,6 +409,17 @@ pub struct OathRegister {
     /// (only TOTP) period in seconds for which a TOTP is valid
     #[clap(default_value = "30", long, short)] //, required_if_eq("kind", "totp"))]
     pub period: u32,
+
+    /// require touching the key to calculate an OTP
+    #[clap(long)]
+    pub touch: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+/// format of command output
+pub enum Output {
+    Text,
+    Json,
 }
 
 // ignore case?
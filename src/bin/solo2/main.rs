@@ -3,8 +3,17 @@ extern crate log;
 
 mod cli;
 
+use std::time::Duration;
+
 use anyhow::anyhow;
-use solo2::{Device, Select as _, Solo2, Uuid, UuidSelectable};
+use solo2::transport::ctap::{CancelHandle, Status};
+use solo2::transport::{RecordingTransport, ReplayTransport};
+use solo2::{Device, Select as _, Solo2, Transport, Uuid, UuidSelectable};
+
+lazy_static::lazy_static! {
+    /// Shared by all CTAP devices, triggered by Ctrl-C.
+    static ref CANCEL: CancelHandle = CancelHandle::new();
+}
 
 fn main() {
     restore_cursor_on_ctrl_c();
@@ -18,7 +27,25 @@ fn main() {
 
     if let Err(err) = try_main(args) {
         eprintln!("Error: {}", err);
-        std::process::exit(1);
+        std::process::exit(exit_code(&err));
+    }
+}
+
+/// Distinct exit codes for the library's error variants, so scripts can react
+/// without parsing error messages.
+///
+/// 2 is left to `clap` (usage errors), 130 to Ctrl-C.
+fn exit_code(err: &anyhow::Error) -> i32 {
+    use solo2::Error::*;
+    match err.downcast_ref::<solo2::Error>() {
+        Some(NoDevice | NoSuchDevice(_) | AmbiguousDevice { .. }) => 3,
+        Some(Hid(_) | Pcsc(_) | Unsupported(_) | Protocol(_)) => 4,
+        Some(Status(_) | NotFound(_) | WrongPin { .. } | WrongPassword) => 5,
+        Some(Ctaphid(_) | Ctap2(_)) => 6,
+        Some(Timeout) => 7,
+        Some(UserPresence | Aborted) => 8,
+        Some(Rollback { .. } | Firmware(_)) => 9,
+        _ => 1,
     }
 }
 
@@ -35,23 +62,48 @@ fn try_main(args: cli::Cli) -> anyhow::Result<()> {
     if args.global_options.pcsc {
         Solo2::prefer_pcsc();
     }
+    let cancel_after = args.global_options.timeout.map(Duration::from_secs);
+    let json = args.global_options.output == cli::Output::Json;
+    let replay = args.global_options.replay.as_ref();
+    let transcript = args
+        .global_options
+        .record
+        .as_ref()
+        .map(|path| -> anyhow::Result<_> {
+            Ok(Transcript {
+                file: std::fs::File::create(path)?,
+                redact: !args.global_options.record_secrets,
+            })
+        })
+        .transpose()?;
 
     // use cli::Subcommands::*;
     use cli::Apps::*;
     match args.subcommand {
         cli::Subcommands::App(app) => {
-            let solo2s: Vec<Solo2> =
-                all_or_unwrap_or_interactively_select(uuid, args.global_options.all, "Solo 2")?;
+            let targets: Vec<Target> = match replay {
+                Some(path) => vec![Target::Replay(ReplayTransport::open(path)?)],
+                None => {
+                    all_or_unwrap_or_interactively_select(uuid, args.global_options.all, "Solo 2")?
+                        .into_iter()
+                        .map(Target::Device)
+                        .collect()
+                }
+            };
 
             // let uuid = solo2.uuid();
 
-            solo2s.into_iter().try_for_each(|mut solo2| {
+            targets.into_iter().try_for_each(|mut target| {
+                if let Target::Device(solo2) = &mut target {
+                    hook_ctap(solo2, cancel_after);
+                }
                 match &app {
                     Admin(admin) => {
                         use cli::Admin::*;
                         use solo2::apps::Admin;
 
-                        let mut app = Admin::select(&mut solo2)?;
+                        let mut transport = target.transport(transcript.as_ref());
+                        let mut app = Admin::select(&mut *transport)?;
 
                         match admin {
                             Aid => {
@@ -60,7 +112,11 @@ fn try_main(args: cli::Cli) -> anyhow::Result<()> {
                             }
                             Locked => {
                                 let locked = app.locked()?;
-                                println!("locked: {}", locked);
+                                if json {
+                                    print_json(&serde_json::json!({ "locked": locked }))?;
+                                } else {
+                                    println!("locked: {}", locked);
+                                }
                             }
                             Restart => {
                                 info!("attempting restart of devices");
@@ -70,16 +126,25 @@ fn try_main(args: cli::Cli) -> anyhow::Result<()> {
                                 // TODO: figure out the correct solution
                                 #[allow(clippy::drop_non_drop)]
                                 drop(app);
+                                drop(transport);
                                 println!("Tap button on key to reboot into bootloader/maintenance mode, or replug to abort...");
-                                solo2.into_lpc55()?;
+                                target.into_device()?.into_lpc55()?;
                             }
                             Uuid => {
                                 let uuid = app.uuid()?;
-                                println!("{:X}", uuid.simple());
+                                if json {
+                                    print_json(&serde_json::json!({ "uuid": format!("{:X}", uuid.simple()) }))?;
+                                } else {
+                                    println!("{:X}", uuid.simple());
+                                }
                             }
                             Version => {
                                 let version = app.version()?;
-                                println!("{}", version.to_calver());
+                                if json {
+                                    print_json(&serde_json::json!({ "version": version, "calver": version.to_calver() }))?;
+                                } else {
+                                    println!("{}", version.to_calver());
+                                }
                             }
                             Wink => {
                                 app.wink()?;
@@ -91,12 +156,176 @@ fn try_main(args: cli::Cli) -> anyhow::Result<()> {
                         use cli::Fido::*;
                         use solo2::apps::Fido;
 
-                        let app = Fido::from(solo2.as_ctap_mut().ok_or_else(|| anyhow!("CTAP unavailable"))?);
+                        if let Reset = fido {
+                            let uuid = target.device()?.uuid();
+                            drop(target);
+                            let mut device = wait_for_replug(uuid)?;
+                            hook_ctap_device(&mut device, cancel_after);
+                            record_ctap(&mut device, transcript.as_ref())?;
+                            Fido::from(&mut device).reset()?;
+                            println!("FIDO app reset");
+                            return Ok(());
+                        }
+
+                        let device = target.device()?.as_ctap_mut().ok_or_else(|| solo2::Error::Unsupported("FIDO without CTAP transport".to_string()))?;
+                        record_ctap(device, transcript.as_ref())?;
+                        let mut app = Fido::from(device);
 
                         match fido {
+                            Credentials(credentials) => {
+                                let pin = prompt_pin("PIN", false)?;
+                                let token = app.credential_management_token(&pin)?;
+                                match credentials {
+                                    cli::FidoCredentials::Delete { id } => {
+                                        let id = hex::decode(id)?;
+                                        app.delete_credential(&token, &id)?;
+                                        println!("credential deleted");
+                                    }
+                                    cli::FidoCredentials::List => {
+                                        let metadata = app.credentials_metadata(&token)?;
+                                        println!(
+                                            "{} credentials stored, space for {} more",
+                                            metadata.existing, metadata.remaining
+                                        );
+                                        for rp in app.relying_parties(&token)? {
+                                            match rp.name {
+                                                Some(name) => println!("{} ({})", rp.id, name),
+                                                None => println!("{}", rp.id),
+                                            }
+                                            for credential in app.credentials(&token, &rp.id_hash)? {
+                                                let user = credential
+                                                    .user
+                                                    .name
+                                                    .or(credential.user.display_name)
+                                                    .unwrap_or_else(|| hex::encode(&credential.user.id));
+                                                println!("  {}: {}", user, hex::encode(&credential.id));
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                            Info => {
+                                let info = app.info()?;
+                                println!("versions: {}", info.versions.join(", "));
+                                println!("extensions: {}", info.extensions.join(", "));
+                                println!("aaguid: {}", hex::encode_upper(info.aaguid));
+                                let options: Vec<String> = info
+                                    .options
+                                    .iter()
+                                    .map(|(option, value)| format!("{}={}", option, value))
+                                    .collect();
+                                println!("options: {}", options.join(", "));
+                                if let Some(max_msg_size) = info.max_msg_size {
+                                    println!("max message size: {}", max_msg_size);
+                                }
+                                let protocols: Vec<String> = info
+                                    .pin_uv_auth_protocols
+                                    .iter()
+                                    .map(|protocol| protocol.to_string())
+                                    .collect();
+                                println!("PIN/UV auth protocols: {}", protocols.join(", "));
+                                if let Some(firmware_version) = info.firmware_version {
+                                    println!("firmware version: {}", firmware_version);
+                                }
+                            }
                             Init => {
                                 println!("{:?}", app.init()?);
                             }
+                            Pin(pin) => match pin {
+                                cli::FidoPin::Change => {
+                                    let current_pin = prompt_pin("Current PIN", false)?;
+                                    let pin = prompt_pin("New PIN", true)?;
+                                    app.change_pin(&current_pin, &pin)?;
+                                    println!("PIN changed");
+                                }
+                                cli::FidoPin::Retries => {
+                                    println!("{}", app.pin_retries()?);
+                                }
+                                cli::FidoPin::Set => {
+                                    let pin = prompt_pin("New PIN", true)?;
+                                    app.set_pin(&pin)?;
+                                    println!("PIN set");
+                                }
+                            },
+                            Reset => unreachable!("handled above"),
+                            SelfTest { certificate } => {
+                                use solo2::apps::fido::credential_management::User;
+
+                                let trusted = certificate
+                                    .as_ref()
+                                    .map(|path| solo2::pki::Certificate::try_from_der(&std::fs::read(path)?).map_err(anyhow::Error::from))
+                                    .transpose()?;
+                                let random = || -> anyhow::Result<[u8; 32]> {
+                                    let mut bytes = [0u8; 32];
+                                    getrandom::getrandom(&mut bytes).map_err(|e| anyhow!("{}", e))?;
+                                    Ok(bytes)
+                                };
+
+                                let token = match app.info()?.options.get("clientPin") {
+                                    Some(true) => Some(app.pin_token(&prompt_pin("PIN", false)?)?),
+                                    _ => None,
+                                };
+
+                                let rp_id = "self-test.solo2.dev";
+                                let user = User {
+                                    id: random()?[..16].to_vec(),
+                                    name: Some("self-test".to_string()),
+                                    display_name: None,
+                                };
+                                let client_data_hash = random()?;
+                                let attestation = app.make_credential(&client_data_hash, rp_id, &user, false, token.as_ref())?;
+                                let credential = attestation.credential();
+                                println!("credential: {}", hex::encode(&credential.id));
+                                println!("algorithm: {}", credential.public_key.algorithm());
+                                println!("aaguid: {}", hex::encode_upper(credential.aaguid));
+                                if let Some(leaf) = attestation.certificates()?.first() {
+                                    println!("attestation certificate: {}", leaf.certificate().subject());
+                                }
+                                attestation.verify(&client_data_hash, trusted.as_ref())?;
+                                println!("attestation ({}): valid", attestation.format);
+
+                                let client_data_hash = random()?;
+                                let assertion = app.get_assertion(&client_data_hash, rp_id, &[&credential.id], token.as_ref())?;
+                                assertion.verify(&credential.public_key, &client_data_hash)?;
+                                println!("assertion signature: valid (counter {})", assertion.authenticator_data.counter);
+                                if assertion.authenticator_data.counter <= attestation.authenticator_data.counter {
+                                    return Err(anyhow!("signature counter did not increase"));
+                                }
+                            }
+                            U2fCheck => {
+                                use sha2::{Digest as _, Sha256};
+
+                                let application: [u8; 32] = Sha256::digest(b"solo2 u2f-check").into();
+                                let mut challenge = [0u8; 32];
+                                getrandom::getrandom(&mut challenge).map_err(|e| anyhow!("{}", e))?;
+
+                                println!("version: {}", app.u2f_version()?);
+                                let registration = app.u2f_register(&challenge, &application)?;
+                                println!("key handle: {}", hex::encode(&registration.key_handle));
+                                println!(
+                                    "attestation certificate: {}",
+                                    registration.attestation_certificate.certificate().subject()
+                                );
+                                registration.verify(&challenge, &application)?;
+                                println!("registration signature: valid");
+
+                                if !app.u2f_check(&application, &registration.key_handle)? {
+                                    return Err(anyhow!("key handle not recognized"));
+                                }
+                                println!("key handle: recognized");
+
+                                let mut counter = None;
+                                for _ in 0..2 {
+                                    getrandom::getrandom(&mut challenge).map_err(|e| anyhow!("{}", e))?;
+                                    let authentication = app.u2f_authenticate(&challenge, &application, &registration.key_handle)?;
+                                    authentication.verify(&registration.public_key, &challenge, &application)?;
+                                    println!("authentication signature: valid (counter {})", authentication.counter);
+                                    if counter.map_or(false, |counter| authentication.counter <= counter) {
+                                        return Err(anyhow!("signature counter did not increase"));
+                                    }
+                                    counter = Some(authentication.counter);
+                                }
+                            }
                             Wink => {
                                 let channel = app.init()?.channel;
                                 app.wink(channel)?;
@@ -108,7 +337,8 @@ fn try_main(args: cli::Cli) -> anyhow::Result<()> {
                         use cli::Ndef::*;
                         use solo2::apps::Ndef;
 
-                        let mut app = Ndef::select(&mut solo2)?;
+                        let mut transport = target.transport(transcript.as_ref());
+                        let mut app = Ndef::select(&mut *transport)?;
 
                         match ndef {
                             Aid => {
@@ -130,24 +360,98 @@ fn try_main(args: cli::Cli) -> anyhow::Result<()> {
                         use cli::Oath::*;
                         use solo2::apps::Oath;
 
-                        let mut app = Oath::select(&mut solo2)?;
+                        let mut transport = target.transport(transcript.as_ref());
+                        // resetting works without the password
+                        let password = if Oath::select(&mut *transport)?.password_required() && !matches!(oath, Aid | Reset) {
+                            Some(prompt_password("OATH password", false)?)
+                        } else {
+                            None
+                        };
+                        let password = password.as_deref();
 
                         match oath {
                             Aid => {
                                 println!("{}", hex::encode(Oath::application_id()).to_uppercase());
                                 Ok(())
                             }
+                            Codes { timestamp } => {
+                                use solo2::apps::oath::Calculated;
+
+                                let timestamp = timestamp_or_now(timestamp)?;
+                                let codes = Oath::with_password(&mut *transport, password, |app| app.calculate_all(timestamp))?;
+                                if json {
+                                    let codes: Vec<_> = codes
+                                        .iter()
+                                        .map(|(id, calculated)| match calculated {
+                                            Calculated::Code(code) => serde_json::json!({
+                                                "id": id,
+                                                "code": code.to_string(),
+                                                "valid_from": code.valid_from,
+                                                "valid_until": code.valid_until,
+                                            }),
+                                            needs => serde_json::json!({ "id": id, "needs_calculate": needs }),
+                                        })
+                                        .collect();
+                                    print_json(&codes)?;
+                                } else {
+                                    let width = codes.iter().map(|(id, _)| id.len()).max().unwrap_or(0);
+                                    for (id, calculated) in codes {
+                                        match calculated {
+                                            Calculated::Code(code) => {
+                                                let remaining = code.valid_until.unwrap_or(timestamp).saturating_sub(timestamp);
+                                                println!("{:width$}  {:>10}  {:>3}s", id, code.to_string(), remaining);
+                                            }
+                                            Calculated::Hotp => println!("{:width$}  {:>10}", id, "[HOTP]"),
+                                            Calculated::TouchRequired => println!("{:width$}  {:>10}", id, "[touch]"),
+                                        }
+                                    }
+                                }
+                                Ok(())
+                            }
                             Delete { label } => {
-                                app.delete(label.clone())?;
+                                Oath::with_password(&mut *transport, password, |app| app.delete(label.clone()))?;
+                                Ok(())
+                            }
+                            Hotp { label } => {
+                                let code = Oath::with_password(&mut *transport, password, |app| {
+                                    prompt_touch(app, label)?;
+                                    app.calculate_hotp(label)
+                                })?;
+                                if json {
+                                    print_json(&serde_json::json!({ "id": label, "code": code.to_string() }))?;
+                                } else {
+                                    println!("{}", code);
+                                }
                                 Ok(())
                             }
                             List => {
-                                let labels = app.list()?;
-                                for label in labels {
-                                    println!("{}", label);
+                                let credentials = Oath::with_password(&mut *transport, password, |app| app.list_with_touch())?;
+                                if json {
+                                    let ids: Vec<_> = credentials
+                                        .iter()
+                                        .map(|(id, touch)| serde_json::json!({ "id": id, "touch_required": touch }))
+                                        .collect();
+                                    print_json(&ids)?;
+                                } else {
+                                    for (label, touch) in credentials {
+                                        if touch {
+                                            println!("{} [touch]", label);
+                                        } else {
+                                            println!("{}", label);
+                                        }
+                                    }
                                 }
                                 Ok(())
                             }
+                            Password { clear } => {
+                                let new_password = if *clear {
+                                    None
+                                } else {
+                                    Some(prompt_password("New OATH password", true)?)
+                                };
+                                Oath::with_password(&mut *transport, password, |app| app.set_password(new_password.as_deref()))?;
+                                Ok(())
+                            }
                             // TODO: factor out the conversion
                             Register(args) => {
                                 use solo2::apps::oath;
@@ -178,30 +482,33 @@ fn try_main(args: cli::Cli) -> anyhow::Result<()> {
                                     kind,
                                     algorithm: digest,
                                     digits: args.digits,
+                                    touch_required: args.touch,
                                 };
-                                let credential_id = app.register(credential)?;
+                                let credential_id = Oath::with_password(&mut *transport, password, |app| app.register(credential))?;
                                 println!("{}", credential_id);
                                 Ok(())
                             }
-                            Reset => app.reset(),
+                            Reset => Ok(Oath::with_password(&mut *transport, None, |app| app.reset())?),
                             // TODO: factor out the conversion
                             Totp { label, timestamp } => {
                                 use solo2::apps::oath;
-                                use std::time::SystemTime;
-
-                                let timestamp = timestamp
-                                    .clone()
-                                    .map(|s| s.parse())
-                                    .transpose()?
-                                    .unwrap_or_else(|| {
-                                        let since_epoch = SystemTime::now()
-                                            .duration_since(SystemTime::UNIX_EPOCH)
-                                            .unwrap();
-                                        since_epoch.as_secs()
-                                    });
+
+                                let timestamp = timestamp_or_now(timestamp)?;
                                 let authenticate = oath::Authenticate { label: label.clone(), timestamp };
-                                let code = app.authenticate(authenticate)?;
-                                println!("{}", code);
+                                let code = Oath::with_password(&mut *transport, password, |app| {
+                                    prompt_touch(app, label)?;
+                                    app.authenticate(authenticate)
+                                })?;
+                                if json {
+                                    print_json(&serde_json::json!({
+                                        "id": label,
+                                        "code": code.to_string(),
+                                        "valid_from": code.valid_from,
+                                        "valid_until": code.valid_until,
+                                    }))?;
+                                } else {
+                                    println!("{}", code);
+                                }
                                 Ok(())
                             }
                         }
@@ -211,7 +518,7 @@ fn try_main(args: cli::Cli) -> anyhow::Result<()> {
                         use solo2::apps::Piv;
 
                         // let mut app = Piv::select(&mut solo2)?;
-                        Piv::select(&mut solo2)?;
+                        Piv::select(&mut *target.transport(transcript.as_ref()))?;
 
                         match piv {
                             Aid => {
@@ -224,7 +531,8 @@ fn try_main(args: cli::Cli) -> anyhow::Result<()> {
                         use cli::Provision::*;
                         use solo2::apps::provision::App as Provision;
 
-                        let mut app = Provision::select(&mut solo2)?;
+                        let mut transport = target.transport(transcript.as_ref());
+                        let mut app = Provision::select(&mut *transport)?;
 
                         match provision {
                             Aid => {
@@ -282,7 +590,7 @@ fn try_main(args: cli::Cli) -> anyhow::Result<()> {
                         use cli::Qa::*;
                         use solo2::apps::qa::App;
 
-                        App::select(&mut solo2)?;
+                        App::select(&mut *target.transport(transcript.as_ref()))?;
 
                         match cmd {
                             Aid => {
@@ -346,9 +654,16 @@ fn try_main(args: cli::Cli) -> anyhow::Result<()> {
                 bootloader.reboot();
             }
             cli::Bootloader::List => {
-                let bootloaders = lpc55::Bootloader::list();
-                for bootloader in bootloaders {
-                    println!("{}", &Device::Lpc55(bootloader));
+                let bootloaders: Vec<Device> = lpc55::Bootloader::list()
+                    .into_iter()
+                    .map(Device::Lpc55)
+                    .collect();
+                if json {
+                    print_json(&bootloaders)?;
+                } else {
+                    for bootloader in bootloaders {
+                        println!("{}", &bootloader);
+                    }
                 }
             }
         },
@@ -366,10 +681,96 @@ fn try_main(args: cli::Cli) -> anyhow::Result<()> {
                 cli::Completion::Zsh => generate(Zsh, &mut app, "solo2", &mut stdout()),
             }
         }
+        cli::Subcommands::Apdu { aid, tlv, apdu } => {
+            let mut solo2: Solo2 = unwrap_or_interactively_select(uuid, "Solo 2")?;
+            let device = solo2.as_pcsc_mut().ok_or_else(|| {
+                solo2::Error::Unsupported("raw APDUs without PCSC transport".to_string())
+            })?;
+            let mut transport = recording(device, transcript.as_ref());
+            if let Some(aid) = aid {
+                transport.select(hex::decode(aid)?)?;
+            }
+            match apdu {
+                Some(apdu) => apdu_exchange(&mut *transport, &apdu, tlv)?,
+                None => {
+                    use std::io::{BufRead as _, Write as _};
+                    eprintln!("Enter command APDUs in hex, `quit` or Ctrl-D to end.");
+                    loop {
+                        eprint!("> ");
+                        std::io::stderr().flush()?;
+                        let mut line = String::new();
+                        if std::io::stdin().lock().read_line(&mut line)? == 0 {
+                            break;
+                        }
+                        match line.trim() {
+                            "" => continue,
+                            "exit" | "quit" => break,
+                            apdu => {
+                                if let Err(err) = apdu_exchange(&mut *transport, apdu, tlv) {
+                                    eprintln!("Error: {}", err);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        cli::Subcommands::Ctaphid(ctaphid) => {
+            use solo2::transport::ctap::{VendorCode, MAX_MESSAGE_LENGTH};
+
+            let mut solo2: Solo2 = unwrap_or_interactively_select(uuid, "Solo 2")?;
+            hook_ctap(&mut solo2, cancel_after);
+            let device = solo2.as_ctap_mut().ok_or_else(|| {
+                solo2::Error::Unsupported("CTAPHID commands without CTAP transport".to_string())
+            })?;
+            record_ctap(device, transcript.as_ref())?;
+            let channel = device.init()?.channel;
+            match ctaphid {
+                cli::Ctaphid::Ping { size, count } => {
+                    let sizes = match size {
+                        Some(size) => vec![size],
+                        None => vec![0, 57, 64, 256, 1024, 4096, MAX_MESSAGE_LENGTH],
+                    };
+                    for size in sizes {
+                        let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+                        let mut round_trips = Vec::new();
+                        for _ in 0..count.max(1) {
+                            let start = std::time::Instant::now();
+                            device.ping(channel, &data)?;
+                            round_trips.push(start.elapsed());
+                        }
+                        let total: Duration = round_trips.iter().sum();
+                        let average = total / round_trips.len() as u32;
+                        // INVARIANT: at least one round trip
+                        let min = round_trips.iter().min().unwrap();
+                        let max = round_trips.iter().max().unwrap();
+                        // the payload travels both ways
+                        let throughput = 2.0 * size as f64 / average.as_secs_f64() / 1024.0;
+                        println!(
+                            "{:>5} bytes: min {:.1} ms, avg {:.1} ms, max {:.1} ms, {:.1} KiB/s",
+                            size,
+                            min.as_secs_f64() * 1e3,
+                            average.as_secs_f64() * 1e3,
+                            max.as_secs_f64() * 1e3,
+                            throughput,
+                        );
+                    }
+                }
+                cli::Ctaphid::Send { cmd, data } => {
+                    let response =
+                        device.vendor(channel, VendorCode::try_from(cmd)?, &hex::decode(data)?)?;
+                    println!("{}", hex::encode(response));
+                }
+            }
+        }
         cli::Subcommands::List => {
             let devices = solo2::Device::list();
-            for device in devices {
-                println!("{}", &device);
+            if json {
+                print_json(&devices)?;
+            } else {
+                for device in devices {
+                    println!("{}", &device);
+                }
             }
         }
         cli::Subcommands::Update {
@@ -381,35 +782,68 @@ fn try_main(args: cli::Cli) -> anyhow::Result<()> {
             let firmware: solo2::Firmware = with
                 .map(solo2::Firmware::read_from_file)
                 .unwrap_or_else(|| {
-                    println!("Downloading latest release from https://github.com/solokeys/solo2/");
+                    eprintln!("Downloading latest release from https://github.com/solokeys/solo2/");
                     solo2::Firmware::download_latest()
                 })?;
 
-            println!(
-                "Fetched firmware version {} ({})",
-                &firmware.version().to_calver(),
-                &firmware.version().to_semver(),
-            );
+            if json {
+                print_json(&serde_json::json!({
+                    "event": "firmware",
+                    "version": firmware.version(),
+                    "calver": firmware.version().to_calver(),
+                }))?;
+            } else {
+                println!(
+                    "Fetched firmware version {} ({})",
+                    &firmware.version().to_calver(),
+                    &firmware.version().to_semver(),
+                );
+            }
 
             if dry_run {
                 return Ok(());
             }
 
-            if all {
-                for device in Device::list() {
+            let devices = if all {
+                Device::list()
+            } else {
+                vec![match uuid {
+                    Some(uuid) => Device::having(uuid)?,
+                    None => interactively_select(Device::list(), "Solo 2 devices")?,
+                }]
+            };
+            for mut device in devices {
+                if let Device::Solo2(solo2) = &mut device {
+                    hook_ctap(solo2, cancel_after);
+                }
+                let uuid = format!("{:X}", device.try_uuid()?.simple());
+                if json {
+                    // one event per percent
+                    let total = firmware.len();
+                    let reported = std::cell::Cell::new(None);
+                    let progress = |bytes: usize| {
+                        let percent = bytes * 100 / total.max(1);
+                        if reported.replace(Some(percent)) != Some(percent) {
+                            print_json(&serde_json::json!({
+                                "event": "progress",
+                                "uuid": uuid,
+                                "written": bytes,
+                                "total": total,
+                            }))
+                            .ok();
+                        }
+                    };
+                    device.program(firmware.clone(), yes, Some(&progress))?;
+                    print_json(&serde_json::json!({
+                        "event": "updated",
+                        "uuid": uuid,
+                        "version": firmware.version(),
+                    }))?;
+                } else {
                     let bar = indicatif::ProgressBar::new(firmware.len() as u64);
                     let progress = |bytes: usize| bar.set_position(bytes as u64);
                     device.program(firmware.clone(), yes, Some(&progress))?;
                 }
-                return Ok(());
-            } else {
-                let device = match uuid {
-                    Some(uuid) => Device::having(uuid)?,
-                    None => interactively_select(Device::list(), "Solo 2 devices")?,
-                };
-                let bar = indicatif::ProgressBar::new(firmware.len() as u64);
-                let progress = |bytes: usize| bar.set_position(bytes as u64);
-                return device.program(firmware, yes, Some(&progress));
             }
         }
     }
@@ -423,7 +857,10 @@ pub fn interactively_select<T: core::fmt::Display>(
     description: &str,
 ) -> anyhow::Result<T> {
     let mut candidates = match candidates.len() {
-        0 => return Err(anyhow!("Empty list of {}", description)),
+        0 => {
+            info!("empty list of {}", description);
+            return Err(solo2::Error::NoDevice.into());
+        }
         1 => {
             let mut candidates = candidates;
             return Ok(candidates.remove(0));
@@ -477,11 +914,232 @@ pub fn unwrap_or_interactively_select<T: core::fmt::Display + UuidSelectable>(
     Ok(thing)
 }
 
+/// Read a PIN from the terminal, optionally asking for it twice.
+fn prompt_pin(prompt: &str, confirm: bool) -> anyhow::Result<String> {
+    use dialoguer::{theme, Password};
+    let theme = theme::ColorfulTheme::default();
+    let mut password = Password::with_theme(&theme);
+    password.with_prompt(prompt);
+    if confirm {
+        password.with_confirmation("Repeat PIN", "PINs do not match");
+    }
+    Ok(password.interact()?)
+}
+
+fn prompt_password(prompt: &str, confirm: bool) -> anyhow::Result<String> {
+    use dialoguer::{theme, Password};
+    let theme = theme::ColorfulTheme::default();
+    let mut password = Password::with_theme(&theme);
+    password.with_prompt(prompt);
+    if confirm {
+        password.with_confirmation("Repeat password", "Passwords do not match");
+    }
+    Ok(password.interact()?)
+}
+
+/// Ask for touch if the device reports that the credential requires it.
+///
+/// OATH calculations go over PCSC, which has no keepalives to prompt on.
+fn prompt_touch(app: &mut solo2::apps::Oath<'_>, label: &str) -> solo2::Result<()> {
+    if app.list_with_touch()?.contains(&(label.to_string(), true)) {
+        eprintln!("Touch your Solo 2 to calculate {}...", label);
+    }
+    Ok(())
+}
+
+/// Prompt for a tap exactly when the device asks for one over CTAP,
+/// and let Ctrl-C or `--timeout` cancel the request.
+fn hook_ctap(solo2: &mut Solo2, cancel_after: Option<Duration>) {
+    if let Some(device) = solo2.as_ctap_mut() {
+        hook_ctap_device(device, cancel_after);
+    }
+}
+
+/// Parse an optional timestamp argument, defaulting to the current time.
+fn timestamp_or_now(timestamp: &Option<String>) -> anyhow::Result<u64> {
+    use std::time::SystemTime;
+    Ok(match timestamp {
+        Some(timestamp) => timestamp.parse()?,
+        None => SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs(),
+    })
+}
+
+/// Machine-readable output, one document per line.
+fn print_json(value: &impl serde::Serialize) -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string(value)?);
+    Ok(())
+}
+
+/// Send one command APDU (hex, spaces allowed), printing response and status word.
+fn apdu_exchange(transport: &mut dyn Transport, apdu: &str, tlv: bool) -> anyhow::Result<()> {
+    use solo2::transport::{pcsc::Command, StatusWord};
+
+    let apdu: String = apdu.split_whitespace().collect();
+    let command = Command::try_from(hex::decode(apdu)?.as_slice())?;
+    match transport.call_iso(
+        command.class,
+        command.instruction,
+        command.p1,
+        command.p2,
+        &command.data,
+    ) {
+        Ok(response) => {
+            if !response.is_empty() {
+                match tlv.then(|| ber_tlv(&response, 0)).transpose() {
+                    Ok(Some(tree)) => print!("{}", tree),
+                    // not BER-TLV after all
+                    _ => println!("{}", hex::encode_upper(&response)),
+                }
+            }
+            println!("SW {}", StatusWord::SUCCESS);
+        }
+        Err(solo2::Error::Status(sw)) => println!("SW {}", sw),
+        Err(err) => return Err(err.into()),
+    }
+    Ok(())
+}
+
+/// Indented tree of BER-TLV data, recursing into constructed values.
+fn ber_tlv(data: &[u8], depth: usize) -> flexiber::Result<String> {
+    use core::fmt::Write as _;
+    use flexiber::{Encodable as _, Tag, TaggedSlice};
+
+    let mut tree = String::new();
+    let mut decoder = flexiber::Decoder::new(data);
+    while !decoder.is_finished() {
+        let tlv: TaggedSlice<'_, Tag> = decoder.decode()?;
+        let tag = hex::encode_upper(tlv.tag().to_vec()?);
+        let value = tlv.as_bytes();
+        if tlv.tag().constructed {
+            writeln!(
+                tree,
+                "{:indent$}{} ({} bytes)",
+                "",
+                tag,
+                value.len(),
+                indent = 2 * depth
+            )
+            .ok();
+            tree += &ber_tlv(value, depth + 1)?;
+        } else {
+            writeln!(
+                tree,
+                "{:indent$}{} {}",
+                "",
+                tag,
+                hex::encode_upper(value),
+                indent = 2 * depth
+            )
+            .ok();
+        }
+    }
+    Ok(tree)
+}
+
+/// Where to record traffic of the device.
+struct Transcript {
+    file: std::fs::File,
+    redact: bool,
+}
+
+/// The transport, recording its traffic if requested.
+fn recording<'a>(
+    transport: impl Transport + 'a,
+    transcript: Option<&'a Transcript>,
+) -> Box<dyn Transport + 'a> {
+    match transcript {
+        Some(transcript) => {
+            Box::new(RecordingTransport::new(transport, &transcript.file).redact(transcript.redact))
+        }
+        None => Box::new(transport),
+    }
+}
+
+/// Record the CTAPHID packets of the device, if requested.
+fn record_ctap(
+    device: &mut solo2::device::ctap::Device,
+    transcript: Option<&Transcript>,
+) -> anyhow::Result<()> {
+    if let Some(transcript) = transcript {
+        device.record(transcript.file.try_clone()?);
+    }
+    Ok(())
+}
+
+/// What app commands talk to: a device, or a transcript replayed in its place.
+enum Target {
+    Device(Solo2),
+    Replay(ReplayTransport),
+}
+
+impl Target {
+    fn transport<'a>(&'a mut self, transcript: Option<&'a Transcript>) -> Box<dyn Transport + 'a> {
+        match self {
+            Target::Device(solo2) => recording(solo2, transcript),
+            Target::Replay(replay) => Box::new(replay),
+        }
+    }
+
+    /// The device, for commands that need more than its transport.
+    fn device(&mut self) -> anyhow::Result<&mut Solo2> {
+        match self {
+            Target::Device(solo2) => Ok(solo2),
+            Target::Replay(_) => {
+                Err(solo2::Error::Unsupported("this command in a replay".to_string()).into())
+            }
+        }
+    }
+
+    fn into_device(self) -> anyhow::Result<Solo2> {
+        match self {
+            Target::Device(solo2) => Ok(solo2),
+            Target::Replay(_) => {
+                Err(solo2::Error::Unsupported("this command in a replay".to_string()).into())
+            }
+        }
+    }
+}
+
+fn hook_ctap_device(device: &mut solo2::device::ctap::Device, cancel_after: Option<Duration>) {
+    let prompted = core::cell::Cell::new(false);
+    device.on_keepalive(move |status| {
+        if status == Status::UserPresenceNeeded && !prompted.replace(true) {
+            eprintln!("Tap your key now...");
+        }
+    });
+    device.set_cancel_handle(CANCEL.clone());
+    device.cancel_after(cancel_after);
+}
+
+/// Authenticators only accept authenticatorReset within 10 seconds of power-up,
+/// so have the user replug the key, and pick it up again as soon as it is back.
+fn wait_for_replug(uuid: Uuid) -> anyhow::Result<solo2::device::ctap::Device> {
+    use solo2::device::{ctap, Presence, Watcher};
+
+    let timeout = Duration::from_secs(30);
+    let mut watcher = Watcher::new();
+
+    println!("Unplug your key, and plug it back in...");
+    watcher.wait_for(uuid, None, timeout)?;
+    watcher.wait_for(uuid, Some(Presence::Solo2), timeout)?;
+
+    ctap::list()
+        .into_iter()
+        .find(|device| device.uuid().ok() == Some(uuid))
+        .ok_or_else(|| solo2::Error::NoSuchDevice(uuid).into())
+}
+
 /// In `dialoguer` dialogs, the cursor is hidden and, if the user interrupts via Ctrl-C,
 /// not shown again (for reasons). This is a best effort attempt to show the cursor again
 /// in these situations.
+///
+/// Pending CTAP requests get a moment to send CTAPHID_CANCEL first.
 fn restore_cursor_on_ctrl_c() {
     ctrlc::set_handler(move || {
+        CANCEL.cancel();
+        std::thread::sleep(Duration::from_millis(300));
         let term = dialoguer::console::Term::stderr();
         term.show_cursor().ok();
         // Ctrl-C exit code = 130
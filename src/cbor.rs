@@ -0,0 +1,376 @@
+//! Minimal CBOR ([RFC 8949][rfc8949]), as far as needed for CTAP2.
+//!
+//! There are no floats and no tags. Maps are always encoded in CTAP2 canonical order
+//! (shorter encoded keys first, then bytewise), independent of insertion order.
+//!
+//! [rfc8949]: https://www.rfc-editor.org/rfc/rfc8949.html
+
+use crate::{Error, Result};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Value {
+    /// Major types 0 and 1
+    Integer(i128),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+    Bool(bool),
+    Null,
+}
+
+/// Nesting depth beyond which we refuse to decode.
+const MAX_DEPTH: usize = 16;
+
+fn error(reason: &str) -> Error {
+    Error::Protocol(format!("CBOR: {}", reason))
+}
+
+impl Value {
+    /// Canonical encoding.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        self.encode(&mut buffer);
+        buffer
+    }
+
+    /// Decode a single item, which must span all of `data`.
+    pub fn from_slice(data: &[u8]) -> Result<Self> {
+        let (value, rest) = Self::decode(data)?;
+        if !rest.is_empty() {
+            return Err(error("trailing data"));
+        }
+        Ok(value)
+    }
+
+    /// Decode the first item, returning the remaining data.
+    pub fn decode(data: &[u8]) -> Result<(Self, &[u8])> {
+        decode(data, 0)
+    }
+
+    pub fn as_integer(&self) -> Option<i128> {
+        match self {
+            Value::Integer(integer) => Some(*integer),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Value::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(array) => Some(array),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(&self) -> Option<&[(Value, Value)]> {
+        match self {
+            Value::Map(map) => Some(map),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(boolean) => Some(*boolean),
+            _ => None,
+        }
+    }
+
+    /// Look up a key, if this is a map.
+    pub fn get(&self, key: impl Into<Value>) -> Option<&Value> {
+        let key = key.into();
+        self.as_map()?
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, value)| value)
+    }
+
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match self {
+            Value::Integer(integer) => {
+                if *integer >= 0 {
+                    encode_head(buffer, 0, *integer as u64)
+                } else {
+                    encode_head(buffer, 1, (-1 - *integer) as u64)
+                }
+            }
+            Value::Bytes(bytes) => {
+                encode_head(buffer, 2, bytes.len() as u64);
+                buffer.extend_from_slice(bytes);
+            }
+            Value::Text(text) => {
+                encode_head(buffer, 3, text.len() as u64);
+                buffer.extend_from_slice(text.as_bytes());
+            }
+            Value::Array(array) => {
+                encode_head(buffer, 4, array.len() as u64);
+                for value in array {
+                    value.encode(buffer);
+                }
+            }
+            Value::Map(map) => {
+                encode_head(buffer, 5, map.len() as u64);
+                let mut entries: Vec<(Vec<u8>, &Value)> =
+                    map.iter().map(|(k, v)| (k.to_vec(), v)).collect();
+                entries.sort_by(|(a, _), (b, _)| a.len().cmp(&b.len()).then_with(|| a.cmp(b)));
+                for (key, value) in entries {
+                    buffer.extend_from_slice(&key);
+                    value.encode(buffer);
+                }
+            }
+            Value::Bool(false) => buffer.push(0xF4),
+            Value::Bool(true) => buffer.push(0xF5),
+            Value::Null => buffer.push(0xF6),
+        }
+    }
+}
+
+fn encode_head(buffer: &mut Vec<u8>, major: u8, argument: u64) {
+    let major = major << 5;
+    match argument {
+        0..=23 => buffer.push(major | argument as u8),
+        24..=0xFF => buffer.extend_from_slice(&[major | 24, argument as u8]),
+        0x100..=0xFFFF => {
+            buffer.push(major | 25);
+            buffer.extend_from_slice(&(argument as u16).to_be_bytes());
+        }
+        0x1_0000..=0xFFFF_FFFF => {
+            buffer.push(major | 26);
+            buffer.extend_from_slice(&(argument as u32).to_be_bytes());
+        }
+        _ => {
+            buffer.push(major | 27);
+            buffer.extend_from_slice(&argument.to_be_bytes());
+        }
+    }
+}
+
+fn take(data: &[u8], n: usize) -> Result<(&[u8], &[u8])> {
+    if data.len() < n {
+        return Err(error("truncated"));
+    }
+    Ok(data.split_at(n))
+}
+
+fn decode(data: &[u8], depth: usize) -> Result<(Value, &[u8])> {
+    if depth > MAX_DEPTH {
+        return Err(error("nested too deeply"));
+    }
+    let (&initial, data) = data.split_first().ok_or_else(|| error("truncated"))?;
+    let major = initial >> 5;
+    let additional = initial & 0x1F;
+
+    if major == 7 {
+        let value = match additional {
+            20 => Value::Bool(false),
+            21 => Value::Bool(true),
+            // undefined is as good as null for our purposes
+            22 | 23 => Value::Null,
+            _ => return Err(error("unsupported simple value or float")),
+        };
+        return Ok((value, data));
+    }
+
+    let (argument, data) = match additional {
+        0..=23 => (additional as u64, data),
+        24..=27 => {
+            let (bytes, data) = take(data, 1 << (additional - 24))?;
+            let argument = bytes
+                .iter()
+                .fold(0u64, |argument, &byte| (argument << 8) | byte as u64);
+            (argument, data)
+        }
+        _ => return Err(error("indefinite lengths not supported")),
+    };
+
+    match major {
+        0 => Ok((Value::Integer(argument as i128), data)),
+        1 => Ok((Value::Integer(-1 - argument as i128), data)),
+        2 => {
+            let (bytes, data) = take(data, usize::try_from(argument).unwrap_or(usize::MAX))?;
+            Ok((Value::Bytes(bytes.to_vec()), data))
+        }
+        3 => {
+            let (bytes, data) = take(data, usize::try_from(argument).unwrap_or(usize::MAX))?;
+            let text = std::str::from_utf8(bytes).map_err(|_| error("text is not UTF-8"))?;
+            Ok((Value::Text(text.to_string()), data))
+        }
+        4 => {
+            // each item takes at least one byte
+            if argument > data.len() as u64 {
+                return Err(error("truncated"));
+            }
+            let mut array = Vec::with_capacity(argument as usize);
+            let mut data = data;
+            for _ in 0..argument {
+                let (value, rest) = decode(data, depth + 1)?;
+                array.push(value);
+                data = rest;
+            }
+            Ok((Value::Array(array), data))
+        }
+        5 => {
+            if argument > data.len() as u64 / 2 {
+                return Err(error("truncated"));
+            }
+            let mut map = Vec::with_capacity(argument as usize);
+            let mut data = data;
+            for _ in 0..argument {
+                let (key, rest) = decode(data, depth + 1)?;
+                let (value, rest) = decode(rest, depth + 1)?;
+                map.push((key, value));
+                data = rest;
+            }
+            Ok((Value::Map(map), data))
+        }
+        _ => Err(error("tags not supported")),
+    }
+}
+
+impl From<i128> for Value {
+    fn from(integer: i128) -> Self {
+        Value::Integer(integer)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(integer: i64) -> Self {
+        Value::Integer(integer.into())
+    }
+}
+
+impl From<u64> for Value {
+    fn from(integer: u64) -> Self {
+        Value::Integer(integer.into())
+    }
+}
+
+impl From<i32> for Value {
+    fn from(integer: i32) -> Self {
+        Value::Integer(integer.into())
+    }
+}
+
+impl From<u8> for Value {
+    fn from(integer: u8) -> Self {
+        Value::Integer(integer.into())
+    }
+}
+
+impl From<&[u8]> for Value {
+    fn from(bytes: &[u8]) -> Self {
+        Value::Bytes(bytes.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(bytes: Vec<u8>) -> Self {
+        Value::Bytes(bytes)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(text: &str) -> Self {
+        Value::Text(text.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(text: String) -> Self {
+        Value::Text(text)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(boolean: bool) -> Self {
+        Value::Bool(boolean)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hex_literal::hex;
+
+    #[test]
+    fn rfc8949_examples() {
+        let examples: &[(&[u8], Value)] = &[
+            (&hex!("00"), Value::Integer(0)),
+            (&hex!("17"), Value::Integer(23)),
+            (&hex!("1818"), Value::Integer(24)),
+            (&hex!("1903e8"), Value::Integer(1000)),
+            (&hex!("1b000000e8d4a51000"), Value::Integer(1000000000000)),
+            (
+                &hex!("1bffffffffffffffff"),
+                Value::Integer(18446744073709551615),
+            ),
+            (&hex!("20"), Value::Integer(-1)),
+            (&hex!("3863"), Value::Integer(-100)),
+            (&hex!("f4"), Value::Bool(false)),
+            (&hex!("f6"), Value::Null),
+            (&hex!("4401020304"), Value::Bytes(vec![1, 2, 3, 4])),
+            (&hex!("6449455446"), "IETF".into()),
+            (
+                &hex!("8301820203820405"),
+                Value::Array(vec![
+                    1.into(),
+                    Value::Array(vec![2.into(), 3.into()]),
+                    Value::Array(vec![4.into(), 5.into()]),
+                ]),
+            ),
+            (
+                &hex!("a201020304"),
+                Value::Map(vec![(1.into(), 2.into()), (3.into(), 4.into())]),
+            ),
+        ];
+        for (encoded, value) in examples {
+            assert_eq!(&Value::from_slice(encoded).unwrap(), value);
+            assert_eq!(&value.to_vec(), encoded);
+        }
+    }
+
+    #[test]
+    fn canonical_map_order() {
+        let map = Value::Map(vec![
+            ("aa".into(), 0.into()),
+            ((-1).into(), 0.into()),
+            ("b".into(), 0.into()),
+            (10.into(), 0.into()),
+        ]);
+        assert_eq!(map.to_vec(), hex!("a40a002000616200626161 00"));
+        assert_eq!(map.get("b"), Some(&Value::Integer(0)));
+        assert_eq!(map.get(11), None);
+    }
+
+    #[test]
+    fn malformed() {
+        for encoded in [
+            &hex!("")[..],
+            &hex!("19 03"),
+            &hex!("44 0102"),
+            &hex!("62 fffe"),
+            &hex!("9f"),
+            &hex!("c1 00"),
+            &hex!("fb 3ff199999999999a"),
+            &hex!("bb ffffffffffffffff"),
+            &hex!("00 00"),
+            &[0x81; 32],
+        ] {
+            assert!(Value::from_slice(encoded).is_err());
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! COSE public keys ([RFC 8152][rfc8152]), as used by CTAP2.
+//!
+//! Only the key types Solo 2 uses are supported: P-256 (for ES256 signatures and
+//! ECDH-ES+HKDF-256 key agreement) and Ed25519 (for EdDSA signatures).
+//!
+//! [rfc8152]: https://www.rfc-editor.org/rfc/rfc8152.html#section-13
+
+use ring::signature;
+
+use crate::{cbor::Value, Error, Result};
+
+/// ECDSA with SHA-256
+pub const ES256: i32 = -7;
+/// EdDSA (for us, Ed25519)
+pub const EDDSA: i32 = -8;
+/// ECDH with HKDF-SHA-256, as used for PIN/UV auth protocol key agreement
+pub const ECDH_ES_HKDF_256: i32 = -25;
+
+const KTY: i32 = 1;
+const ALG: i32 = 3;
+const CRV: i32 = -1;
+const X: i32 = -2;
+const Y: i32 = -3;
+
+const KTY_OKP: i32 = 1;
+const KTY_EC2: i32 = 2;
+const CRV_P256: i32 = 1;
+const CRV_ED25519: i32 = 6;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PublicKey {
+    P256 {
+        algorithm: i32,
+        x: [u8; 32],
+        y: [u8; 32],
+    },
+    Ed25519 {
+        x: [u8; 32],
+    },
+}
+
+fn error(reason: &str) -> Error {
+    Error::Protocol(format!("COSE key: {}", reason))
+}
+
+fn integer(key: &Value, label: i32) -> Result<i128> {
+    key.get(label)
+        .and_then(Value::as_integer)
+        .ok_or_else(|| error("missing or invalid parameter"))
+}
+
+fn coordinate(key: &Value, label: i32) -> Result<[u8; 32]> {
+    key.get(label)
+        .and_then(Value::as_bytes)
+        .and_then(|coordinate| coordinate.try_into().ok())
+        .ok_or_else(|| error("missing or invalid coordinate"))
+}
+
+impl TryFrom<&Value> for PublicKey {
+    type Error = Error;
+    fn try_from(key: &Value) -> Result<Self> {
+        let algorithm = integer(key, ALG)?;
+        match (integer(key, KTY)?, integer(key, CRV)?) {
+            (kty, crv) if kty == KTY_EC2.into() && crv == CRV_P256.into() => Ok(Self::P256 {
+                algorithm: i32::try_from(algorithm).map_err(|_| error("invalid algorithm"))?,
+                x: coordinate(key, X)?,
+                y: coordinate(key, Y)?,
+            }),
+            (kty, crv) if kty == KTY_OKP.into() && crv == CRV_ED25519.into() => {
+                if algorithm != EDDSA.into() {
+                    return Err(error("Ed25519 key not for EdDSA"));
+                }
+                Ok(Self::Ed25519 {
+                    x: coordinate(key, X)?,
+                })
+            }
+            _ => Err(error("unsupported key type or curve")),
+        }
+    }
+}
+
+impl PublicKey {
+    /// P-256 key from an uncompressed point (`04 || x || y`).
+    pub fn from_uncompressed(algorithm: i32, point: &[u8]) -> Result<Self> {
+        if point.len() != 65 || point[0] != 0x04 {
+            return Err(error("not an uncompressed P-256 point"));
+        }
+        Ok(Self::P256 {
+            algorithm,
+            x: point[1..33].try_into()?,
+            y: point[33..].try_into()?,
+        })
+    }
+
+    /// The uncompressed point (`04 || x || y`), for P-256 keys.
+    pub fn uncompressed(&self) -> Option<Vec<u8>> {
+        match self {
+            Self::P256 { x, y, .. } => {
+                let mut point = vec![0x04];
+                point.extend_from_slice(x);
+                point.extend_from_slice(y);
+                Some(point)
+            }
+            Self::Ed25519 { .. } => None,
+        }
+    }
+
+    pub fn algorithm(&self) -> i32 {
+        match self {
+            Self::P256 { algorithm, .. } => *algorithm,
+            Self::Ed25519 { .. } => EDDSA,
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        match self {
+            Self::P256 { algorithm, x, y } => Value::Map(vec![
+                (KTY.into(), KTY_EC2.into()),
+                (ALG.into(), (*algorithm).into()),
+                (CRV.into(), CRV_P256.into()),
+                (X.into(), x[..].into()),
+                (Y.into(), y[..].into()),
+            ]),
+            Self::Ed25519 { x } => Value::Map(vec![
+                (KTY.into(), KTY_OKP.into()),
+                (ALG.into(), EDDSA.into()),
+                (CRV.into(), CRV_ED25519.into()),
+                (X.into(), x[..].into()),
+            ]),
+        }
+    }
+
+    /// Verify a signature, DER encoded for ES256.
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> Result<()> {
+        let (algorithm, key): (&dyn signature::VerificationAlgorithm, Vec<u8>) = match self {
+            Self::P256 {
+                algorithm: ES256, ..
+            } => (
+                &signature::ECDSA_P256_SHA256_ASN1,
+                // INVARIANT: P-256 keys have an uncompressed point
+                self.uncompressed().unwrap(),
+            ),
+            Self::Ed25519 { x } => (&signature::ED25519, x.to_vec()),
+            _ => return Err(error("not a signature key")),
+        };
+        signature::UnparsedPublicKey::new(algorithm, key)
+            .verify(message, signature)
+            .map_err(|_| Error::Signature(format!("COSE algorithm {}", self.algorithm())))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ring::{rand::SystemRandom, signature::KeyPair as _};
+
+    #[test]
+    fn roundtrip() {
+        let p256 = PublicKey::P256 {
+            algorithm: ES256,
+            x: [1; 32],
+            y: [2; 32],
+        };
+        let ed25519 = PublicKey::Ed25519 { x: [3; 32] };
+        for key in [p256, ed25519] {
+            let encoded = key.to_value().to_vec();
+            assert_eq!(
+                PublicKey::try_from(&Value::from_slice(&encoded).unwrap()).unwrap(),
+                key
+            );
+        }
+
+        let point = PublicKey::from_uncompressed(ECDH_ES_HKDF_256, &[4; 65]).unwrap();
+        assert_eq!(point.uncompressed().unwrap(), [4; 65]);
+        assert!(PublicKey::from_uncompressed(ES256, &[4; 64]).is_err());
+
+        // RSA
+        let rsa = Value::Map(vec![(1.into(), 3.into()), (3.into(), (-257).into())]);
+        assert!(PublicKey::try_from(&rsa).is_err());
+    }
+
+    #[test]
+    fn verify_ed25519() {
+        let rng = SystemRandom::new();
+        let pkcs8 = signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let key_pair = signature::Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let key = PublicKey::Ed25519 {
+            x: key_pair.public_key().as_ref().try_into().unwrap(),
+        };
+        let signature = key_pair.sign(b"message");
+        assert!(key.verify(b"message", signature.as_ref()).is_ok());
+        assert!(key.verify(b"massage", signature.as_ref()).is_err());
+    }
+}
@@ -2,14 +2,16 @@
 use core::sync::atomic::{AtomicBool, Ordering};
 use std::collections::{BTreeMap, BTreeSet};
 
-use anyhow::anyhow;
 use lpc55::bootloader::{Bootloader as Lpc55, UuidSelectable};
 
-use crate::{apps::Admin, Firmware, Result, Select as _, Uuid, Version};
+use crate::{apps::Admin, Error, Firmware, Result, Select as _, Uuid, Version};
 use core::fmt;
+use serde::{Serialize, Serializer};
 
 pub mod ctap;
 pub mod pcsc;
+pub mod watcher;
+pub use watcher::{Event, Presence, Watcher};
 
 /// A [SoloKeys][solokeys] [Solo 2][solo2] device, in regular mode.
 ///
@@ -19,14 +21,37 @@ pub mod pcsc;
 /// Therefore, it is an invariant that at least one is interface, and the device itself
 /// implements [Transport][crate::Transport].
 ///
+/// Serialized with the UUID as upper case hex, the firmware version as semver,
+/// the available transports as booleans, and the lock status if known.
+///
 /// [solokeys]: https://solokeys.com
 /// [solo2]: https://solo2.dev
+#[derive(Serialize)]
 pub struct Solo2 {
+    #[serde(serialize_with = "serialize_uuid")]
+    uuid: Uuid,
+    version: Version,
+    #[serde(serialize_with = "serialize_presence")]
     ctap: Option<ctap::Device>,
+    #[serde(serialize_with = "serialize_presence")]
     pcsc: Option<pcsc::Device>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     locked: Option<bool>,
-    uuid: Uuid,
-    version: Version,
+}
+
+/// UUIDs as upper case hex, like everywhere else.
+pub fn serialize_uuid<S: Serializer>(
+    uuid: &Uuid,
+    serializer: S,
+) -> core::result::Result<S::Ok, S::Error> {
+    serializer.collect_str(&format_args!("{:X}", uuid.simple()))
+}
+
+fn serialize_presence<T, S: Serializer>(
+    option: &Option<T>,
+    serializer: S,
+) -> core::result::Result<S::Ok, S::Error> {
+    serializer.serialize_bool(option.is_some())
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -58,23 +83,29 @@ impl Solo2 {
     pub fn into_lpc55(self) -> Result<Lpc55> {
         let mut solo2 = self;
         let uuid = solo2.uuid;
+        let mut watcher = Watcher::new();
         // AGAIN: This requires user tap!
-        let now = std::time::Instant::now();
         Admin::select(&mut solo2)?.maintenance().ok();
         drop(solo2);
 
-        std::thread::sleep(std::time::Duration::from_secs(1));
-        let mut lpc55 = Lpc55::having(uuid);
-        while lpc55.is_err() {
-            if now.elapsed().as_secs() > 15 {
-                return Err(anyhow!("User prompt to confirm maintenance timed out (or udev rules for LPC 55 mode missing)!"));
-            }
-            std::thread::sleep(std::time::Duration::from_secs(1));
-            lpc55 = Lpc55::having(uuid);
+        match watcher.wait_for(uuid, Some(Presence::Maintenance), Self::REBOOT_TIMEOUT) {
+            // (or udev rules for LPC 55 mode missing)
+            Err(Error::Timeout) => return Err(Error::UserPresence),
+            result => result?,
+        }
+        let mut candidates: Vec<Lpc55> = Lpc55::list()
+            .into_iter()
+            .filter(|lpc55| lpc55.uuid == uuid.as_u128())
+            .collect();
+        match candidates.len() {
+            0 => Err(Error::NoSuchDevice(uuid)),
+            1 => Ok(candidates.remove(0)),
+            count => Err(Error::AmbiguousDevice { uuid, count }),
         }
-
-        lpc55
     }
+
+    /// How long to wait for a device to come back after switching modes.
+    pub const REBOOT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(15);
 }
 
 impl fmt::Debug for Solo2 {
@@ -116,7 +147,7 @@ impl fmt::Display for Solo2 {
 }
 
 impl UuidSelectable for Solo2 {
-    fn try_uuid(&mut self) -> Result<Uuid> {
+    fn try_uuid(&mut self) -> anyhow::Result<Uuid> {
         Ok(self.uuid)
     }
 
@@ -165,6 +196,26 @@ impl UuidSelectable for Solo2 {
         }
         devices
     }
+
+    /// Fails is if zero or >1 devices have the given UUID.
+    fn having(uuid: Uuid) -> anyhow::Result<Self> {
+        Ok(Self::find(uuid)?)
+    }
+}
+
+impl Solo2 {
+    /// Fails is if zero or >1 devices have the given UUID.
+    fn find(uuid: Uuid) -> Result<Self> {
+        let mut candidates: Vec<Solo2> = Self::list()
+            .into_iter()
+            .filter(|solo2| solo2.uuid == uuid)
+            .collect();
+        match candidates.len() {
+            0 => Err(Error::NoSuchDevice(uuid)),
+            1 => Ok(candidates.remove(0)),
+            count => Err(Error::AmbiguousDevice { uuid, count }),
+        }
+    }
 }
 
 impl Solo2 {
@@ -196,11 +247,11 @@ impl Solo2 {
 }
 
 impl TryFrom<ctap::Device> for Solo2 {
-    type Error = crate::Error;
+    type Error = Error;
     fn try_from(device: ctap::Device) -> Result<Solo2> {
         let mut device = device;
         let locked = Admin::select(&mut device)?.locked().ok();
-        let uuid = device.try_uuid()?;
+        let uuid = device.uuid()?;
         let version = Admin::select(&mut device)?.version()?;
 
         Ok(Solo2 {
@@ -214,7 +265,7 @@ impl TryFrom<ctap::Device> for Solo2 {
 }
 
 impl TryFrom<pcsc::Device> for Solo2 {
-    type Error = crate::Error;
+    type Error = Error;
     fn try_from(device: pcsc::Device) -> Result<Solo2> {
         let mut device = device;
         let mut admin = Admin::select(&mut device)?;
@@ -234,12 +285,30 @@ impl TryFrom<pcsc::Device> for Solo2 {
 ///
 /// Not every [pcsc::Device] is a [Device]; currently if it reacts to the SoloKeys administrative
 /// [App][crate::apps::admin::App] with a valid UUID, then we treat it as such.
+///
+/// Serialized as the [Solo2] or the bootloader UUID, tagged with the `mode`.
 // #[derive(Debug, Eq, PartialEq)]
+#[derive(Serialize)]
+#[serde(tag = "mode", rename_all = "lowercase")]
 pub enum Device {
+    #[serde(rename = "maintenance", serialize_with = "serialize_bootloader")]
     Lpc55(Lpc55),
     Solo2(Solo2),
 }
 
+fn serialize_bootloader<S: Serializer>(
+    lpc55: &Lpc55,
+    serializer: S,
+) -> core::result::Result<S::Ok, S::Error> {
+    use serde::ser::SerializeStruct as _;
+    let mut bootloader = serializer.serialize_struct("Lpc55", 1)?;
+    bootloader.serialize_field(
+        "uuid",
+        &format!("{:X}", Uuid::from_u128(lpc55.uuid).simple()),
+    )?;
+    bootloader.end()
+}
+
 impl fmt::Display for Device {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use Device::*;
@@ -251,7 +320,7 @@ impl fmt::Display for Device {
 }
 
 impl UuidSelectable for Device {
-    fn try_uuid(&mut self) -> Result<Uuid> {
+    fn try_uuid(&mut self) -> anyhow::Result<Uuid> {
         Ok(self.uuid())
     }
 
@@ -262,19 +331,15 @@ impl UuidSelectable for Device {
     }
 
     /// Fails is if zero or >1 devices have the given UUID.
-    fn having(uuid: Uuid) -> Result<Self> {
+    fn having(uuid: Uuid) -> anyhow::Result<Self> {
         let mut candidates: Vec<Device> = Self::list()
             .into_iter()
             .filter(|card| card.uuid() == uuid)
             .collect();
         match candidates.len() {
-            0 => Err(anyhow!("No usable device has UUID {:X}", uuid.simple())),
+            0 => Err(Error::NoSuchDevice(uuid).into()),
             1 => Ok(candidates.remove(0)),
-            n => Err(anyhow!(
-                "Multiple ({}) devices have UUID {:X}",
-                n,
-                uuid.simple()
-            )),
+            count => Err(Error::AmbiguousDevice { uuid, count }.into()),
         }
     }
 }
@@ -287,24 +352,29 @@ impl Device {
         }
     }
 
-    /// NB: will hang if in bootloader mode and Solo 2 firmware does not
-    /// come up cleanly.
+    /// Fails with [`Error::Timeout`] if, in bootloader mode, the Solo 2 firmware
+    /// does not come up cleanly.
     pub fn into_solo2(self) -> Result<Solo2> {
         match self {
             Device::Solo2(solo2) => Ok(solo2),
             Device::Lpc55(lpc55) => {
                 let uuid = Uuid::from_u128(lpc55.uuid);
+                let mut watcher = Watcher::new();
                 lpc55.reboot();
                 drop(lpc55);
 
-                std::thread::sleep(std::time::Duration::from_secs(1));
-                let mut solo2 = Solo2::having(uuid);
-                while solo2.is_err() {
-                    std::thread::sleep(std::time::Duration::from_secs(1));
-                    solo2 = Solo2::having(uuid);
+                let deadline = std::time::Instant::now() + Solo2::REBOOT_TIMEOUT;
+                watcher.wait_for(uuid, Some(Presence::Solo2), Solo2::REBOOT_TIMEOUT)?;
+                // the device may enumerate before its interfaces are ready
+                loop {
+                    match Solo2::find(uuid) {
+                        Ok(solo2) => return Ok(solo2),
+                        Err(_) if std::time::Instant::now() < deadline => {
+                            std::thread::sleep(Watcher::INTERVAL)
+                        }
+                        Err(error) => return Err(error),
+                    }
                 }
-
-                solo2
             }
         }
     }
@@ -340,18 +410,21 @@ impl Device {
                 info!("new fw version: {}", firmware.version().to_calver());
 
                 if solo2.version > firmware.version() {
-                    println!("Firmware version on device higher than firmware version used.");
-                    println!("This would be rejected by the device.");
-                    return Err(anyhow!("Firmware rollback attempt"));
+                    eprintln!("Firmware version on device higher than firmware version used.");
+                    eprintln!("This would be rejected by the device.");
+                    return Err(Error::Rollback {
+                        device: solo2.version,
+                        firmware: firmware.version(),
+                    });
                 }
 
                 let fw_major = firmware.version().major;
                 let major_version_bump = fw_major > solo2.version.major;
                 if !skip_major_prompt && major_version_bump {
                     use dialoguer::{theme, Confirm};
-                    println!("Warning: This is is major update and it could risk breaking any current credentials on your key.");
-                    println!("Check latest release notes here to double check: https://github.com/solokeys/solo2/releases");
-                    println!(
+                    eprintln!("Warning: This is is major update and it could risk breaking any current credentials on your key.");
+                    eprintln!("Check latest release notes here to double check: https://github.com/solokeys/solo2/releases");
+                    eprintln!(
                         "If you haven't used your key for anything yet, you can ignore this.\n"
                     );
 
@@ -360,33 +433,33 @@ impl Device {
                         .wait_for_newline(true)
                         .interact()?
                     {
-                        println!("Continuing");
+                        eprintln!("Continuing");
                     } else {
-                        return Err(anyhow!("User aborted."));
+                        return Err(Error::Aborted);
                     }
                 }
 
-                println!("Tap button on key to confirm, or replug to abort...");
+                eprintln!("Tap button on key to confirm, or replug to abort...");
                 Self::Solo2(solo2).into_lpc55()
                     .map_err(|e| {
                         if std::env::consts::OS == "linux" {
-                            println!("\nIf you touched the key and the LED is off, you are likely missing udev rules for LPC 55 mode.");
-                            println!("Either run `sudo solo2 update`, or install <https://github.com/solokeys/solo2-cli/blob/main/70-solo2.rules>");
-                            println!("Specifically, you need this line:");
+                            eprintln!("\nIf you touched the key and the LED is off, you are likely missing udev rules for LPC 55 mode.");
+                            eprintln!("Either run `sudo solo2 update`, or install <https://github.com/solokeys/solo2-cli/blob/main/70-solo2.rules>");
+                            eprintln!("Specifically, you need this line:");
                             // SUBSYSTEM=="hidraw", ATTRS{idVendor}=="1209", ATTRS{idProduct}=="b000", TAG+="uaccess"
-                            println!(r#"SUBSYSTEM=="hidraw", ATTRS{{idVendor}}=="1209", ATTRS{{idProduct}}=="b000", TAG+="uaccess""#);
-                            println!();
+                            eprintln!(r#"SUBSYSTEM=="hidraw", ATTRS{{idVendor}}=="1209", ATTRS{{idProduct}}=="b000", TAG+="uaccess""#);
+                            eprintln!();
                         }
                         e
                     })?
             }
         };
 
-        println!("LPC55 Bootloader detected. The LED should be off.");
-        println!("Writing new firmware...");
+        eprintln!("LPC55 Bootloader detected. The LED should be off.");
+        eprintln!("Writing new firmware...");
         firmware.write_to(&lpc55, progress);
 
-        println!("Done. Rebooting key. The LED should turn back on.");
+        eprintln!("Done. Rebooting key. The LED should turn back on.");
         Self::Lpc55(lpc55).into_solo2().map(drop)
     }
 }
@@ -402,3 +475,17 @@ impl From<Solo2> for Device {
         Device::Solo2(solo2)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_send<T: Send>() {}
+
+    #[test]
+    fn devices_are_send() {
+        assert_send::<ctap::Device>();
+        assert_send::<Solo2>();
+        assert_send::<Device>();
+    }
+}
@@ -3,11 +3,14 @@
 //! The most convenient entry point is the `list() -> Vec<Device>` function.
 
 use std::fmt;
+use std::io::Write;
+use std::time::Duration;
 
 use hidapi;
 
 // use crate::{apps, Result, Uuid};
-use crate::{Result, Uuid, UuidSelectable};
+use crate::transport::ctap::{CancelHandle, PacketIo, RecordingPacketIo, Status};
+use crate::{Error, Result, Uuid, UuidSelectable};
 
 // This is not such a hot idea after all.
 // For instance, `lpc55-host` uses `hiadpi` as well, and with
@@ -25,9 +28,10 @@ pub struct Session {
     session: hidapi::HidApi,
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq, serde::Serialize)]
 pub struct Info {
     /// the unique identifier for access on all platforms
+    #[serde(serialize_with = "serialize_path")]
     pub path: std::ffi::CString,
     pub vid: u16,
     pub pid: u16,
@@ -36,9 +40,20 @@ pub struct Info {
     pub product: String,
 }
 
+fn serialize_path<S: serde::Serializer>(
+    path: &std::ffi::CStr,
+    serializer: S,
+) -> core::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&path.to_string_lossy())
+}
+
 // #[derive(Clone)]
 pub struct Device {
-    pub(crate) device: hidapi::HidDevice,
+    pub(crate) device: Box<dyn PacketIo + Send>,
+    pub(crate) timeout: Duration,
+    pub(crate) keepalive: Option<Box<dyn Fn(Status) + Send>>,
+    pub(crate) cancel: CancelHandle,
+    pub(crate) cancel_after: Option<Duration>,
     info: Info,
 }
 
@@ -62,9 +77,76 @@ impl From<hidapi::DeviceInfo> for Info {
 }
 
 impl Device {
+    /// How long to wait for each packet of a response.
+    ///
+    /// While processing (e.g., waiting for user presence), devices send keepalives
+    /// at least every 100ms, so this need not cover the full operation.
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// The UUID, as reported in the HID serial number.
+    pub fn uuid(&self) -> Result<Uuid> {
+        let serial = &self.info().serial;
+        hex::decode(serial)
+            .ok()
+            .and_then(|bytes| Uuid::from_slice(&bytes).ok())
+            .ok_or_else(|| Error::Protocol(format!("serial {} is not a UUID", serial)))
+    }
+
     pub fn info(&self) -> &Info {
         &self.info
     }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Observe keepalives sent while the device processes a request,
+    /// e.g. to prompt the user exactly when the device waits for a tap.
+    pub fn on_keepalive(&mut self, observer: impl Fn(Status) + Send + 'static) {
+        self.keepalive = Some(Box::new(observer));
+    }
+
+    /// Handle to cancel pending requests from elsewhere (e.g., a Ctrl-C handler).
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// Use an existing handle to cancel requests, e.g. one shared among devices.
+    pub fn set_cancel_handle(&mut self, cancel: CancelHandle) {
+        self.cancel = cancel;
+    }
+
+    /// Record all CTAPHID packets from now on to a JSON lines transcript,
+    /// cf. [`RecordingPacketIo`].
+    pub fn record(&mut self, writer: impl Write + Send + 'static) {
+        let device = std::mem::replace(&mut self.device, Box::new(Detached));
+        self.device = Box::new(RecordingPacketIo::new(device, writer));
+    }
+
+    /// Cancel requests the device is still processing after this long.
+    ///
+    /// If the device was waiting for user presence, these fail with
+    /// [`UserPresence`][crate::Error::UserPresence], otherwise with [`Timeout`][crate::Error::Timeout].
+    pub fn cancel_after(&mut self, after: Option<Duration>) {
+        self.cancel_after = after;
+    }
+}
+
+/// Stand-in while the packet I/O is moved into a wrapper.
+struct Detached;
+
+impl PacketIo for Detached {
+    fn write_packet(&self, _: &[u8; 64]) -> Result<()> {
+        Err(Error::NoDevice)
+    }
+
+    fn read_packet(&self, _: &mut [u8; 64], _: Duration) -> Result<usize> {
+        Err(Error::NoDevice)
+    }
 }
 
 impl fmt::Debug for Device {
@@ -114,7 +196,14 @@ impl Session {
             .filter_map(|info| {
                 self.session
                     .open_path(&info.path)
-                    .map(|device| Device { device, info })
+                    .map(|device| Device {
+                        device: Box::new(device),
+                        timeout: Device::DEFAULT_TIMEOUT,
+                        keepalive: None,
+                        cancel: CancelHandle::new(),
+                        cancel_after: None,
+                        info,
+                    })
                     .ok()
             })
             .collect()
@@ -123,9 +212,8 @@ impl Session {
 
 impl UuidSelectable for Device {
     /// We'd kinda like to use the `admin-app`
-    fn try_uuid(&mut self) -> Result<Uuid> {
-        let maybe_uuid = hex::decode(&self.info().serial)?;
-        Ok(Uuid::from_slice(&maybe_uuid)?)
+    fn try_uuid(&mut self) -> anyhow::Result<Uuid> {
+        Ok(self.uuid()?)
     }
 
     fn list() -> Vec<Self> {
@@ -15,18 +15,23 @@
 //!
 
 use core::fmt;
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::time::Duration;
+
 use lpc55::bootloader::UuidSelectable;
 
-use pcsc::{Protocols, Scope, ShareMode};
+pub use pcsc::ShareMode;
+use pcsc::{Protocols, ReaderState, Scope, State};
 
-use crate::{apps::admin::App as Admin, Result, Select as _, Uuid};
+use crate::{apps::admin::App as Admin, Error, Result, Select as _, Uuid};
 
 /// A session with the PCSC service (running `pcscd` instance)
 pub struct Session {
     session: pcsc::Context,
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq, serde::Serialize)]
 pub struct Info {
     /// the unique identifier for access on all platforms
     pub name: String,
@@ -49,6 +54,15 @@ pub struct Device {
     pub name: String,
 }
 
+/// An exclusive transaction with a [`Device`], ended when dropped.
+///
+/// Until then, other processes (e.g., `gpg-agent` or browsers) cannot interleave their
+/// own commands, such as SELECTs of other applications.
+pub struct Transaction<'d> {
+    pub(crate) device: pcsc::Transaction<'d>,
+    pub name: &'d str,
+}
+
 pub fn list() -> Vec<Device> {
     Session::new()
         .map(|session| session.devices())
@@ -72,14 +86,15 @@ impl Session {
 
     /// Get a connection to a smartcard by name.
     ///
+    /// With [`ShareMode::Exclusive`], no other process can connect until the device is dropped;
+    /// usually [`ShareMode::Shared`] with [transactions][Device::transaction] is friendlier.
+    ///
     /// We prefer to use normal Rust types for the smartcard names, meaning
     /// that cards with weird non-UTF8 names won't be addressable.
-    pub fn connect(&self, info: &str) -> Result<Device> {
-        let cstring = std::ffi::CString::new(info.as_bytes()).unwrap();
+    pub fn connect(&self, info: &str, share_mode: ShareMode) -> Result<Device> {
+        let cstring = CString::new(info.as_bytes()).unwrap();
         Ok(Device {
-            device: self
-                .session
-                .connect(&cstring, ShareMode::Shared, Protocols::ANY)?,
+            device: self.session.connect(&cstring, share_mode, Protocols::ANY)?,
             name: info.to_string(),
         })
     }
@@ -94,7 +109,11 @@ impl Session {
             .session
             .list_readers(&mut card_names_buffer)?
             .map(|name_cstr| name_cstr.to_string_lossy().to_string())
-            .filter_map(|name| self.connect(&name).ok().map(|device| (name, device)))
+            .filter_map(|name| {
+                self.connect(&name, ShareMode::Shared)
+                    .ok()
+                    .map(|device| (name, device))
+            })
             .map(|(name, device)| {
                 Info {
                     name,
@@ -114,12 +133,203 @@ impl Session {
         self.infos()
             .unwrap_or_else(|_| vec![])
             .iter()
-            .filter_map(|info| self.connect(&info.name).ok())
+            .filter_map(|info| self.connect(&info.name, ShareMode::Shared).ok())
             .collect()
     }
+
+    /// Names of the readers currently attached (with or without card).
+    pub fn readers(&self) -> Result<Vec<String>> {
+        let len = match self.session.list_readers_len() {
+            Err(pcsc::Error::NoReadersAvailable) => return Ok(vec![]),
+            len => len?,
+        };
+        let mut buffer = vec![0; len];
+        let readers = match self.session.list_readers(&mut buffer) {
+            Err(pcsc::Error::NoReadersAvailable) => return Ok(vec![]),
+            readers => readers?,
+        };
+        Ok(readers
+            .map(|name| name.to_string_lossy().to_string())
+            .collect())
+    }
+
+    /// Watch for readers and cards coming and going.
+    ///
+    /// Readers and cards already present are reported first, as if they had just arrived.
+    /// Without timeout, the iterator blocks until the next event.
+    pub fn watch(&self) -> Watcher {
+        Watcher {
+            session: Session {
+                session: self.session.clone(),
+            },
+            readers: vec![ReaderState::new(pcsc::PNP_NOTIFICATION(), State::UNAWARE)],
+            pending: VecDeque::new(),
+            timeout: None,
+        }
+    }
+}
+
+/// Reader or card insertion or removal.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+    ReaderAdded(String),
+    ReaderRemoved(String),
+    /// The UUID is resolved via the admin app, so is `None` for cards other than Solo 2.
+    CardInserted {
+        reader: String,
+        uuid: Option<Uuid>,
+    },
+    CardRemoved(String),
+}
+
+/// Iterator over [`Event`]s, based on `SCardGetStatusChange`.
+pub struct Watcher {
+    session: Session,
+    /// the PnP pseudo reader, followed by the actual readers
+    readers: Vec<ReaderState>,
+    pending: VecDeque<Event>,
+    timeout: Option<Duration>,
+}
+
+/// What happened to the card in a reader between two states.
+fn card_event(reader: &str, before: State, after: State) -> Option<Event> {
+    let was_present = before.contains(State::PRESENT);
+    let is_present = after.contains(State::PRESENT);
+    match (was_present, is_present) {
+        (false, true) => Some(Event::CardInserted {
+            reader: reader.to_string(),
+            uuid: None,
+        }),
+        (true, false) => Some(Event::CardRemoved(reader.to_string())),
+        _ => None,
+    }
+}
+
+impl Watcher {
+    /// Stop waiting for events after this long, yielding [`Error::Timeout`].
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn resolve_uuid(&self, reader: &str) -> Option<Uuid> {
+        let mut device = self.session.connect(reader, ShareMode::Shared).ok()?;
+        device.try_uuid().ok()
+    }
+
+    /// Track readers that appeared, stop tracking those that disappeared.
+    fn sync_readers(&mut self) -> Result<()> {
+        let readers = self.session.readers()?;
+        let mut removed = Vec::new();
+        self.readers.retain(|state| {
+            let name = state.name().to_string_lossy();
+            let keep =
+                state.name() == pcsc::PNP_NOTIFICATION() || readers.contains(&name.to_string());
+            if !keep {
+                removed.push((name.to_string(), state.current_state()));
+            }
+            keep
+        });
+        for (name, state) in removed {
+            if state.contains(State::PRESENT) {
+                self.pending.push_back(Event::CardRemoved(name.clone()));
+            }
+            self.pending.push_back(Event::ReaderRemoved(name));
+        }
+        for reader in readers {
+            if !self
+                .readers
+                .iter()
+                .any(|state| state.name().to_string_lossy() == reader)
+            {
+                // INVARIANT: reader names are C strings to begin with
+                let name = CString::new(reader.as_bytes()).unwrap();
+                self.readers.push(ReaderState::new(name, State::UNAWARE));
+                self.pending.push_back(Event::ReaderAdded(reader));
+            }
+        }
+        Ok(())
+    }
+
+    fn wait(&mut self) -> Result<()> {
+        if self.readers.len() == 1 {
+            // first call, or no readers
+            self.sync_readers()?;
+        }
+        match self
+            .session
+            .session
+            .get_status_change(self.timeout, &mut self.readers)
+        {
+            Err(pcsc::Error::Timeout) => return Err(Error::Timeout),
+            result => result?,
+        }
+
+        let mut readers_changed = false;
+        let mut events = Vec::new();
+        for state in self.readers.iter_mut() {
+            if !state.event_state().contains(State::CHANGED) {
+                continue;
+            }
+            // the PnP pseudo reader signals readers coming and going,
+            // readers themselves signal it by becoming unknown or ignored
+            if state.name() == pcsc::PNP_NOTIFICATION()
+                || state
+                    .event_state()
+                    .intersects(State::UNKNOWN | State::IGNORE)
+            {
+                readers_changed = true;
+            } else {
+                let name = state.name().to_string_lossy().to_string();
+                events.extend(card_event(
+                    &name,
+                    state.current_state(),
+                    state.event_state(),
+                ));
+            }
+            state.sync_current_state();
+        }
+        if readers_changed {
+            self.sync_readers()?;
+        }
+        for event in events {
+            let event = match event {
+                Event::CardInserted { reader, .. } => Event::CardInserted {
+                    uuid: self.resolve_uuid(&reader),
+                    reader,
+                },
+                event => event,
+            };
+            self.pending.push_back(event);
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() {
+            if let Err(error) = self.wait() {
+                return Some(Err(error));
+            }
+        }
+        self.pending.pop_front().map(Ok)
+    }
 }
 
 impl Device {
+    /// Begin an exclusive transaction (SCardBeginTransaction).
+    ///
+    /// Blocks while another process has a transaction with the card.
+    pub fn transaction(&mut self) -> Result<Transaction<'_>> {
+        Ok(Transaction {
+            device: self.device.transaction()?,
+            name: &self.name,
+        })
+    }
+
     // serial: CStr
     // vendor: CStr
     // version: [u8] (?)
@@ -134,7 +344,7 @@ impl Device {
                 }
                 attribute
             })
-            .unwrap_or_else(|| "".to_string())
+            .unwrap_or_default()
     }
 }
 
@@ -151,9 +361,9 @@ impl fmt::Display for Device {
 }
 
 impl UuidSelectable for Device {
-    fn try_uuid(&mut self) -> Result<Uuid> {
+    fn try_uuid(&mut self) -> anyhow::Result<Uuid> {
         let mut admin = Admin::select(self)?;
-        admin.uuid()
+        Ok(admin.uuid()?)
     }
 
     /// Infallible method listing all usable smartcards.
@@ -180,3 +390,30 @@ impl UuidSelectable for Device {
     //     }
     // }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn card_events() {
+        let inserted = card_event("reader", State::EMPTY, State::PRESENT | State::CHANGED);
+        assert_eq!(
+            inserted,
+            Some(Event::CardInserted {
+                reader: "reader".to_string(),
+                uuid: None
+            })
+        );
+        // initial state of a reader with card
+        assert!(card_event("reader", State::UNAWARE, State::PRESENT).is_some());
+        assert_eq!(
+            card_event("reader", State::PRESENT | State::INUSE, State::EMPTY),
+            Some(Event::CardRemoved("reader".to_string()))
+        );
+        assert_eq!(
+            card_event("reader", State::PRESENT, State::PRESENT | State::INUSE),
+            None
+        );
+    }
+}
@@ -0,0 +1,194 @@
+//! Presence of Solo 2 devices, across CTAP HID, PCSC and the LPC 55 bootloader.
+//!
+//! HID offers no notifications, so HID devices (including bootloaders) are enumerated
+//! periodically, while PCSC card events are waited for in between.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use lpc55::bootloader::{Bootloader as Lpc55, UuidSelectable as _};
+
+use super::{ctap, pcsc};
+use crate::{Error, Result, Uuid};
+
+/// The mode a present device is in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Presence {
+    /// Regular firmware, reachable via CTAP and/or PCSC
+    Solo2,
+    /// LPC 55 bootloader
+    Maintenance,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Event {
+    /// Appeared with regular firmware (possibly leaving maintenance mode).
+    Arrived(Uuid),
+    /// Appeared in bootloader mode (possibly leaving regular mode).
+    EnteredMaintenance(Uuid),
+    Left(Uuid),
+}
+
+/// Merged stream of [`Event`]s, keyed by UUID.
+///
+/// Devices already present are reported first, as if they had just arrived.
+pub struct Watcher {
+    pcsc: Option<pcsc::Watcher>,
+    /// UUIDs of Solo 2 cards, by reader name
+    cards: BTreeMap<String, Uuid>,
+    devices: BTreeMap<Uuid, Presence>,
+    pending: VecDeque<Event>,
+}
+
+impl Default for Watcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Events turning the `before` presences into the `after` ones.
+fn diff(before: &BTreeMap<Uuid, Presence>, after: &BTreeMap<Uuid, Presence>) -> Vec<Event> {
+    let mut events = Vec::new();
+    for (uuid, presence) in after {
+        if before.get(uuid) != Some(presence) {
+            events.push(match presence {
+                Presence::Solo2 => Event::Arrived(*uuid),
+                Presence::Maintenance => Event::EnteredMaintenance(*uuid),
+            });
+        }
+    }
+    for uuid in before.keys() {
+        if !after.contains_key(uuid) {
+            events.push(Event::Left(*uuid));
+        }
+    }
+    events
+}
+
+impl Watcher {
+    /// How often HID devices are enumerated.
+    pub const INTERVAL: Duration = Duration::from_millis(250);
+
+    pub fn new() -> Self {
+        Self {
+            pcsc: pcsc::Session::new()
+                .ok()
+                .map(|session| session.watch().timeout(Some(Self::INTERVAL))),
+            cards: BTreeMap::new(),
+            devices: BTreeMap::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Current presence of a device, as of the last event.
+    pub fn presence(&self, uuid: Uuid) -> Option<Presence> {
+        self.devices.get(&uuid).copied()
+    }
+
+    /// Wait up to one interval for changes, queueing events.
+    fn poll(&mut self) {
+        match self.pcsc.as_mut().and_then(Iterator::next) {
+            Some(Ok(pcsc::Event::CardInserted {
+                reader,
+                uuid: Some(uuid),
+            })) => {
+                self.cards.insert(reader, uuid);
+            }
+            Some(Ok(pcsc::Event::CardRemoved(reader) | pcsc::Event::ReaderRemoved(reader))) => {
+                self.cards.remove(&reader);
+            }
+            Some(Ok(_)) | Some(Err(Error::Timeout)) => {}
+            Some(Err(error)) => {
+                // e.g., the PCSC service went away
+                warn!("giving up on PCSC events: {}", error);
+                self.pcsc = None;
+            }
+            None => std::thread::sleep(Self::INTERVAL),
+        }
+
+        let mut devices = BTreeMap::new();
+        if let Ok(session) = ctap::Session::new() {
+            for info in session.infos() {
+                if let Some(uuid) = hex::decode(&info.serial)
+                    .ok()
+                    .and_then(|uuid| Uuid::from_slice(&uuid).ok())
+                {
+                    devices.insert(uuid, Presence::Solo2);
+                }
+            }
+        }
+        for uuid in self.cards.values() {
+            devices.insert(*uuid, Presence::Solo2);
+        }
+        for bootloader in Lpc55::list() {
+            devices.insert(Uuid::from_u128(bootloader.uuid), Presence::Maintenance);
+        }
+
+        self.pending.extend(diff(&self.devices, &devices));
+        self.devices = devices;
+    }
+
+    /// The next event, if it happens before the deadline.
+    pub fn next_before(&mut self, deadline: Instant) -> Result<Event> {
+        while self.pending.is_empty() {
+            if Instant::now() >= deadline {
+                return Err(Error::Timeout);
+            }
+            self.poll();
+        }
+        // INVARIANT: not empty
+        Ok(self.pending.pop_front().unwrap())
+    }
+
+    /// Wait until the device is present in the given mode (or gone, for `None`).
+    pub fn wait_for(
+        &mut self,
+        uuid: Uuid,
+        presence: Option<Presence>,
+        timeout: Duration,
+    ) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        // make sure we have seen the current state at least once
+        if self.devices.is_empty() {
+            self.poll();
+        }
+        while self.presence(uuid) != presence {
+            self.next_before(deadline)?;
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = Event;
+
+    /// Blocks until the next event.
+    fn next(&mut self) -> Option<Event> {
+        while self.pending.is_empty() {
+            self.poll();
+        }
+        self.pending.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn presence_changes() {
+        let (a, b, c) = (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        let before = BTreeMap::from_iter([(a, Presence::Solo2), (b, Presence::Solo2)]);
+        let after = BTreeMap::from_iter([(a, Presence::Maintenance), (c, Presence::Solo2)]);
+        assert_eq!(
+            diff(&before, &after),
+            [
+                Event::EnteredMaintenance(a),
+                Event::Arrived(c),
+                Event::Left(b)
+            ]
+        );
+        assert!(diff(&after, &after).is_empty());
+        assert_eq!(diff(&BTreeMap::new(), &before).len(), 2);
+    }
+}
@@ -1,7 +1,145 @@
 //! Error and Result types.
 //!
-//! Currently we just use `anyhow`. In time, library errors should be modeled properly,
-//! and implemented e.g. using `thiserror`.
+//! Library functions return the structured [`Error`], so that callers can tell apart,
+//! e.g., a missing device from a card refusing a command, without matching on strings.
 //!
-pub type Error = anyhow::Error;
-pub type Result<T> = anyhow::Result<T>;
+use crate::{
+    transport::{ctap, StatusWord},
+    Uuid, Version,
+};
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// No usable device is connected.
+    #[error("no usable device found")]
+    NoDevice,
+
+    /// No usable device has the requested UUID.
+    #[error("no usable device has UUID {:X}", .0.simple())]
+    NoSuchDevice(Uuid),
+
+    /// Several devices claim the requested UUID.
+    #[error("multiple ({count}) devices have UUID {:X}", .uuid.simple())]
+    AmbiguousDevice { uuid: Uuid, count: usize },
+
+    /// Communication with a HID device failed.
+    #[error("HID transport: {0}")]
+    Hid(#[from] hidapi::HidError),
+
+    /// Communication with the PCSC service or a smartcard failed.
+    #[error("PCSC transport: {0}")]
+    Pcsc(#[from] pcsc::Error),
+
+    /// The card answered with an ISO 7816 status word other than 9000.
+    #[error("card signaled error {0}")]
+    Status(StatusWord),
+
+    /// The requested object (e.g., an OATH credential) does not exist.
+    #[error("not found: {0}")]
+    NotFound(String),
+
+    /// PIN verification failed.
+    #[error("wrong PIN ({retries} retries left)")]
+    WrongPin { retries: u8 },
+
+    /// The OATH app rejected the password.
+    #[error("wrong password")]
+    WrongPassword,
+
+    /// The device answered with a CTAPHID error.
+    #[error("CTAPHID error: {0:?}")]
+    Ctaphid(ctap::Error),
+
+    /// The authenticator answered a CTAP2 command with a status other than success.
+    #[error("CTAP2 error {0:#04X}")]
+    Ctap2(u8),
+
+    /// The operation is not available on the transport in use.
+    #[error("not supported: {0}")]
+    Unsupported(String),
+
+    /// The device responded with something we can't make sense of.
+    #[error("unexpected response: {0}")]
+    Protocol(String),
+
+    /// The device did not respond in time.
+    #[error("timed out")]
+    Timeout,
+
+    /// The user did not confirm presence (by tapping the device) in time.
+    #[error("user presence not confirmed")]
+    UserPresence,
+
+    /// The user declined to continue.
+    #[error("aborted by user")]
+    Aborted,
+
+    /// Firmware older than the one on the device would be rejected by the bootloader.
+    #[error(
+        "firmware rollback refused (device runs {}, firmware is {})",
+        .device.to_calver(),
+        .firmware.to_calver()
+    )]
+    Rollback { device: Version, firmware: Version },
+
+    /// Firmware could not be obtained or did not verify.
+    #[error("firmware: {0}")]
+    Firmware(String),
+
+    /// A certificate could not be parsed or did not verify.
+    #[error("certificate: {0}")]
+    Certificate(String),
+
+    /// A signature made by the device did not verify.
+    #[error("signature: {0}")]
+    Signature(String),
+
+    /// The caller passed something unusable.
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Http(Box<ureq::Error>),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    /// The system could not provide randomness (e.g., for a challenge or an ephemeral key).
+    #[error("no randomness: {0}")]
+    Rng(String),
+}
+
+impl From<ctap::Error> for Error {
+    fn from(error: ctap::Error) -> Self {
+        Self::Ctaphid(error)
+    }
+}
+
+impl From<ureq::Error> for Error {
+    fn from(error: ureq::Error) -> Self {
+        Self::Http(Box::new(error))
+    }
+}
+
+impl From<core::array::TryFromSliceError> for Error {
+    fn from(_: core::array::TryFromSliceError) -> Self {
+        Self::Protocol("unexpected response length".to_string())
+    }
+}
+
+impl From<flexiber::ErrorKind> for Error {
+    fn from(error: flexiber::ErrorKind) -> Self {
+        Self::Protocol(format!("TLV decoding failed: {}", error))
+    }
+}
+
+impl From<x509_parser::nom::Err<x509_parser::error::X509Error>> for Error {
+    fn from(error: x509_parser::nom::Err<x509_parser::error::X509Error>) -> Self {
+        Self::Certificate(error.to_string())
+    }
+}
@@ -1,10 +1,9 @@
 //! Signed firmware releases for Solo 2 devices.
 //!
-use anyhow::anyhow;
 /// Version of a firmware
 pub use lpc55::secure_binary::Version;
 
-use crate::Result;
+use crate::{Error, Result};
 
 pub mod github;
 
@@ -26,11 +25,7 @@ impl Firmware {
     // pub fn verify(&self) -> Result<()> {
     // }
 
-    pub fn write_to<'a>(
-        &self,
-        bootloader: &lpc55::Bootloader,
-        progress: Option<&'a dyn Fn(usize)>,
-    ) {
+    pub fn write_to(&self, bootloader: &lpc55::Bootloader, progress: Option<&dyn Fn(usize)>) {
         bootloader.receive_sb_file(&self.content, progress);
     }
 
@@ -43,12 +38,15 @@ impl Firmware {
 
         (hex::encode(hasher.finalize()) == sha256_hex_hash)
             .then(|| ())
-            .ok_or_else(|| anyhow!("Sha2 hash on downloaded firmware did not verify!"))
+            .ok_or_else(|| {
+                Error::Firmware("Sha2 hash on downloaded firmware did not verify!".to_string())
+            })
     }
 
     pub fn new(content: Vec<u8>) -> Result<Self> {
         let header_bytes = &content.as_slice()[..96];
-        let header = lpc55::secure_binary::Sb2Header::from_bytes(header_bytes)?;
+        let header = lpc55::secure_binary::Sb2Header::from_bytes(header_bytes)
+            .map_err(|e| Error::Firmware(format!("invalid SB2 header: {}", e)))?;
 
         Ok(Self {
             content,
@@ -3,10 +3,9 @@
 //! We'd use one of the many existing clients, if only there were a non-async one.
 use std::io::Read as _;
 
-use anyhow::anyhow;
 use serde_json::{from_value, Value};
 
-use super::{Firmware, Result};
+use super::{Error, Firmware, Result};
 
 /// An asset that can be downloaded from GitHub
 #[derive(Clone, Debug)]
@@ -17,7 +16,7 @@ pub struct AssetSpec {
 }
 
 impl TryFrom<Value> for AssetSpec {
-    type Error = crate::Error;
+    type Error = Error;
     fn try_from(value: Value) -> Result<Self> {
         let name: String = from_value(value["name"].clone())?;
         let url: String = from_value(value["browser_download_url"].clone())?;
@@ -40,7 +39,10 @@ impl AssetSpec {
         if self.len == buffer.len() {
             Ok(buffer)
         } else {
-            Err(anyhow!("Truncated download from {}", &self.url))
+            Err(Error::Firmware(format!(
+                "Truncated download from {}",
+                &self.url
+            )))
         }
     }
 }
@@ -79,11 +81,11 @@ impl Release {
         let spec = self.assets.iter()
             // poor man's format!
             .find(|asset| asset.name == Self::HASH_TEMPLATE.replace("{}", &self.tag))
-            .ok_or_else(|| anyhow!("Unable to find hash digest in latest SoloKeys release. Please open ticket on solokeys.com/solo2 or contact hello@solokeys.com."))?;
+            .ok_or_else(|| Error::Firmware("Unable to find hash digest in latest SoloKeys release. Please open ticket on solokeys.com/solo2 or contact hello@solokeys.com.".to_string()))?;
 
         let hash_data = &spec.fetch_asset()?;
         let hash = std::str::from_utf8(hash_data)
-            .map_err(|_| anyhow!("Invalid hash digest in latest SoloKeys release. Please open ticket on solokeys.com/solo2 or contact hello@solokeys.com."))?;
+            .map_err(|_| Error::Firmware("Invalid hash digest in latest SoloKeys release. Please open ticket on solokeys.com/solo2 or contact hello@solokeys.com.".to_string()))?;
         let hash = hash.split_whitespace().next().unwrap().to_string();
         Ok(hash)
     }
@@ -92,7 +94,7 @@ impl Release {
         let spec = self.assets.iter()
             // poor man's format!
             .find(|asset| asset.name == Self::SB2_TEMPLATE.replace("{}", &self.tag))
-            .ok_or_else(|| anyhow!("Unable to find firmware SB2 file in latest SoloKeys release. Please open ticket on solokeys.com/solo2 or contact hello@solokeys.com."))?;
+            .ok_or_else(|| Error::Firmware("Unable to find firmware SB2 file in latest SoloKeys release. Please open ticket on solokeys.com/solo2 or contact hello@solokeys.com.".to_string()))?;
 
         let firmware = Firmware::new(spec.fetch_asset()?)?;
         firmware.verify_hexhash(&self.fetch_hash()?)?;
@@ -7,6 +7,8 @@ pub use lpc55::{uuid::Uuid, UuidSelectable};
 
 pub mod apps;
 pub use apps::{admin::App as Admin, Select};
+pub mod cbor;
+pub mod cose;
 pub mod device;
 pub use device::{Device, Solo2};
 pub mod error;
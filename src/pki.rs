@@ -54,7 +54,7 @@ use std::io::Read as _;
 
 pub use x509_parser::certificate::X509Certificate;
 
-use crate::Result;
+use crate::{Error, Result};
 
 pub const S2PKI_TLD: &str = "s2pki.net";
 
@@ -96,7 +96,7 @@ impl Authority {
 }
 
 impl TryFrom<&str> for Authority {
-    type Error = crate::Error;
+    type Error = Error;
     fn try_from(name: &str) -> Result<Authority> {
         Ok(match name.to_uppercase().as_str() {
             "B1" => Authority::B1,
@@ -111,7 +111,12 @@ impl TryFrom<&str> for Authority {
             "S4" => Authority::S4,
             "T1" => Authority::T1,
             "T2" => Authority::T2,
-            _ => return Err(anyhow::anyhow!("Unknown authority name {}", name)),
+            _ => {
+                return Err(Error::InvalidInput(format!(
+                    "Unknown authority name {}",
+                    name
+                )))
+            }
         })
     }
 }
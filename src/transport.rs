@@ -1,9 +1,85 @@
 //! Partial abstraction (to-be-improved)
 
-use crate::{Result, Solo2};
+use core::fmt;
+
+use crate::{Error, Result, Solo2};
 
 pub mod ctap;
 pub mod pcsc;
+pub mod record;
+pub use record::{RecordingTransport, ReplayTransport};
+pub mod sim;
+
+/// ISO 7816-4 status word, as returned by cards (and U2F authenticators) after each command.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct StatusWord(pub u16);
+
+impl StatusWord {
+    pub const SUCCESS: Self = Self(0x9000);
+    pub const WRONG_LENGTH: Self = Self(0x6700);
+    pub const CHAINING_UNSUPPORTED: Self = Self(0x6884);
+    pub const SECURITY_STATUS_NOT_SATISFIED: Self = Self(0x6982);
+    pub const AUTHENTICATION_BLOCKED: Self = Self(0x6983);
+    pub const DATA_INVALID: Self = Self(0x6984);
+    pub const CONDITIONS_NOT_SATISFIED: Self = Self(0x6985);
+    pub const WRONG_DATA: Self = Self(0x6A80);
+    pub const NOT_FOUND: Self = Self(0x6A82);
+    pub const NOT_ENOUGH_MEMORY: Self = Self(0x6A84);
+    pub const INSTRUCTION_NOT_SUPPORTED: Self = Self(0x6D00);
+    pub const CLASS_NOT_SUPPORTED: Self = Self(0x6E00);
+    pub const UNKNOWN: Self = Self(0x6F00);
+
+    pub fn sw1(self) -> u8 {
+        (self.0 >> 8) as u8
+    }
+
+    pub fn sw2(self) -> u8 {
+        self.0 as u8
+    }
+
+    /// Remaining verification attempts, signaled via 63Cx.
+    pub fn retries(self) -> Option<u8> {
+        (self.0 & 0xFFF0 == 0x63C0).then(|| self.sw2() & 0xF)
+    }
+
+    fn description(self) -> Option<&'static str> {
+        Some(match self {
+            Self::SUCCESS => "success",
+            Self::WRONG_LENGTH => "wrong length",
+            Self::CHAINING_UNSUPPORTED => "command chaining not supported",
+            Self::SECURITY_STATUS_NOT_SATISFIED => "security status not satisfied",
+            Self::AUTHENTICATION_BLOCKED => "authentication method blocked",
+            Self::DATA_INVALID => "data invalid",
+            Self::CONDITIONS_NOT_SATISFIED => "conditions of use not satisfied",
+            Self::WRONG_DATA => "incorrect data",
+            Self::NOT_FOUND => "not found",
+            Self::NOT_ENOUGH_MEMORY => "not enough memory",
+            Self::INSTRUCTION_NOT_SUPPORTED => "instruction not supported",
+            Self::CLASS_NOT_SUPPORTED => "class not supported",
+            Self::UNKNOWN => "unknown error",
+            _ => return None,
+        })
+    }
+}
+
+impl From<u16> for StatusWord {
+    fn from(sw: u16) -> Self {
+        Self(sw)
+    }
+}
+
+impl fmt::Display for StatusWord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04X}", self.0)?;
+        if let Some(retries) = self.retries() {
+            write!(f, " (verification failed, {} retries left)", retries)
+        } else if let Some(description) = self.description() {
+            write!(f, " ({})", description)
+        } else {
+            Ok(())
+        }
+    }
+}
 
 // Applications that only implement single-byte instructions
 // with byte slice responses can be implemented on this
@@ -25,26 +101,111 @@ pub trait Transport {
         p2: u8,
         data: &[u8],
     ) -> Result<Vec<u8>>;
-    fn select(&mut self, aid: Vec<u8>) -> Result<()>;
+    /// Select an application, returning its answer (empty on the CTAP transport).
+    fn select(&mut self, aid: Vec<u8>) -> Result<Vec<u8>>;
+    /// Make a sequence of calls that other processes can't interleave with.
+    ///
+    /// Only the PCSC transport needs this (other processes may SELECT other applications
+    /// in between), the CTAP transport just makes the calls.
+    fn transaction(
+        &mut self,
+        calls: &mut dyn FnMut(&mut dyn Transport) -> Result<()>,
+    ) -> Result<()>;
+}
+
+impl<T: Transport + ?Sized> Transport for &mut T {
+    fn call(&mut self, instruction: u8, data: &[u8]) -> Result<Vec<u8>> {
+        (**self).call(instruction, data)
+    }
+
+    fn call_iso(
+        &mut self,
+        class: u8,
+        instruction: u8,
+        p1: u8,
+        p2: u8,
+        data: &[u8],
+    ) -> Result<Vec<u8>> {
+        (**self).call_iso(class, instruction, p1, p2, data)
+    }
+
+    fn select(&mut self, aid: Vec<u8>) -> Result<Vec<u8>> {
+        (**self).select(aid)
+    }
+
+    fn transaction(
+        &mut self,
+        calls: &mut dyn FnMut(&mut dyn Transport) -> Result<()>,
+    ) -> Result<()> {
+        (**self).transaction(calls)
+    }
+}
+
+/// Parse BER-TLV data with one byte tags (as used by the OATH app) into tags and value ranges.
+pub(crate) fn tlvs(data: &[u8]) -> Result<Vec<(u8, core::ops::Range<usize>)>> {
+    tlvs_and_tvs(data, &[])
+}
+
+/// Like [tlvs], but the `tvs` tags are followed by one value byte without length,
+/// such as the properties of an OATH PUT.
+pub(crate) fn tlvs_and_tvs(data: &[u8], tvs: &[u8]) -> Result<Vec<(u8, core::ops::Range<usize>)>> {
+    let malformed = || Error::Protocol(format!("malformed TLV data {}", hex::encode(data)));
+    let mut tlvs = Vec::new();
+    let mut offset = 0;
+    while let Some(&tag) = data.get(offset) {
+        let (length, header) = match data[offset + 1..] {
+            _ if tvs.contains(&tag) => (1, 1),
+            [0x81, length, ..] => (length as usize, 3),
+            [0x82, hi, lo, ..] => (u16::from_be_bytes([hi, lo]) as usize, 4),
+            [length, ..] if length < 0x80 => (length as usize, 2),
+            _ => return Err(malformed()),
+        };
+        let start = offset + header;
+        if data.len() - start < length {
+            return Err(malformed());
+        }
+        tlvs.push((tag, start..start + length));
+        offset = start + length;
+    }
+    Ok(tlvs)
 }
 
 impl Transport for ctap::Device {
     fn call(&mut self, instruction: u8, data: &[u8]) -> Result<Vec<u8>> {
         use ctap::{Code, Command};
         let init = self.init()?;
-        let command = Command::new(Code::from(instruction)).with_data(data);
+        let command = Command::new(Code::try_from(instruction)?).with_data(data)?;
         ctap::Device::call(self, init.channel, command)
     }
 
     fn call_iso(&mut self, _: u8, _: u8, _: u8, _: u8, _: &[u8]) -> Result<Vec<u8>> {
-        Err(anyhow::anyhow!(
-            "p1/p2 parameters not supported on this transport"
+        Err(Error::Unsupported(
+            "p1/p2 parameters on this transport".to_string(),
         ))
     }
 
-    fn select(&mut self, _: Vec<u8>) -> Result<()> {
-        Ok(())
+    fn select(&mut self, _: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(vec![])
     }
+
+    fn transaction(
+        &mut self,
+        calls: &mut dyn FnMut(&mut dyn Transport) -> Result<()>,
+    ) -> Result<()> {
+        calls(self)
+    }
+}
+
+/// The ISO 7816 SELECT command, logging the answer.
+fn select(transport: &mut dyn Transport, aid: &[u8]) -> Result<Vec<u8>> {
+    let answer_to_select =
+        transport.call_iso(0, iso7816::Instruction::Select.into(), 0x04, 0x00, aid)?;
+    info!(
+        "answer to selecting {}: {}",
+        &hex::encode(aid),
+        &hex::encode(&answer_to_select)
+    );
+    Ok(answer_to_select)
 }
 
 impl Transport for pcsc::Device {
@@ -63,22 +224,44 @@ impl Transport for pcsc::Device {
         self.call(class, instruction, p1, p2, Some(data))
     }
 
-    fn select(&mut self, aid: Vec<u8>) -> Result<()> {
-        let answer_to_select = pcsc::Device::call(
-            self,
-            0,
-            iso7816::Instruction::Select.into(),
-            0x04,
-            0x00,
-            Some(&aid),
-        )?;
-        // let answer_to_select = app.select()?;
-        info!(
-            "answer to selecting {}: {}",
-            &hex::encode(&aid),
-            &hex::encode(answer_to_select)
-        );
-        Ok(())
+    fn select(&mut self, aid: Vec<u8>) -> Result<Vec<u8>> {
+        select(self, &aid)
+    }
+
+    fn transaction(
+        &mut self,
+        calls: &mut dyn FnMut(&mut dyn Transport) -> Result<()>,
+    ) -> Result<()> {
+        calls(&mut pcsc::Device::transaction(self)?)
+    }
+}
+
+impl Transport for pcsc::Transaction<'_> {
+    fn call(&mut self, instruction: u8, data: &[u8]) -> Result<Vec<u8>> {
+        pcsc::Transaction::call(self, 0, instruction, 0x00, 0x00, Some(data))
+    }
+
+    fn call_iso(
+        &mut self,
+        class: u8,
+        instruction: u8,
+        p1: u8,
+        p2: u8,
+        data: &[u8],
+    ) -> Result<Vec<u8>> {
+        self.call(class, instruction, p1, p2, Some(data))
+    }
+
+    fn select(&mut self, aid: Vec<u8>) -> Result<Vec<u8>> {
+        select(self, &aid)
+    }
+
+    /// Already exclusive.
+    fn transaction(
+        &mut self,
+        calls: &mut dyn FnMut(&mut dyn Transport) -> Result<()>,
+    ) -> Result<()> {
+        calls(self)
     }
 }
 
@@ -124,17 +307,31 @@ impl Transport for Solo2 {
         if let Some(device) = self.as_pcsc_mut() {
             device.call_iso(class, instruction, p1, p2, data)
         } else {
-            Err(anyhow::anyhow!(
-                "p1/p2 parameters not supported on this transport"
+            Err(Error::Unsupported(
+                "p1/p2 parameters on this transport".to_string(),
             ))
         }
     }
 
-    fn select(&mut self, aid: Vec<u8>) -> Result<()> {
+    fn select(&mut self, aid: Vec<u8>) -> Result<Vec<u8>> {
         if let Some(device) = self.as_pcsc_mut() {
             device.select(aid)
         } else {
-            Ok(())
+            Ok(vec![])
+        }
+    }
+
+    fn transaction(
+        &mut self,
+        calls: &mut dyn FnMut(&mut dyn Transport) -> Result<()>,
+    ) -> Result<()> {
+        use crate::device::TransportPreference::*;
+        match (Solo2::transport_preference(), self.as_ctap().is_some()) {
+            (Ctap, true) => calls(self),
+            _ => match self.as_pcsc_mut() {
+                Some(device) => Transport::transaction(device, calls),
+                None => calls(self),
+            },
         }
     }
 }
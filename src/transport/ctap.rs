@@ -2,14 +2,26 @@
 //!
 //! Can switch to `ctaphid` once it stabilizes.
 
+use std::io::Write;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
+
+use super::record;
+
 pub use crate::{device::ctap::Device, Result};
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 /// CTAPHID commands
 pub enum Code {
     Ping,
+    Msg,
     Init,
     Wink,
+    Cbor,
+    Cancel,
     Error,
     Keepalive,
     Vendor(VendorCode),
@@ -77,18 +89,27 @@ impl From<u8> for Error {
     }
 }
 
-impl From<u8> for Code {
-    fn from(code: u8) -> Self {
+impl TryFrom<u8> for Code {
+    type Error = crate::Error;
+    fn try_from(code: u8) -> Result<Self> {
         use Code::*;
-        match code {
+        Ok(match code {
             0x1 => Ping,
+            0x3 => Msg,
             0x6 => Init,
             0x8 => Wink,
+            0x10 => Cbor,
+            0x11 => Cancel,
             0x3F => Error,
             0x3B => Keepalive,
-            vendor_code @ 0x40..=0x7F => Vendor(VendorCode::new(vendor_code)),
-            _ => panic!(),
-        }
+            vendor_code @ 0x40..=0x7F => Vendor(VendorCode(vendor_code)),
+            code => {
+                return Err(crate::Error::InvalidInput(format!(
+                    "not a CTAPHID command: {:#04x}",
+                    code
+                )))
+            }
+        })
     }
 }
 
@@ -97,8 +118,11 @@ impl From<Code> for u8 {
         use Code::*;
         match code {
             Ping => 0x1,
+            Msg => 0x3,
             Init => 0x6,
             Wink => 0x8,
+            Cbor => 0x10,
+            Cancel => 0x11,
             Error => 0x3F,
             Keepalive => 0x3B,
             Vendor(code) => code.0,
@@ -108,12 +132,23 @@ impl From<Code> for u8 {
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct VendorCode(u8);
-impl VendorCode {
-    /// Must be at least 0x40 and at most 0x7F, else panic.
-    pub const fn new(vendor_code: u8) -> Self {
-        assert!(vendor_code >= 0x40);
-        assert!(vendor_code <= 0x7F);
-        Self(vendor_code)
+
+impl TryFrom<u8> for VendorCode {
+    type Error = crate::Error;
+    fn try_from(code: u8) -> Result<Self> {
+        match code {
+            0x40..=0x7F => Ok(Self(code)),
+            code => Err(crate::Error::InvalidInput(format!(
+                "vendor commands are 0x40 to 0x7F, not {:#04x}",
+                code
+            ))),
+        }
+    }
+}
+
+impl From<VendorCode> for u8 {
+    fn from(code: VendorCode) -> u8 {
+        code.0
     }
 }
 
@@ -127,19 +162,20 @@ impl Command {
         Self { code, data: vec![] }
     }
 
-    pub fn with_data(self, data: &[u8]) -> Self {
-        assert!(data.len() <= 7609);
-        Self {
+    /// Fails if the data exceeds the maximum CTAPHID message length.
+    pub fn with_data(self, data: &[u8]) -> Result<Self> {
+        check_length(data)?;
+        Ok(Self {
             code: self.code,
             data: data.to_vec(),
-        }
+        })
     }
 
     pub fn packets(&self, channel: Channel) -> impl Iterator<Item = [u8; 64]> + '_ {
         use std::iter;
 
+        // INVARIANT: `with_data` checked the length fits in the sequence numbers
         let l = self.data.len();
-        assert!(l <= 7609);
         let data = &self.data;
         let init_l = core::cmp::min(l, 64 - 7);
         // dbg!("init_l", init_l);
@@ -185,110 +221,340 @@ impl Channel {
     pub const BROADCAST: Self = Self(0xffff_ffff);
 }
 
-impl Device {
-    pub fn call(&self, channel: Channel, request: Command) -> Result<Vec<u8>> {
-        let result: Result<Vec<()>> = request
-            .packets(channel)
-            .enumerate()
-            .map(|(_i, packet)| {
-                // need to prefix report ID
-                let mut prefixed = vec![0];
-                prefixed.extend_from_slice(&packet);
-                self.device.write(&prefixed).map_err(|e| e.into()).map(drop) //|size| println!("sent {}", size))
-            })
-            .collect();
-        result?;
-
-        let mut packet = [0u8; 64];
-        // trace!("packet: {}", hex::encode(packet));
-        loop {
-            let read = self.device.read(&mut packet)?;
-            assert!(read >= 7);
-            if packet[..4] != channel.0.to_be_bytes() {
-                // got response for other channel
-                continue;
+/// Packet level I/O underlying CTAPHID.
+///
+/// Implemented for `hidapi` devices; abstracted so the framing below can be
+/// exercised without hardware.
+pub trait PacketIo {
+    /// Write one packet (without report ID).
+    fn write_packet(&self, packet: &[u8; 64]) -> Result<()>;
+    /// Read one packet, returning the number of bytes read (zero on timeout).
+    fn read_packet(&self, packet: &mut [u8; 64], timeout: Duration) -> Result<usize>;
+}
+
+impl PacketIo for hidapi::HidDevice {
+    fn write_packet(&self, packet: &[u8; 64]) -> Result<()> {
+        // need to prefix report ID
+        let mut prefixed = vec![0];
+        prefixed.extend_from_slice(packet);
+        self.write(&prefixed)?;
+        Ok(())
+    }
+
+    fn read_packet(&self, packet: &mut [u8; 64], timeout: Duration) -> Result<usize> {
+        let timeout = i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX);
+        Ok(self.read_timeout(packet, timeout)?)
+    }
+}
+
+/// Wraps a [`PacketIo`], writing each packet to a JSON lines transcript.
+///
+/// Uses the format of [`RecordingTransport`][super::RecordingTransport] (cf. [`record`]),
+/// so [`ReplayTransport`][super::ReplayTransport] can serve the packets back.
+pub struct RecordingPacketIo<P, W> {
+    inner: P,
+    writer: Mutex<W>,
+}
+
+impl<P: PacketIo, W: Write> RecordingPacketIo<P, W> {
+    pub fn new(inner: P, writer: W) -> Self {
+        Self {
+            inner,
+            writer: Mutex::new(writer),
+        }
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+
+    fn record(&self, entry: serde_json::Value, time: u64) -> Result<()> {
+        // a panic while writing at worst leaves a partial line
+        let mut writer = self
+            .writer
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        record::write_entry(&mut *writer, entry, time)
+    }
+}
+
+impl<P: PacketIo, W: Write> PacketIo for RecordingPacketIo<P, W> {
+    fn write_packet(&self, packet: &[u8; 64]) -> Result<()> {
+        let time = record::now();
+        let result = self.inner.write_packet(packet);
+        self.record(
+            record::packet_entry(record::CTAPHID_WRITE, packet, &result),
+            time,
+        )?;
+        result
+    }
+
+    fn read_packet(&self, packet: &mut [u8; 64], timeout: Duration) -> Result<usize> {
+        let time = record::now();
+        let result = self.inner.read_packet(packet, timeout);
+        let (read, outcome) = match &result {
+            Ok(read) => (&packet[..*read], Ok(())),
+            Err(error) => (&[][..], Err(crate::Error::Protocol(error.to_string()))),
+        };
+        self.record(
+            record::packet_entry(record::CTAPHID_READ, read, &outcome),
+            time,
+        )?;
+        result
+    }
+}
+
+impl<P: PacketIo + ?Sized> PacketIo for Box<P> {
+    fn write_packet(&self, packet: &[u8; 64]) -> Result<()> {
+        (**self).write_packet(packet)
+    }
+
+    fn read_packet(&self, packet: &mut [u8; 64], timeout: Duration) -> Result<usize> {
+        (**self).read_packet(packet, timeout)
+    }
+}
+
+/// Maximum payload of a CTAPHID message: one initialization packet and 128 continuation packets.
+pub const MAX_MESSAGE_LENGTH: usize = 7609;
+const INIT_DATA_LENGTH: usize = 64 - 7;
+const CONT_DATA_LENGTH: usize = 64 - 5;
+
+fn send(device: &dyn PacketIo, channel: Channel, request: &Command) -> Result<()> {
+    request
+        .packets(channel)
+        .try_for_each(|packet| device.write_packet(&packet))
+}
+
+/// Handle to cancel a pending request, e.g. from a Ctrl-C handler.
+///
+/// Cancellation happens while the device is still processing (i.e., sending keepalives),
+/// by sending CTAPHID_CANCEL, and the request then fails with [`Aborted`][crate::Error::Aborted].
+#[derive(Clone, Debug, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request cancellation of the pending (or next) request.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn take(&self) -> bool {
+        self.0.swap(false, Ordering::SeqCst)
+    }
+}
+
+/// How to wait for a response.
+struct Waiting<'a> {
+    /// for each packet
+    timeout: Duration,
+    keepalive: Option<&'a (dyn Fn(Status) + Send)>,
+    cancel: Option<&'a CancelHandle>,
+    /// for the entire response
+    deadline: Option<Instant>,
+}
+
+impl Waiting<'_> {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            keepalive: None,
+            cancel: None,
+            deadline: None,
+        }
+    }
+}
+
+/// Discard the final response to a cancelled request (usually CTAP2_ERR_KEEPALIVE_CANCEL
+/// or an error frame), so the next request on `channel` does not read it.
+///
+/// Gives up after `timeout` without the final response.
+fn drain(device: &dyn PacketIo, channel: Channel, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    let mut packet = [0u8; 64];
+    // continuation bytes still expected of the final response
+    let mut remaining = None;
+    while Instant::now() < deadline {
+        let read = match device.read_packet(&mut packet, timeout) {
+            Ok(read) if read >= 5 => read,
+            _ => return,
+        };
+        if packet[..4] != channel.0.to_be_bytes() {
+            continue;
+        }
+        match remaining {
+            None if packet[4] & (1 << 7) == 0 => continue,
+            None if packet[4] & !(1 << 7) == u8::from(Code::Keepalive) => continue,
+            None if read < 7 => return,
+            None => {
+                let l = u16::from_be_bytes([packet[5], packet[6]]) as usize;
+                remaining = Some(l.saturating_sub(INIT_DATA_LENGTH));
             }
+            Some(l) => remaining = Some(l.saturating_sub(CONT_DATA_LENGTH)),
+        }
+        if remaining == Some(0) {
+            trace!("drained response to cancelled request");
+            return;
+        }
+    }
+}
+
+/// Read a full response to `code` on `channel`.
+///
+/// Packets for other channels, keepalives and stray continuation packets are skipped,
+/// anything else unexpected is an error.
+fn receive(
+    device: &dyn PacketIo,
+    channel: Channel,
+    code: Code,
+    waiting: &Waiting<'_>,
+) -> Result<Vec<u8>> {
+    let timeout = waiting.timeout;
+    let mut packet = [0u8; 64];
+    let read = loop {
+        let read = device.read_packet(&mut packet, timeout)?;
+        if read == 0 {
+            return Err(crate::Error::Timeout);
+        }
+        if read < 7 {
+            return Err(crate::Error::Protocol(format!(
+                "short CTAPHID packet: {}",
+                hex::encode(&packet[..read])
+            )));
+        }
+        if packet[..4] != channel.0.to_be_bytes() {
+            trace!("skipping packet for other channel");
+            continue;
+        }
+        if packet[4] & (1 << 7) == 0 {
+            trace!("skipping stray continuation packet");
+            continue;
+        }
 
-            if packet[4] == u8::from(Code::Keepalive) | (1 << 7) {
-                let status = Status::from(packet[7]);
-                info!("received keepalive, status {:?}", status);
-                continue;
+        let command = packet[4] & !(1 << 7);
+        if command == u8::from(Code::Keepalive) {
+            let status = Status::from(packet[7]);
+            info!("received keepalive, status {:?}", status);
+            if let Some(keepalive) = waiting.keepalive {
+                keepalive(status);
             }
 
-            // the assertion on packet[4] below is is not the case for failed commands (!)
-            if packet[4] == u8::from(Code::Error) | (1 << 7) {
-                return Err(anyhow::anyhow!("error: {:?}", Error::from(packet[7])));
+            let cancelled = waiting.cancel.map_or(false, CancelHandle::take);
+            let expired = waiting
+                .deadline
+                .map_or(false, |deadline| Instant::now() >= deadline);
+            if cancelled || expired {
+                info!("sending CTAPHID_CANCEL");
+                send(device, channel, &Command::new(Code::Cancel))?;
+                drain(device, channel, timeout);
+                return Err(if cancelled {
+                    crate::Error::Aborted
+                } else if status == Status::UserPresenceNeeded {
+                    crate::Error::UserPresence
+                } else {
+                    crate::Error::Timeout
+                });
             }
+            continue;
+        }
+        if command == u8::from(Code::Error) {
+            return Err(Error::from(packet[7]).into());
+        }
+        if command != u8::from(code) {
+            return Err(crate::Error::Protocol(format!(
+                "expected response to command {:#04X}, got {:#04X}",
+                u8::from(code),
+                command
+            )));
+        }
+        break read;
+    };
+
+    let l = u16::from_be_bytes([packet[5], packet[6]]) as usize;
+    if l > MAX_MESSAGE_LENGTH {
+        return Err(crate::Error::Protocol(format!(
+            "CTAPHID message length {} exceeds maximum",
+            l
+        )));
+    }
+    let mut data = vec![0u8; l];
+    let init_l = core::cmp::min(l, INIT_DATA_LENGTH);
+    data[..init_l].copy_from_slice(&packet[7..][..init_l]);
+    if read < 7 + init_l {
+        return Err(crate::Error::Protocol(format!(
+            "short CTAPHID packet: {}",
+            hex::encode(&packet[..read])
+        )));
+    }
 
-            assert_eq!(packet[4], u8::from(request.code) | (1 << 7));
-            break;
+    let mut received = init_l;
+    let mut sequence = 0u8;
+    while received < l {
+        let read = device.read_packet(&mut packet, timeout)?;
+        if read == 0 {
+            return Err(crate::Error::Timeout);
         }
+        if read < 5 {
+            return Err(crate::Error::Protocol(format!(
+                "short CTAPHID packet: {}",
+                hex::encode(&packet[..read])
+            )));
+        }
+        if packet[..4] != channel.0.to_be_bytes() {
+            trace!("skipping packet for other channel");
+            continue;
+        }
+        if packet[4] != sequence {
+            return Err(crate::Error::Protocol(format!(
+                "expected continuation packet {}, got {:#04X}",
+                sequence, packet[4]
+            )));
+        }
+        let chunk_l = core::cmp::min(l - received, CONT_DATA_LENGTH);
+        if read < 5 + chunk_l {
+            return Err(crate::Error::Protocol(format!(
+                "short CTAPHID packet: {}",
+                hex::encode(&packet[..read])
+            )));
+        }
+        data[received..][..chunk_l].copy_from_slice(&packet[5..][..chunk_l]);
+        received += chunk_l;
+        sequence += 1;
+    }
 
-        let l = u16::from_be_bytes(packet[5..][..2].try_into().unwrap());
-        let mut data = vec![0u8; l as _];
-        let init_l = core::cmp::min(l, 64 - 7) as usize;
-        data[..init_l].copy_from_slice(&packet[7..][..init_l]);
+    Ok(data)
+}
 
-        let result: Result<Vec<()>> = data[init_l..]
-            .chunks_mut(64 - 5)
-            .enumerate()
-            .map(|(i, chunk)| {
-                let read = self.device.read(&mut packet).unwrap();
-                assert!(read >= 5);
-                // dbg!(hex::encode(&packet[..read]));
-                assert_eq!(packet[..4], channel.0.to_be_bytes());
-                assert_eq!(packet[4], i as u8);
-                chunk.copy_from_slice(&packet[5..][..chunk.len()]); //64- 5]);
-                Ok(())
-            })
-            .collect();
-        result?;
-
-        // let cont_iter = data[init_l..].chunks(64 - 5).enumerate()
-        //     .map(move |(i, chunk)| {
-        //         let mut cont_packet = [0u8; 64];
-        //         cont_packet[..4].copy_from_slice(&channel.0.to_be_bytes());
-        //         cont_packet[4] = i as u8;
-        //         cont_packet[5..][..chunk.len()].copy_from_slice(chunk);
-        //         cont_packet
-        //     });
-        Ok(data)
+impl Device {
+    pub fn call(&self, channel: Channel, request: Command) -> Result<Vec<u8>> {
+        send(&*self.device, channel, &request)?;
+        let waiting = Waiting {
+            timeout: self.timeout,
+            keepalive: self.keepalive.as_deref(),
+            cancel: Some(&self.cancel),
+            deadline: self.cancel_after.map(|after| Instant::now() + after),
+        };
+        receive(&*self.device, channel, request.code, &waiting)
     }
 
     pub fn init(&self) -> Result<Init> {
-        let mut nonce = [0u8; 8];
-        getrandom::getrandom(&mut nonce).unwrap();
-        // dbg!(hex::encode(&nonce));
-        let command = Command::new(Code::Init).with_data(&nonce);
-        let response = self.call(Channel::BROADCAST, command)?;
-        // let mut packet = [0u8; 64];
-        // let read = self.device.read(&mut packet)?;
-        assert_eq!(response.len(), 17);
-        assert_eq!(response[..8], nonce);
-        let version = response[12];
-        assert_eq!(version, 2);
-        let capabilities = response[16];
-
-        Ok(Init {
-            channel: Channel(u32::from_be_bytes(response[8..][..4].try_into().unwrap())),
-            // version: response[12],
-            major: response[13],
-            minor: response[14],
-            build: response[15],
-            can_wink: (capabilities & 1) != 0,
-            can_cbor: (capabilities & 4) != 0,
-            can_msg: (capabilities & 8) == 0,
-        })
-        // // assert_eq!(nonce, response[..8])
-        // println!("response: {}", hex::encode(&response));//&packet[..read]));
-        // todo!();
+        init(&*self.device, self.timeout)
     }
 
     pub fn ping(&self, channel: Channel, data: &[u8]) -> Result<Vec<u8>> {
-        let command = Command::new(Code::Ping).with_data(data);
+        let command = Command::new(Code::Ping).with_data(data)?;
         let response = self.call(channel, command)?;
 
-        assert_eq!(data, response);
+        if data != response {
+            return Err(crate::Error::Protocol("ping response differs".to_string()));
+        }
         Ok(response)
     }
 
@@ -297,6 +563,74 @@ impl Device {
         let response = self.call(channel, command)?;
         Ok(response)
     }
+
+    /// Send a vendor command, as implemented by Trussed apps (e.g., the admin app).
+    pub fn vendor(&self, channel: Channel, code: VendorCode, data: &[u8]) -> Result<Vec<u8>> {
+        self.call(channel, Command::new(Code::Vendor(code)).with_data(data)?)
+    }
+}
+
+fn check_length(data: &[u8]) -> Result<()> {
+    if data.len() > MAX_MESSAGE_LENGTH {
+        return Err(crate::Error::InvalidInput(format!(
+            "{} bytes exceed the maximum CTAPHID message length {}",
+            data.len(),
+            MAX_MESSAGE_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+fn init(device: &dyn PacketIo, timeout: Duration) -> Result<Init> {
+    let mut nonce = [0u8; 8];
+    getrandom::getrandom(&mut nonce).map_err(|e| crate::Error::Rng(format!("nonce: {}", e)))?;
+    let command = Command::new(Code::Init).with_data(&nonce)?;
+    send(device, Channel::BROADCAST, &command)?;
+
+    let response = loop {
+        let response = receive(
+            device,
+            Channel::BROADCAST,
+            Code::Init,
+            &Waiting::new(timeout),
+        )?;
+        if response.len() < 17 {
+            return Err(crate::Error::Protocol(format!(
+                "INIT response too short: {}",
+                hex::encode(&response)
+            )));
+        }
+        // other clients may be initializing channels too
+        if response[..8] == nonce {
+            break response;
+        }
+        trace!("skipping INIT response for other nonce");
+    };
+
+    let version = response[12];
+    if version != 2 {
+        return Err(crate::Error::Protocol(format!(
+            "unsupported CTAPHID protocol version {}",
+            version
+        )));
+    }
+    let capabilities = response[16];
+
+    Ok(Init {
+        channel: Channel(u32::from_be_bytes([
+            response[8],
+            response[9],
+            response[10],
+            response[11],
+        ])),
+        // version: response[12],
+        major: response[13],
+        minor: response[14],
+        build: response[15],
+        can_wink: (capabilities & 1) != 0,
+        can_cbor: (capabilities & 4) != 0,
+        can_msg: (capabilities & 8) == 0,
+    })
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -310,3 +644,349 @@ pub struct Init {
     pub can_cbor: bool,
     pub can_msg: bool,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    const CHANNEL: Channel = Channel(0x1234_5678);
+    const TIMEOUT: Duration = Duration::from_millis(10);
+
+    /// Serves scripted packets, then times out.
+    #[derive(Default)]
+    struct FakeHid {
+        reads: RefCell<VecDeque<Vec<u8>>>,
+        writes: RefCell<Vec<[u8; 64]>>,
+    }
+
+    impl FakeHid {
+        fn new(reads: Vec<Vec<u8>>) -> Self {
+            Self {
+                reads: RefCell::new(reads.into()),
+                ..Default::default()
+            }
+        }
+    }
+
+    impl PacketIo for FakeHid {
+        fn write_packet(&self, packet: &[u8; 64]) -> Result<()> {
+            self.writes.borrow_mut().push(*packet);
+            Ok(())
+        }
+
+        fn read_packet(&self, packet: &mut [u8; 64], _: Duration) -> Result<usize> {
+            match self.reads.borrow_mut().pop_front() {
+                Some(read) => {
+                    packet[..read.len()].copy_from_slice(&read);
+                    Ok(read.len())
+                }
+                None => Ok(0),
+            }
+        }
+    }
+
+    fn response(channel: Channel, code: Code, data: &[u8]) -> Vec<Vec<u8>> {
+        Command::new(code)
+            .with_data(data)
+            .unwrap()
+            .packets(channel)
+            .map(|packet| packet.to_vec())
+            .collect()
+    }
+
+    fn init_packet(channel: Channel, command: u8, data: &[u8]) -> Vec<u8> {
+        let mut packet = channel.0.to_be_bytes().to_vec();
+        packet.push(command | (1 << 7));
+        packet.extend_from_slice(data);
+        packet.resize(64, 0);
+        packet
+    }
+
+    fn payload(l: usize) -> Vec<u8> {
+        (0..l).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let data = payload(200);
+        let hid = FakeHid::new(response(CHANNEL, Code::Ping, &data));
+        let command = Command::new(Code::Ping).with_data(&data).unwrap();
+        send(&hid, CHANNEL, &command).unwrap();
+        assert_eq!(hid.writes.borrow().len(), 4);
+        assert_eq!(
+            receive(&hid, CHANNEL, Code::Ping, &Waiting::new(TIMEOUT)).unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn maximum_length() {
+        let data = payload(MAX_MESSAGE_LENGTH);
+        let hid = FakeHid::new(response(CHANNEL, Code::Ping, &data));
+        assert_eq!(
+            receive(&hid, CHANNEL, Code::Ping, &Waiting::new(TIMEOUT)).unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn vendor_codes() {
+        let code = Code::Vendor(VendorCode::try_from(0x61).unwrap());
+        assert_eq!(Code::try_from(u8::from(code)).unwrap(), code);
+        assert!(Code::try_from(0xA1).is_err());
+        assert!(VendorCode::try_from(0x3F).is_err());
+        assert!(VendorCode::try_from(0x80).is_err());
+        assert!(check_length(&payload(MAX_MESSAGE_LENGTH)).is_ok());
+        assert!(check_length(&payload(MAX_MESSAGE_LENGTH + 1)).is_err());
+        assert!(Command::new(Code::Cbor)
+            .with_data(&payload(MAX_MESSAGE_LENGTH + 1))
+            .is_err());
+    }
+
+    #[test]
+    fn skips_other_channels_keepalives_and_stray_continuations() {
+        let data = payload(100);
+        let other = Channel(0xCAFE_CAFE);
+        let mut reads = vec![
+            response(other, Code::Ping, &data)[0].clone(),
+            // stray continuation packet on our channel
+            response(CHANNEL, Code::Ping, &data)[1].clone(),
+            init_packet(CHANNEL, Code::Keepalive.into(), &[0, 1, 2]),
+        ];
+        let mut ours = response(CHANNEL, Code::Ping, &data);
+        reads.push(ours.remove(0));
+        reads.push(response(other, Code::Ping, &data)[1].clone());
+        reads.append(&mut ours);
+
+        let hid = FakeHid::new(reads);
+        assert_eq!(
+            receive(&hid, CHANNEL, Code::Ping, &Waiting::new(TIMEOUT)).unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn short_read() {
+        let hid = FakeHid::new(vec![CHANNEL.0.to_be_bytes().to_vec()]);
+        assert!(matches!(
+            receive(&hid, CHANNEL, Code::Ping, &Waiting::new(TIMEOUT)),
+            Err(crate::Error::Protocol(_))
+        ));
+
+        let mut reads = response(CHANNEL, Code::Ping, &payload(20));
+        reads[0].truncate(10);
+        let hid = FakeHid::new(reads);
+        assert!(matches!(
+            receive(&hid, CHANNEL, Code::Ping, &Waiting::new(TIMEOUT)),
+            Err(crate::Error::Protocol(_))
+        ));
+
+        let mut reads = response(CHANNEL, Code::Ping, &payload(100));
+        reads[1].truncate(20);
+        let hid = FakeHid::new(reads);
+        assert!(matches!(
+            receive(&hid, CHANNEL, Code::Ping, &Waiting::new(TIMEOUT)),
+            Err(crate::Error::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn out_of_order_sequence() {
+        let mut reads = response(CHANNEL, Code::Ping, &payload(200));
+        reads.swap(1, 2);
+        let hid = FakeHid::new(reads);
+        assert!(matches!(
+            receive(&hid, CHANNEL, Code::Ping, &Waiting::new(TIMEOUT)),
+            Err(crate::Error::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn unexpected_command() {
+        let hid = FakeHid::new(response(CHANNEL, Code::Wink, &[]));
+        assert!(matches!(
+            receive(&hid, CHANNEL, Code::Ping, &Waiting::new(TIMEOUT)),
+            Err(crate::Error::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn oversized_length() {
+        let hid = FakeHid::new(vec![init_packet(CHANNEL, Code::Ping.into(), &[0xFF, 0xFF])]);
+        assert!(matches!(
+            receive(&hid, CHANNEL, Code::Ping, &Waiting::new(TIMEOUT)),
+            Err(crate::Error::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn ctaphid_error() {
+        let hid = FakeHid::new(vec![init_packet(
+            CHANNEL,
+            Code::Error.into(),
+            &[0, 1, Error::ChannelBusy as u8],
+        )]);
+        assert!(matches!(
+            receive(&hid, CHANNEL, Code::Ping, &Waiting::new(TIMEOUT)),
+            Err(crate::Error::Ctaphid(Error::ChannelBusy))
+        ));
+    }
+
+    #[test]
+    fn timeout() {
+        let hid = FakeHid::new(vec![]);
+        assert!(matches!(
+            receive(&hid, CHANNEL, Code::Ping, &Waiting::new(TIMEOUT)),
+            Err(crate::Error::Timeout)
+        ));
+
+        let mut reads = response(CHANNEL, Code::Ping, &payload(200));
+        reads.pop();
+        let hid = FakeHid::new(reads);
+        assert!(matches!(
+            receive(&hid, CHANNEL, Code::Ping, &Waiting::new(TIMEOUT)),
+            Err(crate::Error::Timeout)
+        ));
+    }
+
+    #[test]
+    fn keepalive_observer() {
+        let data = payload(10);
+        let mut reads = vec![
+            init_packet(CHANNEL, Code::Keepalive.into(), &[0, 1, 1]),
+            init_packet(CHANNEL, Code::Keepalive.into(), &[0, 1, 2]),
+        ];
+        reads.append(&mut response(CHANNEL, Code::Ping, &data));
+        let hid = FakeHid::new(reads);
+
+        let statuses = Mutex::new(Vec::new());
+        let observer = |status| statuses.lock().unwrap().push(status);
+        let waiting = Waiting {
+            keepalive: Some(&observer),
+            ..Waiting::new(TIMEOUT)
+        };
+        assert_eq!(receive(&hid, CHANNEL, Code::Ping, &waiting).unwrap(), data);
+        assert_eq!(
+            *statuses.lock().unwrap(),
+            [Status::Processing, Status::UserPresenceNeeded]
+        );
+    }
+
+    #[test]
+    fn cancel() {
+        let hid = FakeHid::new(vec![init_packet(
+            CHANNEL,
+            Code::Keepalive.into(),
+            &[0, 1, 2],
+        )]);
+        let cancel = CancelHandle::new();
+        cancel.cancel();
+        let waiting = Waiting {
+            cancel: Some(&cancel),
+            ..Waiting::new(TIMEOUT)
+        };
+        assert!(matches!(
+            receive(&hid, CHANNEL, Code::Ping, &waiting),
+            Err(crate::Error::Aborted)
+        ));
+        assert!(!cancel.is_cancelled());
+
+        let writes = hid.writes.borrow();
+        assert_eq!(writes.len(), 1);
+        assert_eq!(writes[0][..4], CHANNEL.0.to_be_bytes());
+        assert_eq!(writes[0][4], 0x11 | (1 << 7));
+    }
+
+    #[test]
+    fn cancel_drains_final_response() {
+        let keepalive = init_packet(CHANNEL, Code::Keepalive.into(), &[0, 1, 2]);
+        // CTAP2_ERR_KEEPALIVE_CANCEL, then the response to the next request
+        let mut reads = vec![
+            keepalive.clone(),
+            keepalive,
+            init_packet(CHANNEL, Code::Cbor.into(), &[0, 1, 0x2D]),
+        ];
+        reads.extend(response(CHANNEL, Code::Ping, &payload(100)));
+        let hid = FakeHid::new(reads);
+
+        let cancel = CancelHandle::new();
+        cancel.cancel();
+        let waiting = Waiting {
+            cancel: Some(&cancel),
+            ..Waiting::new(TIMEOUT)
+        };
+        assert!(matches!(
+            receive(&hid, CHANNEL, Code::Cbor, &waiting),
+            Err(crate::Error::Aborted)
+        ));
+        assert_eq!(
+            receive(&hid, CHANNEL, Code::Ping, &Waiting::new(TIMEOUT)).unwrap(),
+            payload(100)
+        );
+    }
+
+    #[test]
+    fn cancel_after_deadline() {
+        let hid = FakeHid::new(vec![init_packet(
+            CHANNEL,
+            Code::Keepalive.into(),
+            &[0, 1, 2],
+        )]);
+        let waiting = Waiting {
+            deadline: Some(Instant::now()),
+            ..Waiting::new(TIMEOUT)
+        };
+        assert!(matches!(
+            receive(&hid, CHANNEL, Code::Ping, &waiting),
+            Err(crate::Error::UserPresence)
+        ));
+        assert_eq!(hid.writes.borrow()[0][4], 0x11 | (1 << 7));
+    }
+
+    #[test]
+    fn record_and_replay_packets() {
+        // record an INIT with a fixed nonce, and a ping on the new channel
+        let nonce = payload(8);
+        let mut init_response = nonce.clone();
+        init_response.extend_from_slice(&CHANNEL.0.to_be_bytes());
+        init_response.extend_from_slice(&[2, 1, 2, 3, 0]);
+        let mut reads = response(Channel::BROADCAST, Code::Init, &init_response);
+        reads.extend(response(CHANNEL, Code::Ping, &payload(100)));
+
+        let mut transcript = Vec::new();
+        let recording = RecordingPacketIo::new(FakeHid::new(reads), &mut transcript);
+        let request = Command::new(Code::Init).with_data(&nonce).unwrap();
+        send(&recording, Channel::BROADCAST, &request).unwrap();
+        receive(
+            &recording,
+            Channel::BROADCAST,
+            Code::Init,
+            &Waiting::new(TIMEOUT),
+        )
+        .unwrap();
+        let ping = Command::new(Code::Ping).with_data(&payload(100)).unwrap();
+        send(&recording, CHANNEL, &ping).unwrap();
+        receive(&recording, CHANNEL, Code::Ping, &Waiting::new(TIMEOUT)).unwrap();
+        assert_eq!(recording.into_inner().writes.borrow().len(), 3);
+
+        // the replay answers INIT with whatever nonce is sent now
+        let replay = super::super::ReplayTransport::new(&transcript[..]).unwrap();
+        assert_eq!(init(&replay, TIMEOUT).unwrap().channel, CHANNEL);
+        send(&replay, CHANNEL, &ping).unwrap();
+        assert_eq!(
+            receive(&replay, CHANNEL, Code::Ping, &Waiting::new(TIMEOUT)).unwrap(),
+            payload(100)
+        );
+        assert!(replay.is_finished());
+
+        let replay = super::super::ReplayTransport::new(&transcript[..]).unwrap();
+        init(&replay, TIMEOUT).unwrap();
+        let other = Command::new(Code::Ping).with_data(&payload(99)).unwrap();
+        assert!(matches!(
+            send(&replay, CHANNEL, &other),
+            Err(crate::Error::Protocol(_))
+        ));
+    }
+}
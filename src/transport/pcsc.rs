@@ -1,9 +1,275 @@
-use anyhow::anyhow;
 use iso7816::Status;
 
-pub use crate::{device::pcsc::Device, Error, Result};
+use super::StatusWord;
+
+pub use crate::{
+    device::pcsc::{Device, Transaction},
+    Error, Result,
+};
+
+/// Commands with more data are sent in chunks (ISO 7816-4 command chaining),
+/// or as extended length APDU.
+const SHORT_MAX: usize = 255;
+/// CLA bit signaling that more chunks follow.
+const CHAINING: u8 = 0x10;
+/// SW1 for "more data available", SW2 is how much
+const SW1_MORE_AVAILABLE: u16 = 0x61;
+/// SW1 for "wrong Le", SW2 is the right one
+const SW1_WRONG_LE: u16 = 0x6C;
+const GET_RESPONSE: u8 = 0xC0;
+/// Bound on collecting responses with GET RESPONSE.
+const MAX_RESPONSE_LENGTH: usize = 1 << 20;
+
+/// Raw exchange of command and response APDUs.
+///
+/// Implemented by PCSC cards, and by mock cards in tests.
+pub trait Transmit {
+    fn transmit(&self, command: &[u8]) -> Result<Vec<u8>>;
+
+    /// The longest command APDU the reader accepts, if known.
+    fn max_command_length(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl Transmit for pcsc::Card {
+    fn transmit(&self, command: &[u8]) -> Result<Vec<u8>> {
+        let mut buffer = vec![0; pcsc::MAX_BUFFER_SIZE_EXTENDED];
+        let l = pcsc::Card::transmit(self, command, &mut buffer)?.len();
+        buffer.truncate(l);
+        Ok(buffer)
+    }
+
+    /// pcsc-lite reports this (for CCID readers) as 32-bit little-endian integer.
+    fn max_command_length(&self) -> Option<usize> {
+        let max = self.get_attribute_owned(pcsc::Attribute::Maxinput).ok()?;
+        Some(u32::from_le_bytes(max.get(..4)?.try_into().ok()?) as usize)
+    }
+}
+
+/// Short APDU, expecting a response (Le = 0, meaning up to 256 bytes).
+fn short_apdu(cla: u8, ins: u8, p1: u8, p2: u8, data: &[u8], expect_response: bool) -> Vec<u8> {
+    let mut apdu = vec![cla, ins, p1, p2];
+    if !data.is_empty() {
+        apdu.push(data.len() as u8);
+        apdu.extend_from_slice(data);
+    }
+    if expect_response {
+        apdu.push(0);
+    }
+    apdu
+}
+
+/// Extended length APDU, expecting a response (Le = 0, meaning up to 65536 bytes).
+fn extended_apdu(cla: u8, ins: u8, p1: u8, p2: u8, data: &[u8]) -> Vec<u8> {
+    let mut apdu = vec![cla, ins, p1, p2, 0];
+    apdu.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    apdu.extend_from_slice(data);
+    apdu.extend_from_slice(&[0, 0]);
+    apdu
+}
+
+/// Send one command APDU as is, returning response data and status word.
+fn transmit(card: &dyn Transmit, command: &[u8]) -> Result<(Vec<u8>, u16)> {
+    debug!(">> {}", hex::encode(command));
+    let mut response = card.transmit(command)?;
+    debug!("RECV {} bytes", response.len());
+    debug!("<< {}", hex::encode(&response));
+
+    if response.len() < 2 {
+        return Err(Error::Protocol(format!(
+            "response should end with two status bytes! received {}",
+            hex::encode(response)
+        )));
+    }
+    let sw2 = response.pop().unwrap();
+    let sw1 = response.pop().unwrap();
+    Ok((response, u16::from_be_bytes([sw1, sw2])))
+}
+
+/// The same command, with a different Le.
+///
+/// Our commands always carry Le, so it is the last byte (short APDUs),
+/// or the last two bytes (extended length APDUs, recognizable by their zero byte after P2).
+fn with_le(command: &[u8], le: u8) -> Vec<u8> {
+    let mut command = command.to_vec();
+    let extended = command.len() > 5 && command[4] == 0;
+    command.truncate(command.len() - if extended { 2 } else { 1 });
+    if extended {
+        command.push(0);
+    }
+    command.push(le);
+    command
+}
+
+/// Send one command APDU (carrying Le), returning the response data if the card signals success.
+///
+/// Cards may split responses (61xx: "xx more bytes available"), in which case we collect
+/// the rest via GET RESPONSE, or ask for the exact Le (6Cxx: "wrong Le, xx is right"),
+/// in which case we repeat the command with that Le.
+fn exchange(card: &dyn Transmit, command: &[u8]) -> Result<Vec<u8>> {
+    let (mut data, mut sw) = transmit(card, command)?;
+
+    if sw >> 8 == SW1_WRONG_LE {
+        debug!("card asks for Le = {}, repeating command", sw & 0xFF);
+        (data, sw) = transmit(card, &with_le(command, sw as u8))?;
+    }
+
+    while sw >> 8 == SW1_MORE_AVAILABLE {
+        if data.len() > MAX_RESPONSE_LENGTH {
+            return Err(Error::Protocol("response too long".to_string()));
+        }
+        let get_response = [command[0] & !CHAINING, GET_RESPONSE, 0, 0, sw as u8];
+        let (more, next_sw) = transmit(card, &get_response)?;
+        data.extend_from_slice(&more);
+        sw = next_sw;
+    }
+
+    check(data, sw)
+}
+
+/// Response data, if the card signals success.
+fn check(data: Vec<u8>, sw: u16) -> Result<Vec<u8>> {
+    let [sw1, sw2] = sw.to_be_bytes();
+    let status = (sw1, sw2).try_into();
+    if Ok(Status::Success) != status {
+        if !data.is_empty() {
+            debug!(
+                "card signaled error {:?} ({:X}, {:X}) with data {}",
+                status,
+                sw1,
+                sw2,
+                hex::encode(data)
+            );
+        }
+        return Err(Error::Status(sw.into()));
+    }
+
+    Ok(data)
+}
+
+/// Send a command, chaining it if there is more data than fits in a short APDU.
+///
+/// If the card does not support chaining, but the reader supports extended length APDUs,
+/// the command is sent as one extended length APDU instead.
+pub(crate) fn call(
+    card: &dyn Transmit,
+    cla: u8,
+    ins: u8,
+    p1: u8,
+    p2: u8,
+    data: &[u8],
+) -> Result<Vec<u8>> {
+    if data.len() <= SHORT_MAX {
+        return exchange(card, &short_apdu(cla, ins, p1, p2, data, true));
+    }
+    if data.len() > u16::MAX as usize {
+        return Err(Error::InvalidInput(format!(
+            "command data too long ({} bytes)",
+            data.len()
+        )));
+    }
+
+    let chunks: Vec<&[u8]> = data.chunks(SHORT_MAX).collect();
+    // INVARIANT: data is not empty, so there is a last chunk
+    let (last, leading) = chunks.split_last().unwrap();
+    for (i, chunk) in leading.iter().enumerate() {
+        let command = short_apdu(cla | CHAINING, ins, p1, p2, chunk, false);
+        let (response, sw) = transmit(card, &command)?;
+        match check(response, sw) {
+            Err(Error::Status(StatusWord::CHAINING_UNSUPPORTED)) if i == 0 => {
+                let extended = extended_apdu(cla, ins, p1, p2, data);
+                if card
+                    .max_command_length()
+                    .map_or(false, |max| max >= extended.len())
+                {
+                    info!("card does not support chaining, using extended length");
+                    return exchange(card, &extended);
+                }
+                return Err(Error::Status(StatusWord::CHAINING_UNSUPPORTED));
+            }
+            result => {
+                result?;
+            }
+        }
+    }
+    exchange(card, &short_apdu(cla, ins, p1, p2, last, true))
+}
+
+/// A command APDU, parsed from its short or extended length encoding.
+///
+/// Le is dropped, as [`Device::call`] always asks for the complete response.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Command {
+    pub class: u8,
+    pub instruction: u8,
+    pub p1: u8,
+    pub p2: u8,
+    pub data: Vec<u8>,
+}
+
+impl TryFrom<&[u8]> for Command {
+    type Error = Error;
+    fn try_from(apdu: &[u8]) -> Result<Self> {
+        let invalid = || Error::InvalidInput(format!("invalid command APDU {}", hex::encode(apdu)));
+        let (header, body) = match apdu {
+            [class, instruction, p1, p2, body @ ..] => ([*class, *instruction, *p1, *p2], body),
+            _ => return Err(invalid()),
+        };
+        let data = match body {
+            // no data, maybe Le (short or extended)
+            [] | [_] | [0, _, _] => &[][..],
+            [0, hi, lo, rest @ ..] => {
+                let lc = u16::from_be_bytes([*hi, *lo]) as usize;
+                match rest.len().checked_sub(lc) {
+                    Some(0) | Some(2) => &rest[..lc],
+                    _ => return Err(invalid()),
+                }
+            }
+            [lc, rest @ ..] => {
+                let lc = *lc as usize;
+                match rest.len().checked_sub(lc) {
+                    Some(0) | Some(1) => &rest[..lc],
+                    _ => return Err(invalid()),
+                }
+            }
+        };
+        let [class, instruction, p1, p2] = header;
+        Ok(Self {
+            class,
+            instruction,
+            p1,
+            p2,
+            data: data.to_vec(),
+        })
+    }
+}
 
 impl Device {
+    /// Send a parsed command APDU.
+    pub fn call_command(&mut self, command: &Command) -> Result<Vec<u8>> {
+        self.call(
+            command.class,
+            command.instruction,
+            command.p1,
+            command.p2,
+            Some(&command.data),
+        )
+    }
+
+    pub fn call(
+        &mut self,
+        cla: u8,
+        ins: u8,
+        p1: u8,
+        p2: u8,
+        data: Option<&[u8]>,
+    ) -> Result<Vec<u8>> {
+        call(&self.device, cla, ins, p1, p2, data.unwrap_or(&[]))
+    }
+}
+
+impl Transaction<'_> {
     pub fn call(
         &mut self,
         cla: u8,
@@ -12,64 +278,227 @@ impl Device {
         p2: u8,
         data: Option<&[u8]>,
     ) -> Result<Vec<u8>> {
-        let data = data.unwrap_or(&[]);
-        let mut send_buffer = Vec::<u8>::with_capacity(data.len() + 16);
-
-        send_buffer.push(cla);
-        send_buffer.push(ins);
-        send_buffer.push(p1);
-        send_buffer.push(p2);
-
-        // TODO: checks, chain, ...
-        let l = data.len();
-        if l > 0 {
-            if l <= 255 {
-                send_buffer.push(l as u8);
-            } else {
-                send_buffer.push(0);
-                send_buffer.extend_from_slice(&(l as u16).to_be_bytes());
+        call(&*self.device, cla, ins, p1, p2, data.unwrap_or(&[]))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hex_literal::hex;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    #[test]
+    fn parse_commands() {
+        let command = |apdu: &str| Command::try_from(hex::decode(apdu).unwrap().as_slice());
+        let select = command("00A4040005A000000847").unwrap();
+        assert_eq!(select.instruction, 0xA4);
+        assert_eq!((select.p1, select.p2), (0x04, 0x00));
+        assert_eq!(select.data, hex!("A000000847"));
+        assert_eq!(command("00A4040005A00000084700").unwrap(), select);
+        assert_eq!(command("00A40400000005A000000847").unwrap(), select);
+        assert_eq!(command("00A40400000005A0000008470000").unwrap(), select);
+
+        assert!(command("00A40400").unwrap().data.is_empty());
+        assert!(command("00A4040000").unwrap().data.is_empty());
+        assert!(command("00A4040000FFFF").unwrap().data.is_empty());
+
+        assert!(command("00A404").is_err());
+        assert!(command("00A4040005A0000008").is_err());
+        assert!(command("00A4040005A000000847000000").is_err());
+    }
+
+    /// Answers commands with scripted responses, recording the commands.
+    #[derive(Default)]
+    struct MockCard {
+        max_command_length: Option<usize>,
+        responses: RefCell<VecDeque<Vec<u8>>>,
+        commands: RefCell<Vec<Vec<u8>>>,
+    }
+
+    impl MockCard {
+        fn answering(responses: &[&[u8]]) -> Self {
+            Self {
+                responses: RefCell::new(responses.iter().map(|r| r.to_vec()).collect()),
+                ..Default::default()
             }
-            send_buffer.extend_from_slice(data);
         }
+    }
 
-        send_buffer.push(0);
-        if l > 255 {
-            send_buffer.push(0);
+    impl Transmit for MockCard {
+        fn transmit(&self, command: &[u8]) -> Result<Vec<u8>> {
+            self.commands.borrow_mut().push(command.to_vec());
+            self.responses
+                .borrow_mut()
+                .pop_front()
+                .ok_or_else(|| Error::Protocol("mock card has no more responses".to_string()))
         }
 
-        debug!(">> {}", hex::encode(&send_buffer));
+        fn max_command_length(&self) -> Option<usize> {
+            self.max_command_length
+        }
+    }
 
-        let mut recv_buffer = vec![0; 3072];
+    #[test]
+    fn short() {
+        let card = MockCard::answering(&[&hex!("0102 9000"), &hex!("6A82")]);
+        assert_eq!(
+            call(&card, 0, 0xA4, 4, 0, &hex!("A000000527")).unwrap(),
+            hex!("0102")
+        );
+        assert!(matches!(
+            call(&card, 0, 0xA1, 0, 0, &[]),
+            Err(Error::Status(StatusWord(0x6A82)))
+        ));
+        assert_eq!(
+            *card.commands.borrow(),
+            [
+                hex!("00A40400 05 A000000527 00").to_vec(),
+                hex!("00A10000 00").to_vec()
+            ]
+        );
+    }
 
-        let l = self.device.transmit(&send_buffer, &mut recv_buffer)?.len();
-        debug!("RECV {} bytes", l);
-        recv_buffer.resize(l, 0);
-        debug!("<< {}", hex::encode(&recv_buffer));
+    #[test]
+    fn chained() {
+        let card = MockCard::answering(&[&hex!("9000"), &hex!("9000"), &hex!("AA 9000")]);
+        let data: Vec<u8> = (0..600).map(|i| i as u8).collect();
+        assert_eq!(call(&card, 0, 0xBF, 0, 0, &data).unwrap(), hex!("AA"));
 
-        if l < 2 {
-            return Err(anyhow!(
-                "response should end with two status bytes! received {}",
-                hex::encode(recv_buffer)
-            ));
-        }
-        let sw2 = recv_buffer.pop().unwrap();
-        let sw1 = recv_buffer.pop().unwrap();
-
-        let status = (sw1, sw2).try_into();
-        if Ok(Status::Success) != status {
-            return Err(if !recv_buffer.is_empty() {
-                anyhow!(
-                    "card signaled error {:?} ({:X}, {:X}) with data {}",
-                    status,
-                    sw1,
-                    sw2,
-                    hex::encode(recv_buffer)
-                )
-            } else {
-                anyhow!("card signaled error: {:?} ({:X}, {:X})", status, sw1, sw2)
-            });
-        }
+        let commands = card.commands.borrow();
+        assert_eq!(commands.len(), 3);
+        assert_eq!(commands[0][..5], hex!("10BF0000 FF"));
+        assert_eq!(commands[0].len(), 5 + 255);
+        assert_eq!(commands[1][..5], hex!("10BF0000 FF"));
+        assert_eq!(commands[1][5..], data[255..510]);
+        assert_eq!(commands[2][..5], hex!("00BF0000 5A"));
+        assert_eq!(commands[2][5..95], data[510..]);
+        assert_eq!(commands[2][95..], [0]);
+    }
+
+    #[test]
+    fn chaining_error_midway() {
+        let card = MockCard::answering(&[&hex!("9000"), &hex!("6A84")]);
+        assert!(matches!(
+            call(&card, 0, 0xBF, 0, 0, &[0; 1000]),
+            Err(Error::Status(StatusWord(0x6A84)))
+        ));
+        assert_eq!(card.commands.borrow().len(), 2);
+    }
+
+    #[test]
+    fn extended_fallback() {
+        let data = [0x55; 300];
+
+        let mut card = MockCard::answering(&[&hex!("6884"), &hex!("9000")]);
+        card.max_command_length = Some(1024);
+        call(&card, 0, 0xBF, 0, 0, &data).unwrap();
+        let commands = card.commands.borrow();
+        assert_eq!(commands[1][..7], hex!("00BF0000 00012C"));
+        assert_eq!(commands[1][7..307], data);
+        assert_eq!(commands[1][307..], [0, 0]);
+
+        // reader can't take it either
+        let mut card = MockCard::answering(&[&hex!("6884")]);
+        card.max_command_length = Some(261);
+        assert!(matches!(
+            call(&card, 0, 0xBF, 0, 0, &data),
+            Err(Error::Status(StatusWord(0x6884)))
+        ));
+    }
+
+    #[test]
+    fn large_response() {
+        let mut response = vec![0x42; 8192];
+        response.extend_from_slice(&hex!("9000"));
+        let card = MockCard::answering(&[&response]);
+        assert_eq!(call(&card, 0, 0xB0, 0, 0, &[]).unwrap().len(), 8192);
+    }
+
+    #[test]
+    fn no_status() {
+        let card = MockCard::answering(&[&hex!("90")]);
+        assert!(matches!(
+            call(&card, 0, 0xB0, 0, 0, &[]),
+            Err(Error::Protocol(_))
+        ));
+    }
+
+    /// Recorded exchanges, as hex strings of the command and response APDUs.
+    fn replay(
+        transcript: &[(&str, &str)],
+        calling: impl FnOnce(&MockCard) -> Result<Vec<u8>>,
+    ) -> Result<Vec<u8>> {
+        let card = MockCard {
+            responses: RefCell::new(
+                transcript
+                    .iter()
+                    .map(|(_, response)| hex::decode(response).unwrap())
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+        let result = calling(&card);
+        let expected: Vec<Vec<u8>> = transcript
+            .iter()
+            .map(|(command, _)| hex::decode(command).unwrap())
+            .collect();
+        assert_eq!(*card.commands.borrow(), expected);
+        result
+    }
+
+    #[test]
+    fn get_response() {
+        // OATH LIST with many credentials
+        let first = "71".repeat(256);
+        let second = "72".repeat(0x20);
+        let transcript = [
+            ("00a1000000", first.clone() + "6120"),
+            ("00c0000020", second.clone() + "6100"),
+            ("00c0000000", "7373737373".to_string() + "9000"),
+        ];
+        let transcript: Vec<(&str, &str)> =
+            transcript.iter().map(|(c, r)| (*c, r.as_str())).collect();
+        let response = replay(&transcript, |card| call(card, 0, 0xA1, 0, 0, &[])).unwrap();
+        assert_eq!(response.len(), 256 + 0x20 + 5);
+        assert_eq!(hex::encode(&response), first + &second + "7373737373");
+    }
+
+    #[test]
+    fn get_response_error() {
+        let result = replay(
+            &[("00a1000000", "aaaa6110"), ("00c0000010", "6f00")],
+            |card| call(card, 0, 0xA1, 0, 0, &[]),
+        );
+        assert!(matches!(result, Err(Error::Status(StatusWord(0x6F00)))));
+    }
+
+    #[test]
+    fn wrong_le() {
+        // PIV GET DATA
+        let response = replay(
+            &[
+                ("00cb3fff055c035fc10500", "6c04"),
+                ("00cb3fff055c035fc10504", "010203046102"),
+                ("00c0000002", "05069000"),
+            ],
+            |card| call(card, 0, 0xCB, 0x3F, 0xFF, &hex!("5c035fc105")),
+        )
+        .unwrap();
+        assert_eq!(response, hex!("010203040506"));
+    }
 
-        Ok(recv_buffer)
+    #[test]
+    fn le_replacement() {
+        assert_eq!(with_le(&hex!("00CB3FFF 00"), 4), hex!("00CB3FFF 04"));
+        assert_eq!(
+            with_le(&hex!("00CB3FFF 01 AA 00"), 4),
+            hex!("00CB3FFF 01 AA 04")
+        );
+        assert_eq!(
+            with_le(&hex!("00CB3FFF 000001 AA 0000"), 4),
+            hex!("00CB3FFF 000001 AA 0004")
+        );
     }
 }
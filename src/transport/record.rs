@@ -0,0 +1,593 @@
+//! Recording transport traffic as JSON lines transcripts, and replaying them.
+//!
+//! Each line is one exchange, for instance
+//! ```json
+//! {"time":1665000000000,"kind":"call_iso","class":0,"instruction":161,"p1":0,"p2":0,"data":"","response":"7209..."}
+//! ```
+//! with `time` in milliseconds since the UNIX epoch, `kind` one of `call`, `call_iso`
+//! and `select` (which has an `aid` instead of the ISO 7816 parameters and data),
+//! and exactly one of `response` (hex), `status` (hex status word) or `error` (message).
+//!
+//! Secrets (OATH keys and password exchanges, PIV PINs, files written during provisioning) are zeroed
+//! unless redaction is turned off; such exchanges are marked with `"redacted":true`.
+//!
+//! Below transports, CTAPHID traffic is recorded packet by packet
+//! (cf. [`RecordingPacketIo`][super::ctap::RecordingPacketIo]), for instance
+//! ```json
+//! {"time":1665000000000,"kind":"ctaphid_write","packet":"ffffffff860008..."}
+//! {"time":1665000000002,"kind":"ctaphid_read","packet":"ffffffff8600116..."}
+//! ```
+//! with an `error` (message) if the packet could not be written or read, and an empty
+//! `packet` for reads that timed out. Packets are not redacted, the PIN protocol encrypts
+//! PINs anyway.
+
+use std::cell::RefCell;
+use std::io::{BufRead, Write};
+use std::time::{Duration, SystemTime};
+
+use serde_json::{json, Value};
+
+use super::{ctap::PacketIo, StatusWord, Transport};
+use crate::{
+    apps::{Pix, Rid},
+    Error, Result,
+};
+
+/// One request, as seen by a [`Transport`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Request {
+    Call {
+        instruction: u8,
+        data: Vec<u8>,
+    },
+    CallIso {
+        class: u8,
+        instruction: u8,
+        p1: u8,
+        p2: u8,
+        data: Vec<u8>,
+    },
+    Select {
+        aid: Vec<u8>,
+    },
+}
+
+impl Request {
+    fn to_json(&self) -> Value {
+        match self {
+            Self::Call { instruction, data } => json!({
+                "kind": "call",
+                "instruction": instruction,
+                "data": hex::encode(data),
+            }),
+            Self::CallIso {
+                class,
+                instruction,
+                p1,
+                p2,
+                data,
+            } => json!({
+                "kind": "call_iso",
+                "class": class,
+                "instruction": instruction,
+                "p1": p1,
+                "p2": p2,
+                "data": hex::encode(data),
+            }),
+            Self::Select { aid } => json!({
+                "kind": "select",
+                "aid": hex::encode(aid),
+            }),
+        }
+    }
+
+    fn from_json(entry: &Value) -> Result<Self> {
+        let invalid = || Error::InvalidInput(format!("invalid transcript entry {}", entry));
+        let byte = |key: &str| {
+            entry[key]
+                .as_u64()
+                .and_then(|byte| u8::try_from(byte).ok())
+                .ok_or_else(invalid)
+        };
+        let bytes = |key: &str| {
+            entry[key]
+                .as_str()
+                .and_then(|bytes| hex::decode(bytes).ok())
+                .ok_or_else(invalid)
+        };
+        Ok(match entry["kind"].as_str() {
+            Some("call") => Self::Call {
+                instruction: byte("instruction")?,
+                data: bytes("data")?,
+            },
+            Some("call_iso") => Self::CallIso {
+                class: byte("class")?,
+                instruction: byte("instruction")?,
+                p1: byte("p1")?,
+                p2: byte("p2")?,
+                data: bytes("data")?,
+            },
+            Some("select") => Self::Select { aid: bytes("aid")? },
+            _ => return Err(invalid()),
+        })
+    }
+
+    fn data_mut(&mut self) -> Option<&mut Vec<u8>> {
+        match self {
+            Self::Call { data, .. } | Self::CallIso { data, .. } => Some(data),
+            Self::Select { .. } => None,
+        }
+    }
+
+    fn instruction(&self) -> Option<u8> {
+        match self {
+            Self::Call { instruction, .. } | Self::CallIso { instruction, .. } => {
+                Some(*instruction)
+            }
+            Self::Select { .. } => None,
+        }
+    }
+}
+
+/// Kind of the transcript entries of CTAPHID packets.
+pub(crate) const CTAPHID_WRITE: &str = "ctaphid_write";
+pub(crate) const CTAPHID_READ: &str = "ctaphid_read";
+
+fn is_packet(entry: &Value) -> bool {
+    matches!(entry["kind"].as_str(), Some(CTAPHID_WRITE | CTAPHID_READ))
+}
+
+/// The transcript entry of a CTAPHID packet, with the error if it could not be transferred.
+pub(crate) fn packet_entry(kind: &str, packet: &[u8], result: &Result<()>) -> Value {
+    let mut entry = json!({
+        "kind": kind,
+        "packet": hex::encode(packet),
+    });
+    if let Err(error) = result {
+        entry["error"] = error.to_string().into();
+    }
+    entry
+}
+
+/// Milliseconds since the UNIX epoch.
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Write one timestamped entry (one line).
+pub(crate) fn write_entry(writer: &mut dyn Write, mut entry: Value, time: u64) -> Result<()> {
+    entry["time"] = time.into();
+    writeln!(writer, "{}", entry)?;
+    Ok(writer.flush()?)
+}
+
+fn application(rid: &[u8], pix: &[u8]) -> Vec<u8> {
+    [rid, pix].concat()
+}
+
+/// Zeroes secrets in requests, keeping track of the selected application.
+#[derive(Clone, Debug, Default)]
+struct Redactor {
+    selected: Vec<u8>,
+}
+
+impl Redactor {
+    const OATH_PUT: u8 = 0x01;
+    const OATH_KEY: u8 = 0x73;
+    const OATH_PROPERTY: u8 = 0x78;
+    const OATH_SET_CODE: u8 = 0x03;
+    const OATH_VALIDATE: u8 = 0xA3;
+    const PIV_VERIFY: u8 = 0x20;
+    const WRITE_BINARY: u8 = 0xD0;
+
+    /// Observe the request, returning a redacted version if it contains secrets.
+    fn redact(&mut self, request: &Request) -> Option<Request> {
+        if let Request::Select { aid } = request {
+            self.selected = aid.clone();
+            return None;
+        }
+        let selected = |rid, pix| self.selected.starts_with(&application(rid, pix));
+        let instruction = request.instruction()?;
+        let mut redacted = request.clone();
+        // INVARIANT: requests with instruction have data
+        let data = redacted.data_mut().unwrap();
+        if selected(Rid::YUBICO, Pix::OATH) && instruction == Self::OATH_PUT {
+            match super::tlvs_and_tvs(data, &[Self::OATH_PROPERTY]) {
+                Ok(tlvs) => {
+                    for (_, range) in tlvs.into_iter().filter(|(tag, _)| *tag == Self::OATH_KEY) {
+                        // keep kind, algorithm and digits
+                        data[range].iter_mut().skip(2).for_each(|byte| *byte = 0);
+                    }
+                }
+                Err(_) => data.iter_mut().for_each(|byte| *byte = 0),
+            }
+        } else if (selected(Rid::YUBICO, Pix::OATH)
+            && [Self::OATH_SET_CODE, Self::OATH_VALIDATE].contains(&instruction))
+            || (selected(Rid::NIST, Pix::PIV) && instruction == Self::PIV_VERIFY)
+            || (selected(Rid::SOLOKEYS, Pix::PROVISION) && instruction == Self::WRITE_BINARY)
+        {
+            data.iter_mut().for_each(|byte| *byte = 0);
+        }
+        (redacted != *request).then(|| redacted)
+    }
+}
+
+fn response_json(response: &Result<Vec<u8>>) -> (&'static str, Value) {
+    match response {
+        Ok(data) => ("response", hex::encode(data).into()),
+        Err(Error::Status(sw)) => ("status", format!("{:04X}", sw.0).into()),
+        Err(error) => ("error", error.to_string().into()),
+    }
+}
+
+/// Wraps a [`Transport`], writing each exchange to a JSON lines transcript.
+pub struct RecordingTransport<T, W> {
+    inner: T,
+    writer: W,
+    redactor: Redactor,
+    redact: bool,
+}
+
+impl<T: Transport, W: Write> RecordingTransport<T, W> {
+    /// Record with redaction of secrets.
+    pub fn new(inner: T, writer: W) -> Self {
+        Self {
+            inner,
+            writer,
+            redactor: Redactor::default(),
+            redact: true,
+        }
+    }
+
+    /// Whether to zero secrets in the transcript (the default).
+    pub fn redact(mut self, redact: bool) -> Self {
+        self.redact = redact;
+        self
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn record(
+        &mut self,
+        request: Request,
+        exchange: impl FnOnce(&mut T) -> Result<Vec<u8>>,
+    ) -> Result<Vec<u8>> {
+        let redacted = self.redactor.redact(&request);
+        let time = now();
+        let response = exchange(&mut self.inner);
+
+        let mut entry = match redacted.filter(|_| self.redact) {
+            Some(redacted) => {
+                let mut entry = redacted.to_json();
+                entry["redacted"] = true.into();
+                entry
+            }
+            None => request.to_json(),
+        };
+        let (key, value) = response_json(&response);
+        entry[key] = value;
+        write_entry(&mut self.writer, entry, time)?;
+
+        response
+    }
+}
+
+impl<T: Transport, W: Write> Transport for RecordingTransport<T, W> {
+    fn call(&mut self, instruction: u8, data: &[u8]) -> Result<Vec<u8>> {
+        let request = Request::Call {
+            instruction,
+            data: data.to_vec(),
+        };
+        self.record(request, |inner| inner.call(instruction, data))
+    }
+
+    fn call_iso(
+        &mut self,
+        class: u8,
+        instruction: u8,
+        p1: u8,
+        p2: u8,
+        data: &[u8],
+    ) -> Result<Vec<u8>> {
+        let request = Request::CallIso {
+            class,
+            instruction,
+            p1,
+            p2,
+            data: data.to_vec(),
+        };
+        self.record(request, |inner| {
+            inner.call_iso(class, instruction, p1, p2, data)
+        })
+    }
+
+    fn select(&mut self, aid: Vec<u8>) -> Result<Vec<u8>> {
+        let request = Request::Select { aid: aid.clone() };
+        self.record(request, |inner| inner.select(aid))
+    }
+
+    fn transaction(
+        &mut self,
+        calls: &mut dyn FnMut(&mut dyn Transport) -> Result<()>,
+    ) -> Result<()> {
+        let Self {
+            inner,
+            writer,
+            redactor,
+            redact,
+        } = self;
+        // a fixed writer type, avoiding infinite recursion of the generic
+        let writer: &mut dyn Write = writer;
+        inner.transaction(&mut |inner| {
+            let mut recording = RecordingTransport {
+                inner,
+                writer: &mut *writer,
+                redactor: redactor.clone(),
+                redact: *redact,
+            };
+            let result = calls(&mut recording);
+            *redactor = recording.redactor;
+            result
+        })
+    }
+}
+
+/// Serves a transcript written by [`RecordingTransport`], in order.
+///
+/// Requests must match the recorded ones (redacted ones are matched after redaction),
+/// otherwise the replay fails with [`Error::Protocol`].
+///
+/// CTAPHID packets (cf. [`RecordingPacketIo`][super::ctap::RecordingPacketIo]) are served
+/// as [`PacketIo`]. Written packets must match the recorded ones, except for the random
+/// nonce of CTAPHID_INIT, which is substituted in the INIT response read back.
+#[derive(Clone, Debug)]
+pub struct ReplayTransport {
+    // `PacketIo` takes `&self`
+    entries: RefCell<std::vec::IntoIter<Value>>,
+    redactor: Redactor,
+    /// recorded and actual nonce of the last CTAPHID_INIT
+    nonce: RefCell<Option<([u8; 8], [u8; 8])>>,
+}
+
+impl ReplayTransport {
+    pub fn new(transcript: impl BufRead) -> Result<Self> {
+        let mut entries = Vec::new();
+        for line in transcript.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: Value = serde_json::from_str(&line)
+                .map_err(|error| Error::InvalidInput(format!("invalid transcript: {}", error)))?;
+            // validate early
+            if is_packet(&entry) {
+                recorded_packet(&entry)?;
+            } else {
+                Request::from_json(&entry)?;
+            }
+            entries.push(entry);
+        }
+        Ok(Self {
+            entries: RefCell::new(entries.into_iter()),
+            redactor: Redactor::default(),
+            nonce: RefCell::new(None),
+        })
+    }
+
+    pub fn open(path: impl AsRef<std::path::Path>) -> Result<Self> {
+        let file = std::fs::File::open(path.as_ref()).map_err(|error| {
+            std::io::Error::new(
+                error.kind(),
+                format!(
+                    "cannot open transcript {}: {}",
+                    path.as_ref().display(),
+                    error
+                ),
+            )
+        })?;
+        Self::new(std::io::BufReader::new(file))
+    }
+
+    /// Whether all recorded exchanges were replayed.
+    pub fn is_finished(&self) -> bool {
+        self.entries.borrow().as_slice().is_empty()
+    }
+
+    fn replay(&mut self, request: Request) -> Result<Vec<u8>> {
+        let redacted = self.redactor.redact(&request);
+        let entry = self.entries.get_mut().next().ok_or_else(|| {
+            Error::Protocol(format!("transcript exhausted at {}", request.to_json()))
+        })?;
+        if is_packet(&entry) {
+            return Err(Error::Protocol(format!(
+                "replay diverged: expected {}, got {}",
+                entry,
+                request.to_json()
+            )));
+        }
+        // INVARIANT: validated in `new`
+        let recorded = Request::from_json(&entry).unwrap();
+        if recorded != request && Some(&recorded) != redacted.as_ref() {
+            return Err(Error::Protocol(format!(
+                "replay diverged: expected {}, got {}",
+                recorded.to_json(),
+                request.to_json()
+            )));
+        }
+
+        let invalid = || Error::InvalidInput(format!("transcript entry without outcome {}", entry));
+        if let Some(response) = entry["response"].as_str() {
+            hex::decode(response).map_err(|_| invalid())
+        } else if let Some(status) = entry["status"].as_str() {
+            let status = u16::from_str_radix(status, 16).map_err(|_| invalid())?;
+            Err(Error::Status(StatusWord(status)))
+        } else if let Some(error) = entry["error"].as_str() {
+            Err(Error::Protocol(format!("(replayed) {}", error)))
+        } else {
+            Err(invalid())
+        }
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn call(&mut self, instruction: u8, data: &[u8]) -> Result<Vec<u8>> {
+        self.replay(Request::Call {
+            instruction,
+            data: data.to_vec(),
+        })
+    }
+
+    fn call_iso(
+        &mut self,
+        class: u8,
+        instruction: u8,
+        p1: u8,
+        p2: u8,
+        data: &[u8],
+    ) -> Result<Vec<u8>> {
+        self.replay(Request::CallIso {
+            class,
+            instruction,
+            p1,
+            p2,
+            data: data.to_vec(),
+        })
+    }
+
+    fn select(&mut self, aid: Vec<u8>) -> Result<Vec<u8>> {
+        self.replay(Request::Select { aid })
+    }
+
+    fn transaction(
+        &mut self,
+        calls: &mut dyn FnMut(&mut dyn Transport) -> Result<()>,
+    ) -> Result<()> {
+        calls(self)
+    }
+}
+
+/// The packet of a packet entry.
+fn recorded_packet(entry: &Value) -> Result<Vec<u8>> {
+    entry["packet"]
+        .as_str()
+        .and_then(|packet| hex::decode(packet).ok())
+        .filter(|packet| packet.len() <= 64)
+        .ok_or_else(|| Error::InvalidInput(format!("invalid transcript entry {}", entry)))
+}
+
+/// The nonce, if the packet is a CTAPHID_INIT (request or response).
+fn init_nonce(packet: &[u8]) -> Option<[u8; 8]> {
+    const INIT: u8 = 0x86;
+    match packet {
+        [_, _, _, _, INIT, _, _, nonce @ ..] => nonce.get(..8)?.try_into().ok(),
+        _ => None,
+    }
+}
+
+impl ReplayTransport {
+    /// The next entry, which must be a packet entry of the given kind.
+    fn next_packet(&self, kind: &str, actual: Option<&[u8]>) -> Result<Vec<u8>> {
+        let got = || {
+            actual.map_or_else(
+                || kind.to_string(),
+                |packet| packet_entry(kind, packet, &Ok(())).to_string(),
+            )
+        };
+        let entry = self
+            .entries
+            .borrow_mut()
+            .next()
+            .ok_or_else(|| Error::Protocol(format!("transcript exhausted at {}", got())))?;
+        if entry["kind"].as_str() != Some(kind) {
+            return Err(Error::Protocol(format!(
+                "replay diverged: expected {}, got {}",
+                entry,
+                got()
+            )));
+        }
+        if let Some(error) = entry["error"].as_str() {
+            return Err(Error::Protocol(format!("(replayed) {}", error)));
+        }
+        // INVARIANT: validated in `new`
+        Ok(recorded_packet(&entry).unwrap())
+    }
+}
+
+impl PacketIo for ReplayTransport {
+    fn write_packet(&self, packet: &[u8; 64]) -> Result<()> {
+        let recorded = self.next_packet(CTAPHID_WRITE, Some(packet))?;
+        if recorded[..] == packet[..] {
+            return Ok(());
+        }
+        match (init_nonce(&recorded), init_nonce(packet)) {
+            (Some(recorded_nonce), Some(nonce))
+                if recorded[..7] == packet[..7] && recorded[15..] == packet[15..] =>
+            {
+                *self.nonce.borrow_mut() = Some((recorded_nonce, nonce));
+                Ok(())
+            }
+            _ => Err(Error::Protocol(format!(
+                "replay diverged: expected {}, got {}",
+                hex::encode(recorded),
+                hex::encode(packet)
+            ))),
+        }
+    }
+
+    fn read_packet(&self, packet: &mut [u8; 64], _: Duration) -> Result<usize> {
+        let mut recorded = self.next_packet(CTAPHID_READ, None)?;
+        if let (Some(nonce), Some((recorded_nonce, actual))) =
+            (init_nonce(&recorded), *self.nonce.borrow())
+        {
+            if nonce == recorded_nonce {
+                recorded[7..15].copy_from_slice(&actual);
+            }
+        }
+        packet[..recorded.len()].copy_from_slice(&recorded);
+        Ok(recorded.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::apps::{oath, Oath};
+    use crate::{transport::sim::Simulator, Select as _};
+
+    #[test]
+    fn record_and_replay() {
+        let mut transcript = Vec::new();
+        let mut recording = RecordingTransport::new(Simulator::default(), &mut transcript);
+        let mut app = Oath::select(&mut recording).unwrap();
+        let credential = oath::Credential::default_totp("alice", "JBSWY3DPEHPK3PXP").unwrap();
+        app.register(credential.clone()).unwrap();
+        assert_eq!(app.list().unwrap(), ["alice"]);
+        assert!(app.delete("bob".to_string()).is_err());
+        drop(recording);
+
+        let transcript = String::from_utf8(transcript).unwrap();
+        assert_eq!(transcript.lines().count(), 4);
+        assert!(transcript.contains(r#""redacted":true"#));
+        assert!(!transcript.contains(&hex::encode(&credential.key()[2..])));
+        assert!(transcript.contains(r#""status":"6A82""#));
+
+        let mut replay = ReplayTransport::new(transcript.as_bytes()).unwrap();
+        let mut app = Oath::select(&mut replay).unwrap();
+        app.register(credential).unwrap();
+        assert_eq!(app.list().unwrap(), ["alice"]);
+        assert!(matches!(
+            app.delete("bob".to_string()),
+            Err(Error::NotFound(_))
+        ));
+        assert!(replay.is_finished());
+
+        let mut replay = ReplayTransport::new(transcript.as_bytes()).unwrap();
+        let mut app = Oath::select(&mut replay).unwrap();
+        assert!(matches!(app.list(), Err(Error::Protocol(_))));
+    }
+}
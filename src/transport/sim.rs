@@ -0,0 +1,462 @@
+//! In-process simulation of a Solo 2, for testing without hardware.
+//!
+//! The [`Simulator`] behaves like a Solo 2 connected via PCSC, as far as the
+//! apps in this crate are concerned:
+//! - admin: UUID, version, locked status, wink
+//! - OATH: credential store with actual HOTP/TOTP calculation, password protection
+//! - NDEF: capability container and data files
+//! - provision: file writes
+//!
+//! Errors are signaled as [`Error::Status`], like on the PCSC transport.
+
+use std::collections::BTreeMap;
+
+use iso7816::Instruction as Iso;
+
+use super::{StatusWord, Transport};
+use crate::{
+    apps::{admin, oath, Pix, Rid},
+    Error, Result, Uuid, Version,
+};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Application {
+    Admin,
+    Ndef,
+    Oath,
+    Provision,
+}
+
+impl Application {
+    fn aid(self) -> Vec<u8> {
+        let (rid, pix) = match self {
+            Self::Admin => (Rid::SOLOKEYS, Pix::ADMIN),
+            Self::Ndef => (Rid::NFC_FORUM, Pix::NDEF),
+            Self::Oath => (Rid::YUBICO, Pix::OATH),
+            Self::Provision => (Rid::SOLOKEYS, Pix::PROVISION),
+        };
+        [rid, pix].concat()
+    }
+}
+
+const NDEF_CAPABILITIES: [u8; 2] = [0xE1, 0x03];
+const NDEF_DATA: [u8; 2] = [0xE1, 0x04];
+const PROVISION_PATH: [u8; 2] = [0xE1, 0x01];
+const PROVISION_DATA: [u8; 2] = [0xE1, 0x02];
+const PROVISION_WRITE_FILE: u8 = 0xBF;
+const PROVISION_UUID: u8 = 0x62;
+
+const OATH_VERSION: u8 = 0x79;
+const OATH_RESPONSE: u8 = 0x76;
+
+/// An OATH credential, as stored by the simulated device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Entry {
+    id: String,
+    /// kind (high nibble) and algorithm (low nibble)
+    kind: u8,
+    digits: u8,
+    secret: Vec<u8>,
+    counter: u32,
+    touch: bool,
+}
+
+/// A simulated Solo 2, see the [module documentation][self].
+#[derive(Clone, Debug)]
+pub struct Simulator {
+    pub uuid: Uuid,
+    pub version: Version,
+    pub locked: bool,
+    /// Capability container of the NDEF app
+    pub ndef_capabilities: Vec<u8>,
+    /// NDEF file (with its two byte length prefix)
+    pub ndef_data: Vec<u8>,
+    winks: usize,
+    selected: Option<Application>,
+    selected_file: Option<[u8; 2]>,
+    oath: Vec<Entry>,
+    /// OATH password key, and the challenge of the last SELECT until validated
+    oath_key: Option<Vec<u8>>,
+    oath_challenge: Option<Vec<u8>>,
+    path: Vec<u8>,
+    data: Vec<u8>,
+    files: BTreeMap<String, Vec<u8>>,
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Self::new(Uuid::from_u128(0x0123_4567_89AB_CDEF_0123_4567_89AB_CDEF))
+    }
+}
+
+/// Tags and values of simple BER-TLV data, the `tvs` tags without length.
+fn tlvs<'d>(data: &'d [u8], tvs: &[u8]) -> Result<Vec<(u8, &'d [u8])>> {
+    Ok(super::tlvs_and_tvs(data, tvs)
+        .map_err(|_| Error::Status(StatusWord::WRONG_DATA))?
+        .into_iter()
+        .map(|(tag, range)| (tag, &data[range]))
+        .collect())
+}
+
+fn tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut tlv = vec![tag, value.len() as u8];
+    tlv.extend_from_slice(value);
+    tlv
+}
+
+/// The OATH algorithm nibble of a credential kind.
+fn digest(kind: u8) -> Result<oath::Digest> {
+    oath::Digest::try_from(kind & 0xF).map_err(|_| Error::Status(StatusWord::WRONG_DATA))
+}
+
+impl Simulator {
+    pub fn new(uuid: Uuid) -> Self {
+        // an NDEF message with a single URI record, "https://solokeys.com"
+        let mut ndef_data = vec![0x00, 0x11, 0xD1, 0x01, 0x0D, 0x55, 0x04];
+        ndef_data.extend_from_slice(b"solokeys.com");
+        Self {
+            uuid,
+            version: Version {
+                major: 1,
+                minor: 0,
+                patch: 0,
+            },
+            locked: false,
+            ndef_capabilities: vec![
+                0x00, 0x0F, 0x20, 0x00, 0x7F, 0x00, 0x7F, 0x04, 0x06, 0xE1, 0x04, 0x00, 0xFF, 0x00,
+                0xFF,
+            ],
+            ndef_data,
+            winks: 0,
+            selected: None,
+            selected_file: None,
+            oath: Vec::new(),
+            oath_key: None,
+            oath_challenge: None,
+            path: Vec::new(),
+            data: Vec::new(),
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// How often the device was winked.
+    pub fn winks(&self) -> usize {
+        self.winks
+    }
+
+    /// Files written via the provision app, by path.
+    pub fn files(&self) -> &BTreeMap<String, Vec<u8>> {
+        &self.files
+    }
+
+    fn version_bytes(&self) -> Vec<u8> {
+        let Version {
+            major,
+            minor,
+            patch,
+        } = self.version;
+        (((major as u32) << 22) | ((minor as u32) << 6) | (patch as u32 & 0x3F))
+            .to_be_bytes()
+            .to_vec()
+    }
+
+    fn admin(&mut self, instruction: u8) -> Result<Vec<u8>> {
+        Ok(match instruction {
+            admin::App::UUID_COMMAND => self.uuid.as_bytes().to_vec(),
+            admin::App::VERSION_COMMAND => self.version_bytes(),
+            admin::App::LOCKED_COMMAND => vec![self.locked as u8],
+            admin::App::WINK_COMMAND => {
+                self.winks += 1;
+                vec![]
+            }
+            _ => return Err(Error::Status(StatusWord::INSTRUCTION_NOT_SUPPORTED)),
+        })
+    }
+
+    fn select_file(&mut self, data: &[u8], files: &[[u8; 2]]) -> Result<Vec<u8>> {
+        let file = files
+            .iter()
+            .find(|file| file[..] == *data)
+            .ok_or(Error::Status(StatusWord::NOT_FOUND))?;
+        self.selected_file = Some(*file);
+        Ok(vec![])
+    }
+
+    fn ndef(&mut self, instruction: u8, data: &[u8]) -> Result<Vec<u8>> {
+        match Iso::from(instruction) {
+            Iso::Select => self.select_file(data, &[NDEF_CAPABILITIES, NDEF_DATA]),
+            Iso::ReadBinary => match self.selected_file {
+                Some(NDEF_CAPABILITIES) => Ok(self.ndef_capabilities.clone()),
+                Some(NDEF_DATA) => Ok(self.ndef_data.clone()),
+                _ => Err(Error::Status(StatusWord::CONDITIONS_NOT_SATISFIED)),
+            },
+            _ => Err(Error::Status(StatusWord::INSTRUCTION_NOT_SUPPORTED)),
+        }
+    }
+
+    fn provision(&mut self, instruction: u8, data: &[u8]) -> Result<Vec<u8>> {
+        match instruction {
+            PROVISION_UUID => return Ok(self.uuid.as_bytes().to_vec()),
+            PROVISION_WRITE_FILE => {
+                let path = String::from_utf8(self.path.clone())
+                    .map_err(|_| Error::Status(StatusWord::WRONG_DATA))?;
+                self.files.insert(path, self.data.clone());
+                return Ok(vec![]);
+            }
+            _ => {}
+        }
+        match Iso::from(instruction) {
+            Iso::Select => self.select_file(data, &[PROVISION_PATH, PROVISION_DATA]),
+            Iso::WriteBinary => {
+                match self.selected_file {
+                    Some(PROVISION_PATH) => self.path = data.to_vec(),
+                    Some(PROVISION_DATA) => self.data = data.to_vec(),
+                    _ => return Err(Error::Status(StatusWord::CONDITIONS_NOT_SATISFIED)),
+                }
+                Ok(vec![])
+            }
+            _ => Err(Error::Status(StatusWord::INSTRUCTION_NOT_SUPPORTED)),
+        }
+    }
+
+    fn oath_select(&mut self) -> Result<Vec<u8>> {
+        let mut response = tlv(OATH_VERSION, &[4, 4, 0]);
+        response.extend(tlv(
+            oath::Tag::CredentialId as u8,
+            &self.uuid.as_bytes()[..8],
+        ));
+        self.oath_challenge = None;
+        if self.oath_key.is_some() {
+            let mut challenge = vec![0; 8];
+            getrandom::getrandom(&mut challenge).map_err(|_| Error::Status(StatusWord::UNKNOWN))?;
+            response.extend(tlv(oath::Tag::Challenge as u8, &challenge));
+            response.extend(tlv(oath::Tag::Algorithm as u8, &[0x01]));
+            self.oath_challenge = Some(challenge);
+        }
+        Ok(response)
+    }
+
+    fn oath_entry(&mut self, tlvs: &[(u8, &[u8])]) -> Result<&mut Entry> {
+        let id = tlvs
+            .iter()
+            .find(|(tag, _)| *tag == oath::Tag::CredentialId as u8)
+            .map(|(_, id)| String::from_utf8_lossy(id).to_string())
+            .ok_or(Error::Status(StatusWord::WRONG_DATA))?;
+        self.oath
+            .iter_mut()
+            .find(|entry| entry.id == id)
+            .ok_or(Error::Status(StatusWord::NOT_FOUND))
+    }
+
+    fn oath(&mut self, instruction: u8, p1: u8, p2: u8, data: &[u8]) -> Result<Vec<u8>> {
+        use oath::{Instruction, Tag};
+
+        // PUT properties are a tag and value byte, without length
+        let tvs: &[u8] = if instruction == Instruction::Put as u8 {
+            &[Tag::Property as u8]
+        } else {
+            &[]
+        };
+        let tlvs = tlvs(data, tvs)?;
+        let value = |wanted: Tag| {
+            tlvs.iter()
+                .find(|(tag, _)| *tag == wanted as u8)
+                .map(|(_, value)| *value)
+        };
+        let unlocking = [Instruction::Validate as u8, Instruction::Reset as u8];
+        if self.oath_challenge.is_some() && !unlocking.contains(&instruction) {
+            return Err(Error::Status(StatusWord::SECURITY_STATUS_NOT_SATISFIED));
+        }
+        match instruction {
+            i if i == Instruction::SetCode as u8 => {
+                let key = value(Tag::Key).ok_or(Error::Status(StatusWord::WRONG_DATA))?;
+                if key.is_empty() {
+                    self.oath_key = None;
+                    return Ok(vec![]);
+                }
+                let challenge =
+                    value(Tag::Challenge).ok_or(Error::Status(StatusWord::WRONG_DATA))?;
+                let response = value(Tag::Response).ok_or(Error::Status(StatusWord::WRONG_DATA))?;
+                if key[0] != 0x21 || oath::hmac_sha1(&key[1..], challenge) != response {
+                    return Err(Error::Status(StatusWord::WRONG_DATA));
+                }
+                self.oath_key = Some(key[1..].to_vec());
+                Ok(vec![])
+            }
+            i if i == Instruction::Validate as u8 => {
+                let (key, challenge) = match (&self.oath_key, &self.oath_challenge) {
+                    (Some(key), Some(challenge)) => (key, challenge),
+                    _ => return Err(Error::Status(StatusWord::CONDITIONS_NOT_SATISFIED)),
+                };
+                let response = value(Tag::Response).ok_or(Error::Status(StatusWord::WRONG_DATA))?;
+                let host_challenge =
+                    value(Tag::Challenge).ok_or(Error::Status(StatusWord::WRONG_DATA))?;
+                if oath::hmac_sha1(key, challenge) != response {
+                    return Err(Error::Status(StatusWord::WRONG_DATA));
+                }
+                let response = tlv(Tag::Response as u8, &oath::hmac_sha1(key, host_challenge));
+                self.oath_challenge = None;
+                Ok(response)
+            }
+            i if i == Instruction::Put as u8 => {
+                let id = value(Tag::CredentialId).ok_or(Error::Status(StatusWord::WRONG_DATA))?;
+                let key = value(Tag::Key)
+                    .filter(|key| key.len() >= 2)
+                    .ok_or(Error::Status(StatusWord::WRONG_DATA))?;
+                let counter = match value(Tag::InitialCounter) {
+                    Some(counter) => u32::from_be_bytes(
+                        counter
+                            .try_into()
+                            .map_err(|_| Error::Status(StatusWord::WRONG_DATA))?,
+                    ),
+                    None => 0,
+                };
+                let entry = Entry {
+                    id: String::from_utf8_lossy(id).to_string(),
+                    kind: key[0],
+                    digits: key[1],
+                    secret: key[2..].to_vec(),
+                    counter,
+                    touch: value(Tag::Property).map_or(false, |property| {
+                        property[0] & oath::PROPERTY_REQUIRE_TOUCH != 0
+                    }),
+                };
+                self.oath.retain(|existing| existing.id != entry.id);
+                self.oath.push(entry);
+                Ok(vec![])
+            }
+            i if i == Instruction::Delete as u8 => {
+                let id = self.oath_entry(&tlvs)?.id.clone();
+                self.oath.retain(|entry| entry.id != id);
+                Ok(vec![])
+            }
+            i if i == Instruction::Reset as u8 => {
+                if (p1, p2) != (0xDE, 0xAD) {
+                    return Err(Error::Status(StatusWord::WRONG_DATA));
+                }
+                self.oath.clear();
+                self.oath_key = None;
+                self.oath_challenge = None;
+                Ok(vec![])
+            }
+            i if i == Instruction::List as u8 => Ok(self
+                .oath
+                .iter()
+                .flat_map(|entry| {
+                    let mut value = vec![entry.kind];
+                    value.extend_from_slice(entry.id.as_bytes());
+                    tlv(Tag::NameList as u8, &value)
+                })
+                .collect()),
+            i if i == Instruction::Calculate as u8 => {
+                let challenge = value(Tag::Challenge).unwrap_or_default().to_vec();
+                let entry = self.oath_entry(&tlvs)?;
+                // HOTP credentials use (and advance) their counter instead of the challenge
+                let challenge = if entry.kind >> 4 == 0x1 {
+                    entry.counter += 1;
+                    u64::from(entry.counter - 1).to_be_bytes().to_vec()
+                } else {
+                    challenge
+                };
+                let truncated = oath::truncated(digest(entry.kind)?, &entry.secret, &challenge);
+                let mut response = vec![entry.digits];
+                response.extend_from_slice(&truncated.to_be_bytes());
+                Ok(tlv(OATH_RESPONSE, &response))
+            }
+            i if i == Instruction::CalculateAll as u8 => {
+                let challenge =
+                    value(Tag::Challenge).ok_or(Error::Status(StatusWord::WRONG_DATA))?;
+                let mut response = Vec::new();
+                for entry in &self.oath {
+                    response.extend(tlv(Tag::CredentialId as u8, entry.id.as_bytes()));
+                    if entry.touch {
+                        response.extend(tlv(Tag::Touch as u8, &[entry.digits]));
+                    } else if entry.kind >> 4 == 0x1 {
+                        response.extend(tlv(Tag::Hotp as u8, &[entry.digits]));
+                    } else {
+                        let truncated =
+                            oath::truncated(digest(entry.kind)?, &entry.secret, challenge);
+                        let mut code = vec![entry.digits];
+                        code.extend_from_slice(&truncated.to_be_bytes());
+                        response.extend(tlv(OATH_RESPONSE, &code));
+                    }
+                }
+                Ok(response)
+            }
+            _ => Err(Error::Status(StatusWord::INSTRUCTION_NOT_SUPPORTED)),
+        }
+    }
+}
+
+impl Transport for Simulator {
+    fn call(&mut self, instruction: u8, data: &[u8]) -> Result<Vec<u8>> {
+        self.call_iso(0, instruction, 0x00, 0x00, data)
+    }
+
+    fn call_iso(
+        &mut self,
+        class: u8,
+        instruction: u8,
+        p1: u8,
+        p2: u8,
+        data: &[u8],
+    ) -> Result<Vec<u8>> {
+        if class != 0 {
+            return Err(Error::Status(StatusWord::CLASS_NOT_SUPPORTED));
+        }
+        if instruction == u8::from(Iso::Select) && p1 == 0x04 {
+            let application = [
+                Application::Admin,
+                Application::Ndef,
+                Application::Oath,
+                Application::Provision,
+            ]
+            .into_iter()
+            .find(|application| application.aid().starts_with(data) && !data.is_empty())
+            .ok_or(Error::Status(StatusWord::NOT_FOUND))?;
+            self.selected = Some(application);
+            self.selected_file = None;
+            return Ok(match application {
+                Application::Oath => self.oath_select()?,
+                _ => vec![],
+            });
+        }
+        match self.selected {
+            Some(Application::Admin) => self.admin(instruction),
+            Some(Application::Ndef) => self.ndef(instruction, data),
+            Some(Application::Oath) => self.oath(instruction, p1, p2, data),
+            Some(Application::Provision) => self.provision(instruction, data),
+            None => Err(Error::Status(StatusWord::CONDITIONS_NOT_SATISFIED)),
+        }
+    }
+
+    fn select(&mut self, aid: Vec<u8>) -> Result<Vec<u8>> {
+        self.call_iso(0, Iso::Select.into(), 0x04, 0x00, &aid)
+    }
+
+    fn transaction(
+        &mut self,
+        calls: &mut dyn FnMut(&mut dyn Transport) -> Result<()>,
+    ) -> Result<()> {
+        calls(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_tlvs() {
+        assert_eq!(
+            tlvs(&[0x71, 1, b'a', 0x74, 0x81, 2, 0, 1], &[]).unwrap(),
+            [(0x71, &b"a"[..]), (0x74, &[0, 1][..])]
+        );
+        assert!(tlvs(&[0x71, 2, b'a'], &[]).is_err());
+        assert!(tlvs(&[0x71], &[]).is_err());
+        assert_eq!(
+            tlvs(&[0x71, 1, b'a', 0x78, 0x02, 0x7A, 1, 7], &[0x78]).unwrap(),
+            [(0x71, &b"a"[..]), (0x78, &[0x02][..]), (0x7A, &[7][..])]
+        );
+    }
+}
@@ -0,0 +1,297 @@
+//! The apps, against a simulated Solo 2.
+
+use solo2::{
+    apps::{oath, provision, Admin, Ndef, Oath},
+    transport::sim::Simulator,
+    transport::StatusWord,
+    Error, Select as _, Transport, Uuid, Version,
+};
+
+/// RFC 4226 and RFC 6238 test secret ("12345678901234567890")
+const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+#[test]
+fn admin() {
+    let mut simulator = Simulator::new(Uuid::from_u128(0xC0FFEE));
+    simulator.version = Version {
+        major: 2,
+        minor: 964,
+        patch: 0,
+    };
+    simulator.locked = true;
+
+    let mut admin = Admin::select(&mut simulator).unwrap();
+    assert_eq!(admin.uuid().unwrap(), Uuid::from_u128(0xC0FFEE));
+    assert_eq!(admin.version().unwrap().to_semver(), "2.964.0");
+    assert!(admin.locked().unwrap());
+    admin.wink().unwrap();
+    admin.wink().unwrap();
+    assert_eq!(simulator.winks(), 2);
+}
+
+#[test]
+fn oath_totp() {
+    let mut simulator = Simulator::default();
+    let mut app = Oath::select(&mut simulator).unwrap();
+
+    let mut credential = oath::Credential::default_totp("alice@example.com", SECRET).unwrap();
+    credential.issuer = Some("example".to_string());
+    let id = app.register(credential).unwrap();
+    assert_eq!(id, "example:alice@example.com");
+    assert_eq!(app.list().unwrap(), std::slice::from_ref(&id));
+
+    // RFC 6238, appendix B (SHA-1, truncated to six digits)
+    let code = app
+        .authenticate(oath::Authenticate {
+            label: id.clone(),
+            timestamp: 59,
+        })
+        .unwrap();
+    assert_eq!(code.to_string(), "287082");
+    assert_eq!((code.valid_from, code.valid_until), (Some(30), Some(60)));
+    let code = app
+        .authenticate(oath::Authenticate {
+            label: id.clone(),
+            timestamp: 1111111109,
+        })
+        .unwrap();
+    assert_eq!(code.to_string(), "081804");
+
+    app.delete(id.clone()).unwrap();
+    assert!(app.list().unwrap().is_empty());
+    assert!(matches!(app.delete(id), Err(Error::NotFound(_))));
+}
+
+#[test]
+fn oath_totp_period() {
+    let mut simulator = Simulator::default();
+    let mut app = Oath::select(&mut simulator).unwrap();
+
+    let mut credential = oath::Credential::default_totp("bob", SECRET).unwrap();
+    credential.kind = oath::Kind::Totp(oath::Totp { period: 60 });
+    let id = app.register(credential).unwrap();
+    assert_eq!(id, "60/bob");
+
+    // twice the timestamp with twice the period is the same time step as in RFC 6238
+    let code = app
+        .authenticate(oath::Authenticate {
+            label: id,
+            timestamp: 1111111109 * 2,
+        })
+        .unwrap();
+    assert_eq!(code.to_string(), "081804");
+    assert_eq!(code.valid_from, Some(37037036 * 60));
+    assert_eq!(code.valid_until, Some(37037037 * 60));
+}
+
+#[test]
+fn oath_calculate_all() {
+    let mut simulator = Simulator::default();
+    let mut app = Oath::select(&mut simulator).unwrap();
+
+    let totp = app
+        .register(oath::Credential::default_totp("totp", SECRET).unwrap())
+        .unwrap();
+    let mut credential = oath::Credential::default_totp("slow", SECRET).unwrap();
+    credential.kind = oath::Kind::Totp(oath::Totp { period: 60 });
+    let slow = app.register(credential).unwrap();
+    let mut credential = oath::Credential::default_totp("hotp", SECRET).unwrap();
+    credential.kind = oath::Kind::Hotp(oath::Hotp { initial_counter: 0 });
+    let hotp = app.register(credential).unwrap();
+
+    let codes = app.calculate_all(1111111109).unwrap();
+    let ids: Vec<_> = codes.iter().map(|(id, _)| id.clone()).collect();
+    assert_eq!(ids, [totp, slow.clone(), hotp.clone()]);
+
+    match codes[0].1 {
+        oath::Calculated::Code(code) => {
+            assert_eq!(code.to_string(), "081804");
+            assert_eq!(code.valid_until, Some(1111111110));
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    let expected = app
+        .authenticate(oath::Authenticate {
+            label: slow,
+            timestamp: 1111111109,
+        })
+        .unwrap();
+    assert_eq!(codes[1].1, oath::Calculated::Code(expected));
+    assert_eq!(expected.valid_until, Some(1111111140));
+    assert_eq!(codes[2].1, oath::Calculated::Hotp);
+    assert!(codes[2].1.needs_calculate());
+
+    // the HOTP counter did not advance
+    let code = app
+        .authenticate(oath::Authenticate {
+            label: hotp,
+            timestamp: 0,
+        })
+        .unwrap();
+    assert_eq!(code.to_string(), "755224");
+}
+
+#[test]
+fn oath_hotp() {
+    let mut simulator = Simulator::default();
+    let mut app = Oath::select(&mut simulator).unwrap();
+
+    let mut credential = oath::Credential::default_totp("hotp", SECRET).unwrap();
+    credential.kind = oath::Kind::Hotp(oath::Hotp { initial_counter: 0 });
+    let id = app.register(credential).unwrap();
+
+    // RFC 4226, appendix D; the challenge is ignored, the counter advances
+    for expected in ["755224", "287082", "359152"] {
+        let code = app
+            .authenticate(oath::Authenticate {
+                label: id.clone(),
+                timestamp: 0,
+            })
+            .unwrap();
+        assert_eq!(code.to_string(), expected);
+    }
+
+    app.reset().unwrap();
+    assert!(app.list().unwrap().is_empty());
+}
+
+#[test]
+fn oath_calculate_hotp() {
+    let mut simulator = Simulator::default();
+    let mut app = Oath::select(&mut simulator).unwrap();
+
+    let mut credential = oath::Credential::default_totp("hotp", SECRET).unwrap();
+    credential.kind = oath::Kind::Hotp(oath::Hotp { initial_counter: 7 });
+    credential.digits = 8;
+    let id = app.register(credential.clone()).unwrap();
+
+    for counter in 7..12 {
+        let code = app.calculate_hotp(&id).unwrap();
+        assert_eq!(code, credential.hotp(counter).unwrap());
+        assert_eq!(code.valid_until, None);
+    }
+    assert!(matches!(
+        app.calculate_hotp("missing"),
+        Err(Error::NotFound(_))
+    ));
+}
+
+#[test]
+fn oath_touch_required() {
+    let mut simulator = Simulator::default();
+    let mut app = Oath::select(&mut simulator).unwrap();
+
+    let mut credential = oath::Credential::default_totp("touch", SECRET).unwrap();
+    credential.touch_required = true;
+    let touch = app.register(credential).unwrap();
+    let mut credential = oath::Credential::default_totp("counter", SECRET).unwrap();
+    credential.kind = oath::Kind::Hotp(oath::Hotp { initial_counter: 1 });
+    credential.touch_required = true;
+    let counter = app.register(credential).unwrap();
+    app.register(oath::Credential::default_totp("plain", SECRET).unwrap())
+        .unwrap();
+
+    assert_eq!(
+        app.list_with_touch().unwrap(),
+        [
+            (touch.clone(), true),
+            (counter.clone(), true),
+            ("plain".to_string(), false)
+        ]
+    );
+    let codes = app.calculate_all(59).unwrap();
+    assert_eq!(codes[0].1, oath::Calculated::TouchRequired);
+    assert_eq!(codes[1].1, oath::Calculated::TouchRequired);
+    assert!(!codes[2].1.needs_calculate());
+
+    let code = app
+        .authenticate(oath::Authenticate {
+            label: touch,
+            timestamp: 59,
+        })
+        .unwrap();
+    assert_eq!(code.to_string(), "287082");
+    assert_eq!(app.calculate_hotp(&counter).unwrap().to_string(), "287082");
+}
+
+#[test]
+fn oath_password() {
+    let mut simulator = Simulator::default();
+    let mut app = Oath::select(&mut simulator).unwrap();
+    assert!(!app.password_required());
+    assert_eq!(
+        app.selection().salt,
+        Simulator::default().uuid.as_bytes()[..8]
+    );
+    app.register(oath::Credential::default_totp("alice", SECRET).unwrap())
+        .unwrap();
+    app.set_password(Some("hunter2")).unwrap();
+
+    let mut app = Oath::select(&mut simulator).unwrap();
+    assert!(app.password_required());
+    assert!(matches!(
+        app.list(),
+        Err(Error::Status(StatusWord::SECURITY_STATUS_NOT_SATISFIED))
+    ));
+    assert!(matches!(app.validate("hunter3"), Err(Error::WrongPassword)));
+
+    assert_eq!(
+        Oath::with_password(&mut simulator, Some("hunter2"), |app| app.list()).unwrap(),
+        ["alice"]
+    );
+    assert!(matches!(
+        Oath::with_password(&mut simulator, Some("hunter3"), |app| app.list()),
+        Err(Error::WrongPassword)
+    ));
+
+    let mut app = Oath::select(&mut simulator).unwrap();
+    app.validate("hunter2").unwrap();
+    assert!(!app.password_required());
+    assert_eq!(app.list().unwrap(), ["alice"]);
+    app.set_password(None).unwrap();
+
+    let mut app = Oath::select(&mut simulator).unwrap();
+    assert!(!app.password_required());
+    assert_eq!(app.list().unwrap(), ["alice"]);
+
+    // resetting removes the password
+    app.set_password(Some("hunter2")).unwrap();
+    let mut app = Oath::select(&mut simulator).unwrap();
+    app.reset().unwrap();
+    assert!(!app.password_required());
+    assert!(app.list().unwrap().is_empty());
+}
+
+#[test]
+fn ndef() {
+    let mut simulator = Simulator::default();
+    simulator.ndef_data = vec![0x00, 0x03, 0xD0, 0x00, 0x00];
+    let mut app = Ndef::select(&mut simulator).unwrap();
+    assert_eq!(app.capabilities().unwrap()[..2], [0x00, 0x0F]);
+    assert_eq!(app.data().unwrap(), [0x00, 0x03, 0xD0, 0x00, 0x00]);
+}
+
+#[test]
+fn provision() {
+    let mut simulator = Simulator::default();
+    let mut app = provision::App::select(&mut simulator).unwrap();
+    app.write_file(b"certificate", "/fido/x5c/00").unwrap();
+    app.write_file(&[0; 8192], "/large").unwrap();
+    assert!(app.write_file(&[0; 8193], "/too-large").is_err());
+    assert_eq!(app.uuid().unwrap(), Simulator::default().uuid.as_u128());
+
+    let files = simulator.files();
+    assert_eq!(files.len(), 2);
+    assert_eq!(files["/fido/x5c/00"], b"certificate");
+}
+
+#[test]
+fn unknown_application() {
+    let mut simulator = Simulator::default();
+    assert!(matches!(
+        simulator.select(vec![0xA0, 0x00, 0x00, 0x00, 0x00]),
+        Err(Error::Status(_))
+    ));
+    // nothing selected
+    assert!(simulator.call(Admin::UUID_COMMAND, &[]).is_err());
+}